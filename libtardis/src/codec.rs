@@ -0,0 +1,383 @@
+//! Compression codecs for Tardis resources.
+//!
+//! Each codec is gated behind a cargo feature of the same name, so that the
+//! loader only has to link the codecs it actually needs. [`Store`], which
+//! doesn't compress its input at all, is always available.
+
+use crate::error::TardisError;
+use derive_try_from_primitive::TryFromPrimitive;
+
+/// Identifies the codec that was used to compress a resource.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, TryFromPrimitive)]
+pub enum CodecId {
+    /// Data is stored without any compression.
+    Store = 0,
+    /// LZ4 block format, prefixed with the uncompressed size. Data compressed
+    /// with LZ4-HC uses the same format.
+    Lz4 = 1,
+    /// Zstandard frame.
+    Zstd = 2,
+    /// XZ (LZMA2) stream.
+    Xz = 3,
+    /// Raw DEFLATE stream.
+    Deflate = 4,
+}
+
+impl CodecId {
+    /// All of the codec identifiers, in order.
+    pub const ALL: [CodecId; 5] = [
+        CodecId::Store,
+        CodecId::Lz4,
+        CodecId::Zstd,
+        CodecId::Xz,
+        CodecId::Deflate,
+    ];
+
+    /// The name of the codec (and of the cargo feature that enables it).
+    pub fn name(&self) -> &'static str {
+        match self {
+            CodecId::Store => "store",
+            CodecId::Lz4 => "lz4",
+            CodecId::Zstd => "zstd",
+            CodecId::Xz => "xz",
+            CodecId::Deflate => "deflate",
+        }
+    }
+
+    /// The highest compression level accepted by the codec.
+    pub fn max_level(&self) -> u32 {
+        match self {
+            CodecId::Store => 0,
+            CodecId::Lz4 => 12,
+            CodecId::Zstd => 22,
+            CodecId::Xz => 9,
+            CodecId::Deflate => 10,
+        }
+    }
+
+    /// The compression levels that can be passed to [`new`] for this codec.
+    /// `None` selects the codec's default level (or, for LZ4, the fast encoder).
+    pub fn levels(&self) -> Vec<Option<u32>> {
+        match self {
+            CodecId::Store => vec![None],
+            CodecId::Lz4 if cfg!(feature = "lz4-hc") => {
+                let mut levels = vec![None];
+                levels.extend((1..=self.max_level()).map(Some));
+                levels
+            }
+            CodecId::Lz4 => vec![None],
+            CodecId::Zstd => (1..=self.max_level()).map(Some).collect(),
+            CodecId::Xz | CodecId::Deflate => (0..=self.max_level()).map(Some).collect(),
+        }
+    }
+
+    /// Returns `true` if support for this codec was compiled in.
+    pub fn is_available(&self) -> bool {
+        match self {
+            CodecId::Store => true,
+            CodecId::Lz4 => cfg!(feature = "lz4"),
+            CodecId::Zstd => cfg!(feature = "zstd"),
+            CodecId::Xz => cfg!(feature = "xz"),
+            CodecId::Deflate => cfg!(feature = "deflate"),
+        }
+    }
+}
+
+/// A compression algorithm that can be used to compress resources.
+pub trait Codec {
+    /// The identifier recorded in the manifest for data compressed with this codec.
+    fn id(&self) -> CodecId;
+
+    /// Compress a block of data.
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, TardisError>;
+
+    /// Decompress a block of data that was compressed with [`Codec::compress`].
+    /// Data that would decompress to more than `max_size` bytes is rejected
+    /// before that much is allocated for it.
+    fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, TardisError>;
+}
+
+/// The error for data that decompresses to more than `max_size` bytes.
+fn too_large(max_size: usize) -> TardisError {
+    TardisError::DecompressionError(format!("data decompresses to more than {max_size} bytes"))
+}
+
+/// Read everything from a decoder, failing once it produces more than
+/// `max_size` bytes.
+#[cfg(any(feature = "zstd", feature = "xz"))]
+fn read_limited<R: std::io::Read>(decoder: R, max_size: usize) -> Result<Vec<u8>, TardisError> {
+    use std::io::Read;
+
+    let mut output = Vec::new();
+    decoder
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(|e| TardisError::DecompressionError(e.to_string()))?;
+    if output.len() > max_size {
+        return Err(too_large(max_size));
+    }
+    Ok(output)
+}
+
+/// Create a codec from its identifier.
+///
+/// `level` sets the compression level; if it's `None`, a default that favours
+/// a high compression ratio is used. For [`CodecId::Lz4`], setting a level
+/// selects LZ4-HC (which requires the `lz4-hc` feature). The level is ignored
+/// for decompression.
+pub fn new(id: CodecId, level: Option<u32>) -> Result<Box<dyn Codec>, TardisError> {
+    if let Some(level) = level {
+        if level > id.max_level() {
+            return Err(TardisError::CodecError(format!(
+                "{} level {level} is out of range (maximum is {})",
+                id.name(),
+                id.max_level()
+            )));
+        }
+    }
+
+    match id {
+        CodecId::Store => Ok(Box::new(Store)),
+        #[cfg(feature = "lz4")]
+        CodecId::Lz4 => {
+            if level.is_some() && !cfg!(feature = "lz4-hc") {
+                return Err(TardisError::UnsupportedCodec("lz4-hc".into()));
+            }
+            Ok(Box::new(Lz4 { hc_level: level }))
+        }
+        #[cfg(feature = "zstd")]
+        CodecId::Zstd => {
+            let level = level.unwrap_or(19).max(1);
+            Ok(Box::new(Zstd {
+                level: level as i32,
+            }))
+        }
+        #[cfg(feature = "xz")]
+        CodecId::Xz => Ok(Box::new(Xz {
+            level: level.unwrap_or(9),
+        })),
+        #[cfg(feature = "deflate")]
+        CodecId::Deflate => Ok(Box::new(Deflate {
+            level: level.unwrap_or(9) as u8,
+        })),
+        #[allow(unreachable_patterns)]
+        _ => Err(TardisError::UnsupportedCodec(id.name().into())),
+    }
+}
+
+/// Codec that stores data without compressing it.
+pub struct Store;
+
+impl Codec for Store {
+    fn id(&self) -> CodecId {
+        CodecId::Store
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, TardisError> {
+        Ok(data.to_vec())
+    }
+
+    fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, TardisError> {
+        if data.len() > max_size {
+            return Err(too_large(max_size));
+        }
+        Ok(data.to_vec())
+    }
+}
+
+/// LZ4 codec. Data is compressed with LZ4-HC if `hc_level` is set.
+#[cfg(feature = "lz4")]
+pub struct Lz4 {
+    hc_level: Option<u32>,
+}
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4 {
+    fn id(&self) -> CodecId {
+        CodecId::Lz4
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, TardisError> {
+        match self.hc_level {
+            #[cfg(feature = "lz4-hc")]
+            Some(level) => {
+                use lz4::block::CompressionMode;
+                let mode = CompressionMode::HIGHCOMPRESSION(level as i32);
+                lz4::block::compress(data, Some(mode), true)
+                    .map_err(|e| TardisError::CodecError(e.to_string()))
+            }
+            _ => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+
+    fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, TardisError> {
+        // The output is allocated up front from the size prefix, so the prefix
+        // has to be checked first
+        let (size, data) = lz4_flex::block::uncompressed_size(data)
+            .map_err(|e| TardisError::DecompressionError(e.to_string()))?;
+        if size > max_size {
+            return Err(too_large(max_size));
+        }
+        lz4_flex::block::decompress(data, size)
+            .map_err(|e| TardisError::DecompressionError(e.to_string()))
+    }
+}
+
+/// Zstandard codec.
+#[cfg(feature = "zstd")]
+pub struct Zstd {
+    level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Codec for Zstd {
+    fn id(&self) -> CodecId {
+        CodecId::Zstd
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, TardisError> {
+        zstd::stream::encode_all(data, self.level)
+            .map_err(|e| TardisError::CodecError(e.to_string()))
+    }
+
+    fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, TardisError> {
+        let decoder = zstd::stream::read::Decoder::new(data)
+            .map_err(|e| TardisError::DecompressionError(e.to_string()))?;
+        read_limited(decoder, max_size)
+    }
+}
+
+/// XZ codec.
+#[cfg(feature = "xz")]
+pub struct Xz {
+    level: u32,
+}
+
+#[cfg(feature = "xz")]
+impl Codec for Xz {
+    fn id(&self) -> CodecId {
+        CodecId::Xz
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, TardisError> {
+        use std::io::Read;
+
+        let mut output = Vec::new();
+        xz2::read::XzEncoder::new(data, self.level)
+            .read_to_end(&mut output)
+            .map_err(|e| TardisError::CodecError(e.to_string()))?;
+        Ok(output)
+    }
+
+    fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, TardisError> {
+        read_limited(xz2::read::XzDecoder::new(data), max_size)
+    }
+}
+
+/// DEFLATE codec.
+#[cfg(feature = "deflate")]
+pub struct Deflate {
+    level: u8,
+}
+
+#[cfg(feature = "deflate")]
+impl Codec for Deflate {
+    fn id(&self) -> CodecId {
+        CodecId::Deflate
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, TardisError> {
+        Ok(miniz_oxide::deflate::compress_to_vec(data, self.level))
+    }
+
+    fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, TardisError> {
+        use miniz_oxide::inflate::TINFLStatus;
+
+        miniz_oxide::inflate::decompress_to_vec_with_limit(data, max_size).map_err(|e| {
+            match e.status {
+                TINFLStatus::HasMoreOutput => too_large(max_size),
+                status => TardisError::DecompressionError(format!("{status:?}")),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CodecId;
+    use crate::error::TardisError;
+
+    #[test]
+    fn test_roundtrip() {
+        let data = b"hello, world! hello, world! hello, world!".repeat(16);
+
+        for id in CodecId::ALL.iter().filter(|id| id.is_available()) {
+            let codec = super::new(*id, None).unwrap();
+            assert_eq!(codec.id(), *id);
+
+            let compressed = codec.compress(&data).unwrap();
+            assert_eq!(
+                codec.decompress(&compressed, data.len()).unwrap(),
+                data,
+                "{}",
+                id.name()
+            );
+        }
+    }
+
+    #[cfg(feature = "lz4-hc")]
+    #[test]
+    fn test_lz4_hc_is_lz4_compatible() {
+        let data = b"hello, world! hello, world! hello, world!".repeat(16);
+        let hc = super::new(CodecId::Lz4, Some(12)).unwrap();
+        let fast = super::new(CodecId::Lz4, None).unwrap();
+        assert_eq!(
+            fast.decompress(&hc.compress(&data).unwrap(), data.len())
+                .unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let data = b"hello, world! hello, world! hello, world!".repeat(16);
+
+        for id in CodecId::ALL.iter().filter(|id| id.is_available()) {
+            let codec = super::new(*id, None).unwrap();
+            let compressed = codec.compress(&data).unwrap();
+            let result = codec.decompress(&compressed, data.len() - 1);
+            assert!(
+                matches!(result, Err(TardisError::DecompressionError(_))),
+                "{}",
+                id.name()
+            );
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_lz4_size_prefix_is_checked() {
+        // A prefix claiming 4 GiB of output is rejected before anything is
+        // allocated for it
+        let codec = super::new(CodecId::Lz4, None).unwrap();
+        let result = codec.decompress(&[0xff, 0xff, 0xff, 0xff, 0], 1 << 20);
+        assert!(matches!(result, Err(TardisError::DecompressionError(_))));
+    }
+
+    #[test]
+    fn test_levels() {
+        for id in CodecId::ALL.iter().filter(|id| id.is_available()) {
+            for level in id.levels() {
+                assert!(super::new(*id, level).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn test_level_out_of_range() {
+        for id in CodecId::ALL.iter().filter(|id| id.is_available()) {
+            assert!(super::new(*id, Some(100)).is_err());
+        }
+    }
+}
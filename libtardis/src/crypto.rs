@@ -3,14 +3,30 @@ use ring::{
     error::Unspecified,
 };
 
+/// Return the nonce for a chunk of a resource.
+///
+/// Nonces follow the STREAM construction: they contain the index of the chunk
+/// and a flag marking the final chunk, so that chunks can't be reordered,
+/// dropped or truncated without failing authentication.
+pub fn stream_nonce(counter: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+
+    Nonce::assume_unique_for_key(nonce)
+}
+
 pub struct NonceSeq {
-    counter: usize,
-    len: usize,
+    counter: u64,
+    len: u64,
 }
 
 impl NonceSeq {
     pub fn new(len: usize) -> Self {
-        NonceSeq { counter: 0, len }
+        NonceSeq {
+            counter: 0,
+            len: len as u64,
+        }
     }
 }
 
@@ -20,13 +36,9 @@ impl NonceSequence for NonceSeq {
             return Err(Unspecified);
         }
 
-        // TODO: consider the case where counter may exceed the width of usize?
-        let last_byte = (self.counter == self.len - 1) as u8;
-
-        let mut nonce = [0u8; 12];
-        nonce[3..11].copy_from_slice(&self.counter.to_be_bytes());
-        nonce[11] = last_byte;
+        let nonce = stream_nonce(self.counter, self.counter == self.len - 1);
+        self.counter += 1;
 
-        Ok(Nonce::assume_unique_for_key(nonce))
+        Ok(nonce)
     }
 }
@@ -1,6 +1,206 @@
+use core::fmt;
+use nix::errno::Errno;
+
 #[derive(Debug)]
 pub enum TardisError {
     /// Error involving accessing the filesystem (e.g. to read
     /// an input executable or write its contents to disk).
     FilesystemError(String),
+
+    /// The packed binary that a loader runs from couldn't be opened or read.
+    HostReadError(String),
+
+    /// A structure in the file doesn't start with the magic bytes that
+    /// identify it, e.g. because the file isn't a packed binary at all.
+    BadMagic(String),
+
+    /// The file ends before its manifest, e.g. because the data appended to
+    /// the loader was dropped.
+    FileTruncated(String),
+
+    /// The file doesn't match the digest recorded when it was packed.
+    FileCorrupted(String),
+
+    /// The manifest couldn't be parsed.
+    MalformedManifest(String),
+
+    /// The manifest was written with a newer version of the format than
+    /// this version of Tardis understands.
+    UnsupportedVersion(u32),
+
+    /// The manifest contains a critical extension that this version of
+    /// Tardis doesn't understand.
+    UnsupportedExtension(u16),
+
+    /// The codec needed for a resource isn't supported by this build of Tardis.
+    UnsupportedCodec(String),
+
+    /// A codec was misconfigured or failed to compress its input.
+    CodecError(String),
+
+    /// A resource failed authentication when it was decrypted.
+    AuthenticationError,
+
+    /// The file isn't signed, but the loader requires a signature.
+    MissingSignature,
+
+    /// The file's signature doesn't match its contents or the expected key.
+    InvalidSignature,
+
+    /// A signing or verification key couldn't be used.
+    InvalidKey(String),
+
+    /// A resource couldn't be decompressed.
+    DecompressionError(String),
+
+    /// A resource didn't match the size or digest recorded in its metadata.
+    IntegrityError(String),
+
+    /// A packed binary was written without a loader.
+    MissingLoader,
+
+    /// A packed binary was written without any guests.
+    NoGuests,
+
+    /// No runnable guest has the name that was given for it.
+    UnknownGuest(String),
+
+    /// A guest is started after a guest that isn't run, or doesn't exist.
+    UnknownDependency { guest: String, after: String },
+
+    /// Guests are each started after the others, so none of them can start.
+    DependencyCycle(Vec<String>),
+
+    /// More guests were added than a packed binary can hold.
+    TooManyGuests(usize),
+
+    /// The packed binary, or one of the structures in it, is larger than it
+    /// can be. `what` describes the quantity that's too large.
+    TooLarge {
+        what: &'static str,
+        size: u64,
+        max_size: u64,
+    },
+
+    /// A system call failed while running a guest.
+    SyscallError { syscall: &'static str, errno: Errno },
+
+    /// A guest is restarted when it exits, but the guests aren't supervised
+    /// with an exit policy, so nothing would restart it.
+    RestartUnsupervised(String),
+
+    /// A guest runs in the foreground, replacing the loader, but the guests
+    /// are supervised with an exit policy, which needs the loader to stay.
+    ForegroundSupervised(String),
+
+    /// A guest runs in the foreground, replacing the loader, but other guests
+    /// are started after it or in the foreground too.
+    ForegroundNotLast(String),
+
+    /// A guest that others depend on stopped, or ran out of time, before it
+    /// said it was ready.
+    NotReady(String),
+}
+
+impl fmt::Display for TardisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TardisError::FilesystemError(msg) => write!(f, "filesystem error: {msg}"),
+            TardisError::HostReadError(msg) => write!(f, "couldn't read the packed binary: {msg}"),
+            TardisError::BadMagic(msg) => write!(f, "bad magic: {msg}"),
+            TardisError::FileTruncated(msg) => write!(f, "file truncated: {msg}"),
+            TardisError::FileCorrupted(msg) => write!(f, "file corrupted: {msg}"),
+            TardisError::MalformedManifest(msg) => write!(f, "malformed manifest: {msg}"),
+            TardisError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported manifest version {version} (this build supports up to {})",
+                crate::serialization::FORMAT_VERSION
+            ),
+            TardisError::UnsupportedExtension(tag) => {
+                write!(f, "unsupported critical extension {tag:#06x}")
+            }
+            TardisError::UnsupportedCodec(name) => {
+                write!(f, "codec {name} is not supported by this build")
+            }
+            TardisError::CodecError(msg) => write!(f, "codec error: {msg}"),
+            TardisError::AuthenticationError => write!(f, "resource failed authentication"),
+            TardisError::MissingSignature => write!(f, "file is not signed"),
+            TardisError::InvalidSignature => write!(f, "signature is invalid"),
+            TardisError::InvalidKey(msg) => write!(f, "invalid key: {msg}"),
+            TardisError::DecompressionError(msg) => write!(f, "decompression failed: {msg}"),
+            TardisError::IntegrityError(msg) => write!(f, "integrity check failed: {msg}"),
+            TardisError::MissingLoader => write!(f, "no loader was given"),
+            TardisError::NoGuests => write!(f, "no guests were given"),
+            TardisError::UnknownGuest(name) => write!(f, "no runnable guest named {name}"),
+            TardisError::UnknownDependency { guest, after } => write!(
+                f,
+                "{guest} is started after {after}, but no guest that is run has that name"
+            ),
+            TardisError::DependencyCycle(names) => {
+                write!(f, "guests {} depend on each other", names.join(", "))
+            }
+            TardisError::TooManyGuests(n) => write!(f, "too many guests ({n})"),
+            TardisError::TooLarge {
+                what,
+                size,
+                max_size,
+            } => write!(
+                f,
+                "{what} would be {size}, which exceeds the maximum of {max_size}"
+            ),
+            TardisError::SyscallError { syscall, errno } => {
+                write!(f, "{syscall} failed: {} ({errno})", errno.desc())
+            }
+            TardisError::RestartUnsupervised(name) => write!(
+                f,
+                "{name} can only be restarted by a loader that supervises its guests; set an \
+                 exit policy"
+            ),
+            TardisError::ForegroundSupervised(name) => write!(
+                f,
+                "{name} runs in the foreground, replacing the loader, so the guests can't also \
+                 be supervised with an exit policy"
+            ),
+            TardisError::ForegroundNotLast(name) => write!(
+                f,
+                "{name} runs in the foreground, replacing the loader, so it has to be started \
+                 last; no other guest can be started after it or run in the foreground"
+            ),
+            TardisError::NotReady(name) => {
+                write!(f, "guest {name} stopped or timed out before it was ready")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TardisError {}
+
+impl From<std::io::Error> for TardisError {
+    fn from(err: std::io::Error) -> Self {
+        // Errors raised by the std::io adapters in the stream module wrap a
+        // TardisError, which is unwrapped here so that it isn't lost.
+        let msg = err.to_string();
+        match err
+            .into_inner()
+            .map(|inner| inner.downcast::<TardisError>())
+        {
+            Some(Ok(inner)) => *inner,
+            _ => TardisError::FilesystemError(msg),
+        }
+    }
+}
+
+impl From<TardisError> for std::io::Error {
+    fn from(err: TardisError) -> Self {
+        match err {
+            TardisError::FilesystemError(msg) => std::io::Error::other(msg),
+            err => std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+impl From<deku::DekuError> for TardisError {
+    fn from(err: deku::DekuError) -> Self {
+        TardisError::MalformedManifest(err.to_string())
+    }
 }
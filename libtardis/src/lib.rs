@@ -2,7 +2,13 @@
 
 extern crate alloc;
 
+pub mod codec;
 pub mod crypto;
 pub mod error;
+pub mod metadata;
+pub mod packer;
+pub mod runtime;
 pub mod serialization;
+pub mod signature;
+pub mod stream;
 pub mod syscall;
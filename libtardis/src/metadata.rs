@@ -0,0 +1,642 @@
+//! Metadata attached to the resources in a packed binary.
+//!
+//! Metadata is stored in the attribute area of each
+//! [`TardisResource`](crate::serialization::TardisResource) as a list of
+//! [`Extension`]s, so that new kinds of metadata can be added without
+//! breaking older readers.
+
+use crate::{codec::CodecId, error::TardisError, serialization::Extension};
+use derive_try_from_primitive::TryFromPrimitive;
+use ring::digest::{self, SHA256};
+
+/// Tags for the attributes that can be attached to a resource.
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+pub enum AttributeTag {
+    /// Name of the resource, as a UTF-8 string.
+    Name = 0x0001,
+    /// The [`ResourceKind`] of the resource, as a single byte.
+    Kind = 0x0002,
+    /// Size of the resource before it was compressed, as a u64.
+    OriginalSize = 0x0003,
+    /// Unix mode of the file that the resource was created from, as a u32.
+    Mode = 0x0004,
+    /// SHA-256 digest of the uncompressed resource.
+    Sha256 = 0x0005,
+    /// The [`CodecId`] of the codec used to compress the resource, as a single
+    /// byte. Resources without this attribute are compressed with LZ4. This
+    /// attribute is critical.
+    Codec = 0x8006,
+    /// The amount of uncompressed data stored in each chunk of the resource,
+    /// as a u32. Resources without this attribute are stored as a single sealed
+    /// block rather than as a stream of chunks. This attribute is critical.
+    ChunkSize = 0x8007,
+    /// Arguments that are passed to the guest before the ones given to the
+    /// packed binary, each terminated by a NUL byte. This attribute is
+    /// critical.
+    Args = 0x8008,
+    /// Environment variables that are set for the guest, as `NAME=value`
+    /// strings that are each terminated by a NUL byte. This attribute is
+    /// critical.
+    Env = 0x8009,
+    /// The [`RestartPolicy`] of the guest, as a [`Restart`] byte followed by
+    /// the maximum number of restarts in a row (0 for no limit) and the
+    /// initial delay in milliseconds, both as u32s. Guests without this
+    /// attribute aren't restarted.
+    Restart = 0x000a,
+    /// Marks the guest that the loader runs in its own process, after starting
+    /// the other guests in processes of their own. This attribute has no
+    /// value.
+    Foreground = 0x000b,
+    /// Names of the guests that have to be ready before the guest is started,
+    /// each terminated by a NUL byte. This attribute is critical.
+    After = 0x800c,
+    /// How the guest tells the loader that it's ready (see [`Readiness`]), as
+    /// a single byte that's followed by a number of milliseconds as a u32: the
+    /// timeout for [`Readiness::Notify`], or the delay for
+    /// [`Readiness::Delay`]. This attribute is critical.
+    Ready = 0x800d,
+}
+
+/// Delay, in milliseconds, before the first restart of a guest that doesn't
+/// pick its own.
+pub const DEFAULT_RESTART_DELAY: u32 = 100;
+
+/// Longest delay, in milliseconds, between two restarts of a guest. A guest
+/// that runs for at least this long before it exits is considered to have
+/// started properly, so its next restart isn't delayed any further.
+pub const MAX_RESTART_DELAY: u32 = 60_000;
+
+/// Time, in milliseconds, that a guest with [`Readiness::Notify`] is given to
+/// say that it's ready, unless it picks its own.
+pub const DEFAULT_READY_TIMEOUT: u32 = 90_000;
+
+/// The kind of data stored in a resource.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, TryFromPrimitive)]
+pub enum ResourceKind {
+    /// An executable (e.g. an ELF binary) that the loader runs.
+    #[default]
+    Executable = 0,
+    /// Arbitrary data that the loader doesn't run.
+    Data = 1,
+    /// A script with a `#!` interpreter line that the loader runs.
+    Script = 2,
+}
+
+impl ResourceKind {
+    /// Guess the kind of a resource from its contents.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(b"\x7fELF") {
+            ResourceKind::Executable
+        } else if data.starts_with(b"#!") {
+            ResourceKind::Script
+        } else {
+            ResourceKind::Data
+        }
+    }
+
+    /// Return the name of the kind, as shown to users.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResourceKind::Executable => "executable",
+            ResourceKind::Data => "data",
+            ResourceKind::Script => "script",
+        }
+    }
+
+    /// Returns `true` if the loader should run resources of this kind.
+    pub fn is_runnable(&self) -> bool {
+        *self != ResourceKind::Data
+    }
+}
+
+/// When a supervising loader restarts a guest that exited.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, TryFromPrimitive)]
+pub enum Restart {
+    /// The guest isn't restarted.
+    #[default]
+    No = 0,
+    /// The guest is restarted if it exits with a non-zero status or is killed
+    /// by a signal.
+    OnFailure = 1,
+    /// The guest is restarted whenever it exits.
+    Always = 2,
+}
+
+impl Restart {
+    /// Return the name of the setting, as shown to users.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Restart::No => "no",
+            Restart::OnFailure => "on-failure",
+            Restart::Always => "always",
+        }
+    }
+}
+
+/// How a supervising loader restarts a guest that exited.
+///
+/// Restarts are delayed with an exponential backoff: the first one comes after
+/// `delay` milliseconds, and each one after that waits twice as long as the
+/// previous one, up to [`MAX_RESTART_DELAY`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RestartPolicy {
+    /// When the guest is restarted.
+    pub restart: Restart,
+
+    /// How many times in a row the guest can be restarted, or `None` for no
+    /// limit.
+    pub max_restarts: Option<u32>,
+
+    /// Delay before the first restart, in milliseconds.
+    pub delay: u32,
+}
+
+impl RestartPolicy {
+    /// Return whether a guest that exited with `status` (where 0 means
+    /// success), after being restarted `restarts` times in a row, should be
+    /// restarted again.
+    pub fn should_restart(&self, status: i32, restarts: u32) -> bool {
+        let wanted = match self.restart {
+            Restart::No => false,
+            Restart::OnFailure => status != 0,
+            Restart::Always => true,
+        };
+        wanted && self.max_restarts.is_none_or(|max| restarts < max)
+    }
+
+    /// Return how many milliseconds to wait before restarting a guest that has
+    /// already been restarted `restarts` times in a row.
+    pub fn delay(&self, restarts: u32) -> u32 {
+        let factor = 1u64 << restarts.min(32);
+        (u64::from(self.delay) * factor).min(u64::from(MAX_RESTART_DELAY.max(self.delay))) as u32
+    }
+}
+
+/// How a guest tells the loader that it's ready, so that the guests that are
+/// started after it can be. Guests that don't pick one are ready as soon as
+/// they've been started.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Readiness {
+    /// The guest writes `READY=1` to the file descriptor whose number is given
+    /// in its environment, within this many milliseconds of being started (or
+    /// whenever it likes, if this is 0).
+    Notify(u32),
+    /// The guest is ready once it's been running for this many milliseconds.
+    Delay(u32),
+}
+
+/// Metadata describing a resource.
+///
+/// Every field is optional, since files packed by older versions of Tardis
+/// don't record any metadata for their resources.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ResourceMetadata {
+    /// The name of the resource. Defaults to the name of the input file.
+    pub name: Option<String>,
+
+    /// The kind of data stored in the resource.
+    pub kind: Option<ResourceKind>,
+
+    /// The size of the resource before it was compressed.
+    pub original_size: Option<u64>,
+
+    /// The Unix mode of the file that the resource was created from.
+    pub mode: Option<u32>,
+
+    /// SHA-256 digest of the uncompressed resource.
+    pub sha256: Option<[u8; 32]>,
+
+    /// The codec used to compress the resource.
+    pub codec: Option<CodecId>,
+
+    /// The amount of uncompressed data stored in each chunk of the resource.
+    pub chunk_size: Option<u32>,
+
+    /// Arguments passed to the guest before the ones given to the packed
+    /// binary.
+    pub args: Vec<String>,
+
+    /// Environment variables set for the guest, on top of the ones that the
+    /// packed binary was run with.
+    pub env: Vec<(String, String)>,
+
+    /// How a supervising loader restarts the guest when it exits.
+    pub restart: Option<RestartPolicy>,
+
+    /// Whether the loader runs the guest in its own process.
+    pub foreground: bool,
+
+    /// Names of the guests that have to be ready before this one is started.
+    pub after: Vec<String>,
+
+    /// How the guest tells the loader that it's ready.
+    pub ready: Option<Readiness>,
+}
+
+impl ResourceMetadata {
+    /// Create metadata for a resource with the given name.
+    pub fn named(name: &str) -> Self {
+        ResourceMetadata {
+            name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Fill in the size and digest of the uncompressed resource.
+    pub fn describe(&mut self, data: &[u8]) {
+        self.original_size = Some(data.len() as u64);
+        self.sha256 = Some(sha256(data));
+    }
+
+    /// Check that `data` matches the size and digest recorded in the metadata.
+    pub fn verify(&self, data: &[u8]) -> Result<(), TardisError> {
+        self.verify_summary(data.len() as u64, &sha256(data))
+    }
+
+    /// Check that the size and digest of some data match the ones recorded in
+    /// the metadata.
+    pub fn verify_summary(&self, size: u64, digest: &[u8; 32]) -> Result<(), TardisError> {
+        if let Some(expected) = self.original_size {
+            if expected != size {
+                return Err(TardisError::IntegrityError(format!(
+                    "expected {expected} bytes, got {size}"
+                )));
+            }
+        }
+        if let Some(expected) = self.sha256 {
+            if expected != *digest {
+                return Err(TardisError::IntegrityError("SHA-256 mismatch".into()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse metadata out of a list of resource attributes. Attributes that
+    /// don't describe metadata are ignored.
+    pub fn from_attributes(attributes: &[Extension]) -> Result<Self, TardisError> {
+        let mut metadata = ResourceMetadata::default();
+
+        for attr in attributes {
+            let malformed =
+                || TardisError::MalformedManifest(format!("bad attribute {:#06x}", attr.tag));
+            let Some(tag) = AttributeTag::try_from(attr.tag) else {
+                continue;
+            };
+
+            match tag {
+                AttributeTag::Name => {
+                    let name = String::from_utf8(attr.value.clone()).map_err(|_| malformed())?;
+                    metadata.name = Some(name);
+                }
+                AttributeTag::Kind => {
+                    let kind = match attr.value[..] {
+                        [kind] => ResourceKind::try_from(kind),
+                        _ => None,
+                    };
+                    metadata.kind = Some(kind.ok_or_else(malformed)?);
+                }
+                AttributeTag::OriginalSize => {
+                    let size = attr.value[..].try_into().map_err(|_| malformed())?;
+                    metadata.original_size = Some(u64::from_le_bytes(size));
+                }
+                AttributeTag::Mode => {
+                    let mode = attr.value[..].try_into().map_err(|_| malformed())?;
+                    metadata.mode = Some(u32::from_le_bytes(mode));
+                }
+                AttributeTag::Sha256 => {
+                    let digest = attr.value[..].try_into().map_err(|_| malformed())?;
+                    metadata.sha256 = Some(digest);
+                }
+                AttributeTag::Codec => {
+                    let codec = match attr.value[..] {
+                        [codec] => CodecId::try_from(codec),
+                        _ => None,
+                    };
+                    metadata.codec = Some(codec.ok_or_else(malformed)?);
+                }
+                AttributeTag::ChunkSize => {
+                    let size = attr.value[..].try_into().map_err(|_| malformed())?;
+                    metadata.chunk_size = Some(u32::from_le_bytes(size));
+                }
+                AttributeTag::Args => {
+                    metadata.args = parse_strings(&attr.value).ok_or_else(malformed)?;
+                }
+                AttributeTag::Env => {
+                    metadata.env = parse_strings(&attr.value)
+                        .ok_or_else(malformed)?
+                        .into_iter()
+                        .map(|var| match var.split_once('=') {
+                            Some((name, value)) if !name.is_empty() => {
+                                Ok((name.to_string(), value.to_string()))
+                            }
+                            _ => Err(malformed()),
+                        })
+                        .collect::<Result<_, _>>()?;
+                }
+                AttributeTag::Restart => {
+                    let value: [u8; 9] = attr.value[..].try_into().map_err(|_| malformed())?;
+                    let restart = Restart::try_from(value[0]).ok_or_else(malformed)?;
+                    let max_restarts = u32::from_le_bytes(value[1..5].try_into().unwrap());
+                    metadata.restart = Some(RestartPolicy {
+                        restart,
+                        max_restarts: (max_restarts != 0).then_some(max_restarts),
+                        delay: u32::from_le_bytes(value[5..].try_into().unwrap()),
+                    });
+                }
+                AttributeTag::Foreground => {
+                    if !attr.value.is_empty() {
+                        return Err(malformed());
+                    }
+                    metadata.foreground = true;
+                }
+                AttributeTag::After => {
+                    metadata.after = parse_strings(&attr.value).ok_or_else(malformed)?;
+                }
+                AttributeTag::Ready => {
+                    let ready = match attr.value[..] {
+                        [kind, ref millis @ ..] => {
+                            let millis =
+                                u32::from_le_bytes(millis.try_into().map_err(|_| malformed())?);
+                            match kind {
+                                0 => Readiness::Notify(millis),
+                                1 => Readiness::Delay(millis),
+                                _ => return Err(malformed()),
+                            }
+                        }
+                        _ => return Err(malformed()),
+                    };
+                    metadata.ready = Some(ready);
+                }
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    /// Convert the metadata into a list of resource attributes.
+    pub fn to_attributes(&self) -> Result<Vec<Extension>, TardisError> {
+        let mut attributes = Vec::new();
+
+        if let Some(name) = &self.name {
+            attributes.push(Extension::new(
+                AttributeTag::Name as u16,
+                name.as_bytes().to_vec(),
+            )?);
+        }
+        if let Some(kind) = self.kind {
+            attributes.push(Extension::new(AttributeTag::Kind as u16, vec![kind as u8])?);
+        }
+        if let Some(size) = self.original_size {
+            let value = size.to_le_bytes().to_vec();
+            attributes.push(Extension::new(AttributeTag::OriginalSize as u16, value)?);
+        }
+        if let Some(mode) = self.mode {
+            let value = mode.to_le_bytes().to_vec();
+            attributes.push(Extension::new(AttributeTag::Mode as u16, value)?);
+        }
+        if let Some(digest) = self.sha256 {
+            attributes.push(Extension::new(
+                AttributeTag::Sha256 as u16,
+                digest.to_vec(),
+            )?);
+        }
+        if let Some(codec) = self.codec {
+            attributes.push(Extension::new(
+                AttributeTag::Codec as u16,
+                vec![codec as u8],
+            )?);
+        }
+        if let Some(size) = self.chunk_size {
+            let value = size.to_le_bytes().to_vec();
+            attributes.push(Extension::new(AttributeTag::ChunkSize as u16, value)?);
+        }
+        if !self.args.is_empty() {
+            let value = join_strings(self.args.iter().map(String::as_str));
+            attributes.push(Extension::new(AttributeTag::Args as u16, value)?);
+        }
+        if !self.env.is_empty() {
+            let vars: Vec<_> = self
+                .env
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect();
+            let value = join_strings(vars.iter().map(String::as_str));
+            attributes.push(Extension::new(AttributeTag::Env as u16, value)?);
+        }
+        if let Some(policy) = self.restart {
+            let mut value = vec![policy.restart as u8];
+            value.extend(policy.max_restarts.unwrap_or(0).to_le_bytes());
+            value.extend(policy.delay.to_le_bytes());
+            attributes.push(Extension::new(AttributeTag::Restart as u16, value)?);
+        }
+        if self.foreground {
+            attributes.push(Extension::new(AttributeTag::Foreground as u16, Vec::new())?);
+        }
+        if !self.after.is_empty() {
+            let value = join_strings(self.after.iter().map(String::as_str));
+            attributes.push(Extension::new(AttributeTag::After as u16, value)?);
+        }
+        if let Some(ready) = self.ready {
+            let value = match ready {
+                Readiness::Notify(timeout) => [&[0], &timeout.to_le_bytes()[..]].concat(),
+                Readiness::Delay(delay) => [&[1], &delay.to_le_bytes()[..]].concat(),
+            };
+            attributes.push(Extension::new(AttributeTag::Ready as u16, value)?);
+        }
+
+        Ok(attributes)
+    }
+}
+
+/// Return the order in which guests are started, as indices into `guests`.
+/// Each guest comes after the guests named in its [`after`] list, and guests
+/// that don't depend on each other keep their order.
+///
+/// Fails with [`TardisError::UnknownDependency`] if a guest is started after
+/// one that isn't in `guests`, and with [`TardisError::DependencyCycle`] if
+/// guests depend on each other.
+///
+/// [`after`]: ResourceMetadata::after
+pub fn start_order(guests: &[&ResourceMetadata]) -> Result<Vec<usize>, TardisError> {
+    let name = |guest: &ResourceMetadata| guest.name.clone().unwrap_or_else(|| "unnamed".into());
+    let position = |guest: &ResourceMetadata, after: &str| {
+        guests
+            .iter()
+            .position(|other| other.name.as_deref() == Some(after))
+            .ok_or_else(|| TardisError::UnknownDependency {
+                guest: name(guest),
+                after: after.into(),
+            })
+    };
+    let dependencies = guests
+        .iter()
+        .map(|guest| {
+            guest
+                .after
+                .iter()
+                .map(|after| position(guest, after))
+                .collect()
+        })
+        .collect::<Result<Vec<Vec<usize>>, _>>()?;
+
+    let mut order = Vec::with_capacity(guests.len());
+    while order.len() < guests.len() {
+        let next = (0..guests.len()).find(|index| {
+            !order.contains(index) && dependencies[*index].iter().all(|dep| order.contains(dep))
+        });
+        match next {
+            Some(index) => order.push(index),
+            None => {
+                let names = (0..guests.len())
+                    .filter(|index| !order.contains(index))
+                    .map(|index| name(guests[index]))
+                    .collect();
+                return Err(TardisError::DependencyCycle(names));
+            }
+        }
+    }
+    Ok(order)
+}
+
+/// Parse a list of NUL-terminated UTF-8 strings.
+fn parse_strings(value: &[u8]) -> Option<Vec<String>> {
+    let body = value.strip_suffix(b"\0")?;
+    body.split(|b| *b == 0)
+        .map(|s| String::from_utf8(s.to_vec()).ok())
+        .collect()
+}
+
+/// Encode a list of strings that don't contain NUL bytes, terminating each of
+/// them with a NUL byte.
+fn join_strings<'a>(strings: impl Iterator<Item = &'a str>) -> Vec<u8> {
+    let mut value = Vec::new();
+    for s in strings {
+        value.extend(s.as_bytes());
+        value.push(0);
+    }
+    value
+}
+
+/// Compute the SHA-256 digest of a block of data.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let digest = digest::digest(&SHA256, data);
+    digest.as_ref().try_into().unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        start_order, AttributeTag, Readiness, ResourceKind, ResourceMetadata, Restart,
+        RestartPolicy,
+    };
+    use crate::{codec::CodecId, error::TardisError, serialization::EXTENSION_CRITICAL};
+
+    #[test]
+    fn test_attributes_roundtrip() {
+        let mut metadata = ResourceMetadata::named("guest");
+        metadata.kind = Some(ResourceKind::Script);
+        metadata.mode = Some(0o755);
+        metadata.codec = Some(CodecId::Store);
+        metadata.chunk_size = Some(1 << 20);
+        metadata.args = vec!["--flag".into(), "".into(), "two words".into()];
+        metadata.env = vec![("A".into(), "1=2".into()), ("EMPTY".into(), "".into())];
+        metadata.restart = Some(RestartPolicy {
+            restart: Restart::OnFailure,
+            max_restarts: Some(3),
+            delay: 250,
+        });
+        metadata.foreground = true;
+        metadata.after = vec!["helper".into(), "db".into()];
+        metadata.ready = Some(Readiness::Delay(500));
+        metadata.describe(b"#!/bin/sh\necho hello\n");
+
+        let attributes = metadata.to_attributes().unwrap();
+        let parsed = ResourceMetadata::from_attributes(&attributes).unwrap();
+        assert_eq!(parsed, metadata);
+
+        metadata.ready = Some(Readiness::Notify(2500));
+        let parsed = ResourceMetadata::from_attributes(&metadata.to_attributes().unwrap()).unwrap();
+        assert_eq!(parsed, metadata);
+    }
+
+    #[test]
+    fn test_codec_is_critical() {
+        assert_ne!(AttributeTag::Codec as u16 & EXTENSION_CRITICAL, 0);
+        assert_eq!(AttributeTag::try_from(0x8006), Some(AttributeTag::Codec));
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        let policy = RestartPolicy {
+            restart: Restart::OnFailure,
+            max_restarts: Some(2),
+            delay: 100,
+        };
+        assert!(policy.should_restart(1, 0));
+        assert!(policy.should_restart(1, 1));
+        assert!(!policy.should_restart(1, 2));
+        assert!(!policy.should_restart(0, 0));
+
+        let policy = RestartPolicy {
+            restart: Restart::Always,
+            max_restarts: None,
+            ..policy
+        };
+        assert!(policy.should_restart(0, 1000));
+        assert_eq!(policy.delay(0), 100);
+        assert_eq!(policy.delay(3), 800);
+        assert_eq!(policy.delay(100), super::MAX_RESTART_DELAY);
+    }
+
+    #[test]
+    fn test_start_order() {
+        let guest = |name: &str, after: &[&str]| ResourceMetadata {
+            after: after.iter().map(|name| name.to_string()).collect(),
+            ..ResourceMetadata::named(name)
+        };
+        let (a, b, c) = (guest("a", &["c"]), guest("b", &[]), guest("c", &["b"]));
+        assert_eq!(start_order(&[&a, &b, &c]).unwrap(), vec![1, 2, 0]);
+        let (d, e) = (guest("d", &[]), guest("e", &[]));
+        assert_eq!(start_order(&[&d, &e]).unwrap(), vec![0, 1]);
+
+        let missing = guest("a", &["nope"]);
+        assert!(matches!(
+            start_order(&[&missing]),
+            Err(TardisError::UnknownDependency { guest, after }) if guest == "a" && after == "nope"
+        ));
+        let (x, y) = (guest("x", &["y"]), guest("y", &["x"]));
+        assert!(matches!(
+            start_order(&[&x, &y, &b]),
+            Err(TardisError::DependencyCycle(names)) if names == ["x", "y"]
+        ));
+        let selfish = guest("s", &["s"]);
+        assert!(matches!(
+            start_order(&[&selfish]),
+            Err(TardisError::DependencyCycle(names)) if names == ["s"]
+        ));
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut metadata = ResourceMetadata::default();
+        assert!(metadata.verify(b"anything").is_ok());
+
+        metadata.describe(b"hello");
+        assert!(metadata.verify(b"hello").is_ok());
+        assert!(metadata.verify(b"hellp").is_err());
+        assert!(metadata.verify(b"hello!").is_err());
+    }
+
+    #[test]
+    fn test_detect_kind() {
+        assert_eq!(
+            ResourceKind::detect(b"\x7fELF\x02\x01"),
+            ResourceKind::Executable
+        );
+        assert_eq!(ResourceKind::detect(b"#!/bin/sh"), ResourceKind::Script);
+        assert_eq!(ResourceKind::detect(b"hello"), ResourceKind::Data);
+    }
+}
@@ -0,0 +1,581 @@
+//! Builder for packed binaries.
+//!
+//! A [`Packer`] collects a loader and a list of guests, and writes them out as
+//! a packed binary:
+//!
+//! ```no_run
+//! # use libtardis::{codec::{self, CodecId}, packer::Packer};
+//! # fn main() -> Result<(), libtardis::error::TardisError> {
+//! let loader = std::fs::read("loader")?;
+//! let mut output = std::fs::File::create("packed")?;
+//! Packer::new()
+//!     .loader(loader)
+//!     .codec(codec::new(CodecId::Lz4, None)?)
+//!     .add_guest("hello", std::fs::File::open("hello")?)
+//!     .write_to(&mut output)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The loader isn't part of this crate, since it's built on top of it; the
+//! `tardis` executable embeds a loader for every combination of codecs.
+
+use crate::{
+    codec::{self, Codec, CodecId},
+    error::TardisError,
+    metadata::{self, ResourceKind, ResourceMetadata, Restart},
+    serialization::{
+        file_digest, EndMarker, ExitPolicy, Extension, FileDigest, HeaderTag, ManifestHeader,
+        ResourceHeader, FORMAT_VERSION,
+    },
+    signature,
+    stream::{self, Binding, DEFAULT_CHUNK_SIZE},
+};
+use deku::DekuContainerWrite;
+use ring::signature::Ed25519KeyPair;
+use std::io::{Read, Seek, Write};
+
+/// A guest that hasn't been packed yet.
+struct PendingGuest<'a> {
+    metadata: ResourceMetadata,
+    input: Box<dyn Read + 'a>,
+    codec: Option<Box<dyn Codec>>,
+}
+
+/// Builder for a packed binary. See the [module documentation](self) for an
+/// example.
+#[derive(Default)]
+pub struct Packer<'a> {
+    loader: Option<Vec<u8>>,
+    guests: Vec<PendingGuest<'a>>,
+    codec: Option<Box<dyn Codec>>,
+    chunk_size: Option<u32>,
+    key_pair: Option<Ed25519KeyPair>,
+    max_size: Option<u64>,
+    flag_prefix: Option<String>,
+    exit_policy: Option<ExitPolicy>,
+    grace_period: Option<u32>,
+}
+
+/// Description of a packed binary written by [`Packer::write_to`].
+#[derive(Debug)]
+pub struct PackSummary {
+    /// The size of the packed binary.
+    pub size: u64,
+
+    /// The header of every resource, in order.
+    pub resources: Vec<ResourceHeader>,
+}
+
+impl<'a> Packer<'a> {
+    /// Create a packer without a loader or any guests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the loader that the guests are appended to. The loader must be able
+    /// to decompress every codec that the guests are compressed with.
+    pub fn loader(mut self, loader: impl Into<Vec<u8>>) -> Self {
+        self.loader = Some(loader.into());
+        self
+    }
+
+    /// Set the codec used for guests that don't have their own. Defaults to
+    /// LZ4.
+    pub fn codec(mut self, codec: Box<dyn Codec>) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Set the amount of uncompressed data stored in each chunk of a guest.
+    /// Defaults to [`DEFAULT_CHUNK_SIZE`].
+    pub fn chunk_size(mut self, chunk_size: u32) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Sign the packed binary with an Ed25519 key. The key's public half is
+    /// embedded into the loader, which then refuses to run files that aren't
+    /// signed with it.
+    pub fn sign_with(mut self, key_pair: Ed25519KeyPair) -> Self {
+        self.key_pair = Some(key_pair);
+        self
+    }
+
+    /// Fail with [`TardisError::TooLarge`] if the packed binary would be larger
+    /// than `max_size` bytes.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Set the prefix of the built-in flags (such as `--tardis-info`) that the
+    /// loader handles instead of passing them to the guests. Defaults to
+    /// [`DEFAULT_FLAG_PREFIX`](crate::serialization::DEFAULT_FLAG_PREFIX).
+    pub fn flag_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.flag_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Turn off the loader's built-in flags, so that every argument is passed
+    /// to the guests.
+    pub fn disable_flags(self) -> Self {
+        self.flag_prefix("")
+    }
+
+    /// Make the loader supervise the guests when it runs more than one of them:
+    /// it waits for them, and exits with a status chosen by `policy`. By
+    /// default, the loader exits as soon as the guests have been started.
+    pub fn exit_policy(mut self, policy: ExitPolicy) -> Self {
+        self.exit_policy = Some(policy);
+        self
+    }
+
+    /// Set how many seconds a supervising loader gives its guests to exit after
+    /// asking them to (because it received a signal such as `SIGTERM`, or
+    /// because of its exit policy), before it kills them. Defaults to
+    /// [`DEFAULT_GRACE_PERIOD`](crate::serialization::DEFAULT_GRACE_PERIOD).
+    pub fn grace_period(mut self, seconds: u32) -> Self {
+        self.grace_period = Some(seconds);
+        self
+    }
+
+    /// Add a guest, read from `input`, under the given name. Its kind is
+    /// detected from its contents.
+    pub fn add_guest(self, name: &str, input: impl Read + 'a) -> Self {
+        self.add_guest_with(ResourceMetadata::named(name), input, None)
+    }
+
+    /// Add a guest with the given metadata, compressed with `codec` (or with
+    /// the packer's codec if it's `None`). If the metadata doesn't record the
+    /// guest's kind, it's detected from its contents.
+    pub fn add_guest_with(
+        mut self,
+        metadata: ResourceMetadata,
+        input: impl Read + 'a,
+        codec: Option<Box<dyn Codec>>,
+    ) -> Self {
+        self.guests.push(PendingGuest {
+            metadata,
+            input: Box::new(input),
+            codec,
+        });
+        self
+    }
+
+    /// Write the packed binary to `output`, and return a description of it.
+    /// Offsets in the manifest are relative to the start of `output`, so
+    /// nothing must have been written to it yet.
+    ///
+    /// If an error is returned, `output` is left holding an incomplete file.
+    pub fn write_to<W: Write + Seek>(self, output: &mut W) -> Result<PackSummary, TardisError> {
+        let mut loader = self.loader.ok_or(TardisError::MissingLoader)?;
+        if self.guests.is_empty() {
+            return Err(TardisError::NoGuests);
+        }
+        if u32::try_from(self.guests.len()).is_err() {
+            return Err(TardisError::TooManyGuests(self.guests.len()));
+        }
+
+        // Every guest's kind is needed to tell which guests are run, so the
+        // start of each one is peeked at before anything is compressed
+        let mut guests = Vec::with_capacity(self.guests.len());
+        for mut guest in self.guests {
+            let mut magic = Vec::new();
+            (&mut guest.input).take(4).read_to_end(&mut magic)?;
+            guest
+                .metadata
+                .kind
+                .get_or_insert_with(|| ResourceKind::detect(&magic));
+            guests.push((guest, magic));
+        }
+        let metadata: Vec<_> = guests.iter().map(|(guest, _)| &guest.metadata).collect();
+        check_guests(&metadata, self.exit_policy.as_ref())?;
+
+        // Loaders for signed files only run files signed with the same key
+        if let Some(key_pair) = &self.key_pair {
+            signature::embed_public_key(&mut loader, &signature::public_key(key_pair))?;
+        }
+
+        // Every packed file gets a random identifier that its guests are bound to
+        let bundle_id = Binding::new_bundle_id();
+        let default_codec = match self.codec {
+            Some(codec) => codec,
+            None => codec::new(CodecId::Lz4, None)?,
+        };
+        let chunk_size = self.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+
+        output.write_all(&loader)?;
+
+        let mut resources = Vec::new();
+        for (index, (guest, magic)) in guests.into_iter().enumerate() {
+            let PendingGuest {
+                metadata,
+                input,
+                codec,
+            } = guest;
+            let binding = Binding {
+                bundle_id,
+                index: index as u32,
+            };
+            let codec = codec.as_deref().unwrap_or(&*default_codec);
+            resources.push(stream::write_resource(
+                &mut (&magic[..]).chain(input),
+                output,
+                metadata,
+                codec,
+                chunk_size,
+                Some(binding),
+            )?);
+        }
+
+        let bundle_ext = Extension::new(HeaderTag::BundleId as u16, bundle_id.to_vec())?;
+        let mut extensions = vec![bundle_ext];
+        if let Some(prefix) = self.flag_prefix {
+            extensions.push(Extension::new(
+                HeaderTag::FlagPrefix as u16,
+                prefix.into_bytes(),
+            )?);
+        }
+        if let Some(policy) = self.exit_policy {
+            policy.primary_index(&resources)?;
+            extensions.push(policy.to_extension()?);
+        }
+        if let Some(seconds) = self.grace_period {
+            extensions.push(Extension::new(
+                HeaderTag::GracePeriod as u16,
+                seconds.to_le_bytes().to_vec(),
+            )?);
+        }
+        let mut header = ManifestHeader::new(loader.len() as u64, extensions)?;
+        if let Some(key_pair) = &self.key_pair {
+            header = signature::sign(key_pair, &loader, &header, &resources)?;
+        }
+        let size = write_manifest(output, &loader, &resources, &header)?;
+        if let Some(max_size) = self.max_size {
+            if size > max_size {
+                return Err(TardisError::TooLarge {
+                    what: "the packed file's size in bytes",
+                    size,
+                    max_size,
+                });
+            }
+        }
+        Ok(PackSummary { size, resources })
+    }
+}
+
+/// Check that guests with the given metadata can be run the way that it says,
+/// by a loader that supervises them with `exit_policy` if there is one. Only
+/// the guests that are run are checked. They must be able to start in an
+/// order where each one comes after the ones it depends on, with the
+/// foreground guest, if there is one, last.
+pub fn check_guests(
+    guests: &[&ResourceMetadata],
+    exit_policy: Option<&ExitPolicy>,
+) -> Result<(), TardisError> {
+    let runnable: Vec<_> = guests
+        .iter()
+        .copied()
+        .filter(|metadata| metadata.kind.unwrap_or_default().is_runnable())
+        .collect();
+    metadata::start_order(&runnable)?;
+    let mut foreground = runnable.iter().filter(|metadata| metadata.foreground);
+    if let Some(first) = foreground.next() {
+        let name = first.name.as_deref().unwrap_or("unnamed");
+        let started_after = runnable
+            .iter()
+            .any(|metadata| metadata.after.iter().any(|after| after == name));
+        if started_after || foreground.next().is_some() {
+            return Err(TardisError::ForegroundNotLast(name.into()));
+        }
+    }
+    for metadata in runnable {
+        let name = || metadata.name.clone().unwrap_or_else(|| "unnamed".into());
+        let restarts = metadata
+            .restart
+            .is_some_and(|restart| restart.restart != Restart::No);
+        if restarts && exit_policy.is_none() {
+            return Err(TardisError::RestartUnsupervised(name()));
+        }
+        if metadata.foreground && exit_policy.is_some() {
+            return Err(TardisError::ForegroundSupervised(name()));
+        }
+    }
+    Ok(())
+}
+
+/// Write the manifest of a packed binary after its resources, followed by the
+/// digest of the file and the [`EndMarker`]. `loader` must hold the bytes that
+/// precede the resources. Returns the position of `output` afterwards.
+pub fn write_manifest<W: Write + Seek>(
+    output: &mut W,
+    loader: &[u8],
+    resources: &[ResourceHeader],
+    header: &ManifestHeader,
+) -> Result<u64, TardisError> {
+    let n_resources =
+        u32::try_from(resources.len()).map_err(|_| TardisError::TooManyGuests(resources.len()))?;
+    let marker = EndMarker {
+        manifest_start: output.stream_position()?,
+        n_resources,
+        version: FORMAT_VERSION,
+    };
+    output.write_all(&header.to_bytes()?)?;
+    let sha256 = file_digest(&mut &loader[..], resources, header, &marker)?;
+    output.write_all(&FileDigest { sha256 }.to_bytes()?)?;
+    output.write_all(&marker.to_bytes()?)?;
+    Ok(output.stream_position()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Packer;
+    use crate::{
+        codec::Store,
+        error::TardisError,
+        metadata::{ResourceKind, ResourceMetadata, Restart, RestartPolicy},
+        serialization::{ExitPolicy, Manifest},
+        signature, stream,
+    };
+    use std::io::{Cursor, Seek, SeekFrom};
+
+    /// Read back every guest in a packed binary, along with its name and kind.
+    fn unpack(packed: &[u8]) -> Vec<(String, ResourceKind, Vec<u8>)> {
+        let mut input = Cursor::new(packed);
+        let manifest = Manifest::read_from(&mut input).unwrap();
+        let entries = manifest.entries(&mut input).unwrap();
+        entries
+            .into_iter()
+            .map(|entry| {
+                let metadata = entry.header.metadata().unwrap();
+                let mut data = Vec::new();
+                input.seek(SeekFrom::Start(entry.data_start)).unwrap();
+                stream::read_resource(&mut input, &entry.header, entry.binding, &mut data).unwrap();
+                (metadata.name.unwrap(), metadata.kind.unwrap(), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pack() {
+        let mut output = Cursor::new(Vec::new());
+        let summary = Packer::new()
+            .loader(b"fake loader".to_vec())
+            .codec(Box::new(Store))
+            .chunk_size(4)
+            .add_guest("script", &b"#!/bin/sh\necho hello\n"[..])
+            .add_guest("data", &b"hello"[..])
+            .write_to(&mut output)
+            .unwrap();
+
+        let packed = output.into_inner();
+        assert_eq!(summary.size, packed.len() as u64);
+        let manifest = Manifest::parse(&packed).unwrap();
+        assert_eq!(manifest.header.flag_prefix(), Some("--tardis-"));
+        assert_eq!(summary.resources.len(), 2);
+        assert!(packed.starts_with(b"fake loader"));
+        assert_eq!(
+            unpack(&packed),
+            vec![
+                (
+                    "script".into(),
+                    ResourceKind::Script,
+                    b"#!/bin/sh\necho hello\n".to_vec()
+                ),
+                ("data".into(), ResourceKind::Data, b"hello".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pack_flag_prefix() {
+        for (packer, prefix) in [
+            (Packer::new().flag_prefix("--app-"), Some("--app-")),
+            (Packer::new().disable_flags(), None),
+        ] {
+            let mut output = Cursor::new(Vec::new());
+            packer
+                .loader(b"loader".to_vec())
+                .add_guest("a", &b"hello"[..])
+                .write_to(&mut output)
+                .unwrap();
+            let manifest = Manifest::parse(output.get_ref()).unwrap();
+            assert_eq!(manifest.header.flag_prefix(), prefix);
+        }
+    }
+
+    #[test]
+    fn test_pack_errors() {
+        let mut output = Cursor::new(Vec::new());
+        let result = Packer::new().add_guest("a", &b""[..]).write_to(&mut output);
+        assert!(matches!(result, Err(TardisError::MissingLoader)));
+
+        let result = Packer::new()
+            .loader(b"loader".to_vec())
+            .write_to(&mut output);
+        assert!(matches!(result, Err(TardisError::NoGuests)));
+
+        let result = Packer::new()
+            .loader(b"loader".to_vec())
+            .codec(Box::new(Store))
+            .max_size(10)
+            .add_guest("a", &b"hello"[..])
+            .write_to(&mut output);
+        assert!(matches!(
+            result,
+            Err(TardisError::TooLarge { max_size: 10, .. })
+        ));
+    }
+
+    #[test]
+    fn test_pack_supervision_errors() {
+        let restarted = ResourceMetadata {
+            restart: Some(RestartPolicy {
+                restart: Restart::OnFailure,
+                max_restarts: None,
+                delay: 100,
+            }),
+            ..ResourceMetadata::named("restarted")
+        };
+        let foreground = ResourceMetadata {
+            foreground: true,
+            ..ResourceMetadata::named("foreground")
+        };
+        let script = &b"#!/bin/sh\n"[..];
+
+        // Only a supervising loader restarts guests
+        let mut output = Cursor::new(Vec::new());
+        let result = Packer::new()
+            .loader(b"loader".to_vec())
+            .add_guest_with(restarted.clone(), script, None)
+            .write_to(&mut output);
+        assert!(
+            matches!(result, Err(TardisError::RestartUnsupervised(ref name)) if name == "restarted")
+        );
+        // Nothing has been compressed yet
+        assert!(output.get_ref().is_empty());
+
+        let result = Packer::new()
+            .loader(b"loader".to_vec())
+            .exit_policy(ExitPolicy::AllSucceed)
+            .add_guest_with(restarted, script, None)
+            .write_to(&mut Cursor::new(Vec::new()));
+        assert!(result.is_ok());
+
+        // and a foreground guest replaces the loader, so it can't supervise
+        let result = Packer::new()
+            .loader(b"loader".to_vec())
+            .exit_policy(ExitPolicy::AllSucceed)
+            .add_guest("a", script)
+            .add_guest_with(foreground.clone(), script, None)
+            .write_to(&mut Cursor::new(Vec::new()));
+        assert!(
+            matches!(result, Err(TardisError::ForegroundSupervised(ref name)) if name == "foreground")
+        );
+
+        let result = Packer::new()
+            .loader(b"loader".to_vec())
+            .add_guest("a", script)
+            .add_guest_with(foreground.clone(), script, None)
+            .write_to(&mut Cursor::new(Vec::new()));
+        assert!(result.is_ok());
+
+        // The foreground guest has to be started last
+        let after = ResourceMetadata {
+            after: vec!["foreground".into()],
+            ..ResourceMetadata::named("a")
+        };
+        let result = Packer::new()
+            .loader(b"loader".to_vec())
+            .add_guest_with(after, script, None)
+            .add_guest_with(foreground.clone(), script, None)
+            .write_to(&mut Cursor::new(Vec::new()));
+        assert!(
+            matches!(result, Err(TardisError::ForegroundNotLast(ref name)) if name == "foreground")
+        );
+
+        let other = ResourceMetadata {
+            foreground: true,
+            ..ResourceMetadata::named("other")
+        };
+        let result = Packer::new()
+            .loader(b"loader".to_vec())
+            .add_guest_with(foreground, script, None)
+            .add_guest_with(other, script, None)
+            .write_to(&mut Cursor::new(Vec::new()));
+        assert!(matches!(result, Err(TardisError::ForegroundNotLast(_))));
+    }
+
+    #[test]
+    fn test_pack_start_order() {
+        let after = |name: &str, after: &str| ResourceMetadata {
+            after: vec![after.into()],
+            ..ResourceMetadata::named(name)
+        };
+        let script = &b"#!/bin/sh\n"[..];
+
+        // Guests can only be started after guests that are run
+        let mut output = Cursor::new(Vec::new());
+        let result = Packer::new()
+            .loader(b"loader".to_vec())
+            .add_guest("data", &b"hello"[..])
+            .add_guest_with(after("a", "data"), script, None)
+            .write_to(&mut output);
+        assert!(matches!(
+            result,
+            Err(TardisError::UnknownDependency { ref guest, ref after })
+                if guest == "a" && after == "data"
+        ));
+        assert!(output.get_ref().is_empty());
+
+        let result = Packer::new()
+            .loader(b"loader".to_vec())
+            .add_guest_with(after("a", "b"), script, None)
+            .add_guest_with(after("b", "a"), script, None)
+            .write_to(&mut Cursor::new(Vec::new()));
+        assert!(
+            matches!(result, Err(TardisError::DependencyCycle(ref names)) if *names == ["a", "b"])
+        );
+
+        let result = Packer::new()
+            .loader(b"loader".to_vec())
+            .add_guest_with(after("a", "b"), script, None)
+            .add_guest("b", script)
+            .write_to(&mut Cursor::new(Vec::new()));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_pack_signed() {
+        let pkcs8 = signature::generate_key_pair().unwrap();
+        let key_pair = signature::load_key_pair(&pkcs8).unwrap();
+        let public_key = signature::public_key(&key_pair);
+
+        let mut loader = b"fake loader ".to_vec();
+        loader.extend(signature::PUBLIC_KEY_MAGIC);
+        loader.extend([0; 32]);
+
+        let mut output = Cursor::new(Vec::new());
+        let summary = Packer::new()
+            .loader(loader)
+            .codec(Box::new(Store))
+            .sign_with(key_pair)
+            .add_guest("a", &b"hello"[..])
+            .write_to(&mut output)
+            .unwrap();
+
+        let packed = output.into_inner();
+        let manifest = Manifest::parse(&packed).unwrap();
+        let loader = &packed[..manifest.header.resources_start as usize];
+        signature::verify(&public_key, loader, &manifest.header, &summary.resources).unwrap();
+        assert_eq!(&packed[28..60], &public_key);
+
+        // The signature covers the loader, including the embedded key
+        let mut tampered = loader.to_vec();
+        tampered[0] ^= 1;
+        let result =
+            signature::verify(&public_key, &tampered, &manifest.header, &summary.resources);
+        assert!(matches!(result, Err(TardisError::InvalidSignature)));
+    }
+}
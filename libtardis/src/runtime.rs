@@ -0,0 +1,387 @@
+//! Building blocks for loaders.
+//!
+//! The stock loader is built from these steps, and custom loaders (e.g. ones
+//! that add their own logging or policy checks) can compose them differently:
+//!
+//! 1. [`Bundle::open_self`] locates the manifest of the running binary and
+//!    reads the header of every resource.
+//! 2. [`Bundle::entries`] lists the resources, whose metadata can be read with
+//!    [`ResourceHeader::metadata`](crate::serialization::ResourceHeader::metadata).
+//! 3. [`Bundle::materialize`] decompresses a guest into an in-memory file.
+//!    [`Bundle::extract_to`] writes a resource to a directory instead.
+//! 4. [`Guest::exec`] replaces the current process with the guest, and
+//!    [`Guest::spawn`] runs it in a new process.
+
+use crate::{
+    error::TardisError,
+    metadata::{ResourceKind, ResourceMetadata},
+    serialization::{Manifest, ResourceEntry},
+    signature,
+    stream::{self, StreamSummary},
+};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, AtFlags, FcntlArg, FdFlag, OFlag},
+    sys::{
+        memfd::{memfd_create, MemFdCreateFlag},
+        wait::waitpid,
+    },
+    unistd::{execveat, fork, pipe2, ForkResult, Pid},
+};
+use std::{
+    convert::Infallible,
+    ffi::CString,
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::fs::PermissionsExt,
+    },
+    path::{Component, Path, PathBuf},
+};
+
+/// Path to the packed binary that the current process is running from.
+pub const HOST: &str = "/proc/self/exe";
+
+/// A packed binary whose manifest has been read.
+pub struct Bundle {
+    file: File,
+    manifest: Manifest,
+    entries: Vec<ResourceEntry>,
+}
+
+impl Bundle {
+    /// Open the packed binary that the current process is running from.
+    pub fn open_self() -> Result<Self, TardisError> {
+        // The running binary must have been packed, so if it has no end marker
+        // then it lost its end
+        let file = open_host(Path::new(HOST))?;
+        let manifest = Manifest::read_packed(&mut Host(&file))?;
+        Self::with_manifest(file, manifest)
+    }
+
+    /// Open a packed binary.
+    pub fn open(path: &Path) -> Result<Self, TardisError> {
+        Self::from_file(open_host(path)?)
+    }
+
+    /// Read the manifest of a packed binary, and the header of every resource
+    /// in it.
+    pub fn from_file(file: File) -> Result<Self, TardisError> {
+        let manifest = Manifest::read_from(&mut Host(&file))?;
+        Self::with_manifest(file, manifest)
+    }
+
+    fn with_manifest(file: File, manifest: Manifest) -> Result<Self, TardisError> {
+        let entries = manifest.entries(&mut Host(&file))?;
+        Ok(Bundle {
+            file,
+            manifest,
+            entries,
+        })
+    }
+
+    /// The manifest of the packed binary.
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// The resources in the packed binary, in order.
+    pub fn entries(&self) -> &[ResourceEntry] {
+        &self.entries
+    }
+
+    /// Check that the packed binary was signed by the owner of `public_key`.
+    /// The signature covers the loader and the digest of every guest, which is
+    /// checked when the guest is materialized.
+    pub fn verify_signature(&self, public_key: &[u8; 32]) -> Result<(), TardisError> {
+        let mut host = Host(&self.file);
+        host.seek(SeekFrom::Start(0))?;
+        let mut loader = Vec::new();
+        host.take(self.manifest.header.resources_start)
+            .read_to_end(&mut loader)?;
+        let headers: Vec<_> = self
+            .entries
+            .iter()
+            .map(|entry| entry.header.clone())
+            .collect();
+        signature::verify(public_key, &loader, &self.manifest.header, &headers)
+    }
+
+    /// Decompress a resource into `output`. As with
+    /// [`stream::read_resource`], nothing written to `output` can be trusted if
+    /// this returns an error.
+    pub fn read_resource<W: Write>(
+        &self,
+        entry: &ResourceEntry,
+        output: &mut W,
+    ) -> Result<StreamSummary, TardisError> {
+        let mut host = Host(&self.file);
+        host.seek(SeekFrom::Start(entry.data_start))?;
+        let mut input = BufReader::new(host);
+        stream::read_resource(&mut input, &entry.header, entry.binding, output)
+    }
+
+    /// Decompress a guest into a new in-memory file. This also authenticates
+    /// the guest and checks it against the size and digest stored in its
+    /// metadata.
+    pub fn materialize(&self, entry: &ResourceEntry) -> Result<Guest, TardisError> {
+        let metadata = entry.header.metadata()?;
+
+        // The file is closed on exec, so that it doesn't leak into other
+        // guests. Guest::exec makes an exception for the guest that it runs.
+        let name = c"a";
+        let flags = MemFdCreateFlag::MFD_CLOEXEC;
+        let mut file = File::from(memfd_create(name, flags).map_err(syscall("memfd_create"))?);
+        let summary = self.read_resource(entry, &mut file)?;
+
+        Ok(Guest {
+            file,
+            metadata,
+            summary,
+        })
+    }
+
+    /// Decompress the resource at `index` into a new file in `dir`, named after
+    /// the resource and with its permissions, and return the file's path.
+    /// Resources whose names aren't a single path component are named after
+    /// their index instead, so that a packed file can't write outside `dir`.
+    /// Nothing is left behind if the resource can't be read.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is out of bounds.
+    pub fn extract_to(&self, index: usize, dir: &Path) -> Result<PathBuf, TardisError> {
+        let entry = &self.entries[index];
+        let metadata = entry.header.metadata()?;
+        let name = metadata
+            .name
+            .filter(|name| {
+                let mut components = Path::new(name).components();
+                matches!(
+                    (components.next(), components.next()),
+                    (Some(Component::Normal(_)), None)
+                )
+            })
+            .unwrap_or_else(|| format!("resource-{index}"));
+        let path = dir.join(name);
+
+        let mut output = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| TardisError::FilesystemError(format!("{}: {e}", path.display())))?;
+        if let Err(e) = self.read_resource(entry, &mut output) {
+            drop(output);
+            fs::remove_file(&path)?;
+            return Err(e);
+        }
+        if let Some(mode) = metadata.mode {
+            output.set_permissions(fs::Permissions::from_mode(mode))?;
+        }
+        Ok(path)
+    }
+}
+
+/// A guest that has been decompressed into an in-memory file.
+pub struct Guest {
+    /// The in-memory file holding the guest.
+    pub file: File,
+
+    /// The guest's metadata.
+    pub metadata: ResourceMetadata,
+
+    /// The sizes and digest of the guest's data, as it was read.
+    pub summary: StreamSummary,
+}
+
+impl Guest {
+    /// Replace the current process with the guest. This only returns if the
+    /// guest couldn't be run.
+    pub fn exec(&self, argv: &[CString], envp: &[CString]) -> Result<Infallible, TardisError> {
+        self.execveat(argv, envp)
+    }
+
+    fn execveat(&self, argv: &[CString], envp: &[CString]) -> Result<Infallible, TardisError> {
+        // Scripts are run by an interpreter that opens the script through
+        // /proc/self/fd, so their file can't be closed on exec
+        if self.metadata.kind == Some(ResourceKind::Script) {
+            fcntl(self.file.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::empty()))
+                .map_err(syscall("fcntl"))?;
+        }
+
+        let path = c"";
+        let flags = AtFlags::AT_EMPTY_PATH;
+        execveat(self.file.as_raw_fd(), path, argv, envp, flags).map_err(syscall("execveat"))
+    }
+
+    /// Run the guest in a new child process, and return the child's PID.
+    ///
+    /// If the guest can't be run, the child exits with status 127 and the
+    /// error is returned.
+    pub fn spawn(&self, argv: &[CString], envp: &[CString]) -> Result<Pid, TardisError> {
+        self.spawn_inheriting(argv, envp, &[])
+    }
+
+    /// Run the guest in a new child process, like [`Guest::spawn`], and let it
+    /// inherit `fds` even if they're closed on exec in the loader.
+    pub fn spawn_inheriting(
+        &self,
+        argv: &[CString],
+        envp: &[CString],
+        fds: &[RawFd],
+    ) -> Result<Pid, TardisError> {
+        // The child reports a failure to exec through a pipe, which is closed
+        // without anything being written to it if the exec succeeds
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC).map_err(syscall("pipe2"))?;
+        let (mut reader, mut writer) =
+            unsafe { (File::from_raw_fd(reader), File::from_raw_fd(writer)) };
+
+        match unsafe { fork() }.map_err(syscall("fork"))? {
+            ForkResult::Child => {
+                drop(reader);
+                let inherited = fds.iter().try_for_each(|fd| {
+                    fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                        .map(drop)
+                        .map_err(syscall("fcntl"))
+                });
+                let errno = match inherited.and_then(|()| self.execveat(argv, envp)) {
+                    Err(TardisError::SyscallError { errno, .. }) => errno,
+                    _ => Errno::EINVAL,
+                };
+                let _ = writer.write_all(&(errno as i32).to_ne_bytes());
+                unsafe { nix::libc::_exit(127) };
+            }
+            ForkResult::Parent { child } => {
+                drop(writer);
+                let mut errno = Vec::new();
+                reader.read_to_end(&mut errno)?;
+                match errno.try_into() {
+                    Ok(errno) => {
+                        let _ = waitpid(child, None);
+                        Err(TardisError::SyscallError {
+                            syscall: "execveat",
+                            errno: Errno::from_i32(i32::from_ne_bytes(errno)),
+                        })
+                    }
+                    Err(_) => Ok(child),
+                }
+            }
+        }
+    }
+}
+
+/// The file of a packed binary, whose I/O errors are reported as
+/// [`TardisError::HostReadError`] so that they can be told apart from errors
+/// writing guests out.
+struct Host<'a>(&'a File);
+
+impl Read for Host<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(host_error)
+    }
+}
+
+impl Seek for Host<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos).map_err(host_error)
+    }
+}
+
+fn host_error(err: io::Error) -> io::Error {
+    match err.kind() {
+        io::ErrorKind::Interrupted => err,
+        kind => io::Error::new(kind, TardisError::HostReadError(err.to_string())),
+    }
+}
+
+fn open_host(path: &Path) -> Result<File, TardisError> {
+    File::open(path).map_err(|e| TardisError::HostReadError(format!("{}: {e}", path.display())))
+}
+
+/// Wrap the error returned by a system call.
+fn syscall(syscall: &'static str) -> impl Fn(Errno) -> TardisError {
+    move |errno| TardisError::SyscallError { syscall, errno }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Bundle;
+    use crate::{codec::Store, error::TardisError, packer::Packer};
+    use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+    use std::{
+        fs::{self, File},
+        io::{Read, Seek, SeekFrom},
+    };
+
+    #[test]
+    fn test_materialize() {
+        let mut file = File::from(memfd_create(c"test", MemFdCreateFlag::empty()).unwrap());
+        Packer::new()
+            .loader(b"fake loader".to_vec())
+            .codec(Box::new(Store))
+            .add_guest("script", &b"#!/bin/sh\necho hello\n"[..])
+            .add_guest("data", &b"hello"[..])
+            .write_to(&mut file)
+            .unwrap();
+
+        let bundle = Bundle::from_file(file).unwrap();
+        assert_eq!(bundle.entries().len(), 2);
+
+        let mut guest = bundle.materialize(&bundle.entries()[1]).unwrap();
+        assert_eq!(guest.metadata.name.as_deref(), Some("data"));
+        assert_eq!(guest.summary.original_size, 5);
+        let mut data = Vec::new();
+        guest.file.seek(SeekFrom::Start(0)).unwrap();
+        guest.file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn test_host_read_error() {
+        // Errors reading the packed binary itself are told apart from others
+        let dir = std::env::temp_dir();
+        for path in [dir.join("tardis-nonexistent"), dir] {
+            assert!(matches!(
+                Bundle::open(&path),
+                Err(TardisError::HostReadError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_extract_to() {
+        let mut file = File::from(memfd_create(c"test", MemFdCreateFlag::empty()).unwrap());
+        Packer::new()
+            .loader(b"fake loader".to_vec())
+            .add_guest("data", &b"hello"[..])
+            .add_guest("../escape", &b"hello"[..])
+            .add_guest("sub/dir", &b"hello"[..])
+            .write_to(&mut file)
+            .unwrap();
+        let bundle = Bundle::from_file(file).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("tardis-extract-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<_> = (0..3)
+            .map(|index| bundle.extract_to(index, &dir).unwrap())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                dir.join("data"),
+                dir.join("resource-1"),
+                dir.join("resource-2")
+            ]
+        );
+        assert_eq!(fs::read(&paths[1]).unwrap(), b"hello");
+
+        // Existing files are left alone
+        fs::write(&paths[0], "old").unwrap();
+        assert!(bundle.extract_to(0, &dir).is_err());
+        assert_eq!(fs::read(&paths[0]).unwrap(), b"old");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,102 +1,892 @@
 //! Types and methods for serializing the compressed executable and writing
 //! it out as a new binary.
+//!
+//! A packed binary is laid out as follows:
+//!
+//! ```text
+//! +--------+-------------------+----------------+------------+-----------+
+//! | loader | TardisResource... | ManifestHeader | FileDigest | EndMarker |
+//! +--------+-------------------+----------------+------------+-----------+
+//! ```
+//!
+//! Version 0 files (written before the format was versioned) don't contain a
+//! [`ManifestHeader`], and their resources don't carry any attributes. Files
+//! written before version 3 don't contain a [`FileDigest`].
+//!
+//! All integers are stored as fixed-width little-endian values, so the layout
+//! doesn't depend on the architecture that the file was packed on.
 
-use crate::crypto;
+use crate::{
+    codec::{self, Codec, CodecId},
+    crypto,
+    error::TardisError,
+    metadata::{AttributeTag, ResourceMetadata},
+    stream::{self, Binding, DEFAULT_CHUNK_SIZE},
+};
+use core::mem::size_of;
 use deku::prelude::*;
-use lz4_flex::block::DecompressError;
 use ring::{
     aead::{self, BoundKey, UnboundKey, CHACHA20_POLY1305},
-    rand,
+    digest,
 };
+use std::io::{Cursor, Read, Seek, SeekFrom};
 
-/// End marker for Tardis's manifest.
-///
-/// The end marker helps Tardis figure out where the start of the compressed
-/// executable is, so that it knows what it needs to extract into memory. The
-/// end marker is placed at the end of the file so that the executable always
-/// knows where to look for it.
+/// The version of the manifest format written by this version of Tardis.
 ///
-/// The magic specified below is written on serialization. During deserialization,
-/// Deku will check for the presence of this magic to confirm that it's reading
-/// the correct data.
+/// Readers accept any version up to and including this one, and reject files
+/// written with a newer version. Starting with version 2, every resource is
+/// bound to the file it belongs to (see [`Binding`]), and starting with version
+/// 3, the file carries a [`FileDigest`].
+pub const FORMAT_VERSION: u32 = 3;
+
+/// Bit set on the tag of an [`Extension`] that readers must understand. A
+/// reader that comes across a critical extension that it doesn't recognize
+/// must refuse to load the file; non-critical extensions can be skipped.
+pub const EXTENSION_CRITICAL: u16 = 0x8000;
+
+/// The structures that a packed binary is made of.
 ///
-/// Note that the size of the `EndMarker` is always the magic length plus the
-/// size of the fields.
-#[derive(Debug, DekuRead, DekuWrite)]
-#[deku(magic = b"etar")]
-pub struct EndMarker {
-    /// The location in the binary where the manifest starts
-    pub manifest_start: usize,
+/// They're defined in a module of their own because the code that deku's
+/// derive macros generate for them trips `clippy::manual_div_ceil`, and lints
+/// can't be allowed on derived impls alone.
+mod layout {
+    #![allow(clippy::manual_div_ceil)]
+
+    use super::*;
+
+    /// End marker for Tardis's manifest.
+    ///
+    /// The end marker helps Tardis figure out where the start of the compressed
+    /// executable is, so that it knows what it needs to extract into memory. The
+    /// end marker is placed at the end of the file so that the executable always
+    /// knows where to look for it.
+    ///
+    /// The magic specified below is written on serialization. During deserialization,
+    /// Deku will check for the presence of this magic to confirm that it's reading
+    /// the correct data.
+    ///
+    /// Note that the size of the `EndMarker` is always the magic length plus the
+    /// size of the fields.
+    #[derive(Debug, DekuRead, DekuWrite)]
+    #[deku(magic = b"etar")]
+    pub struct EndMarker {
+        /// The location in the binary where the manifest starts. For version 0
+        /// files this is the location of the first resource; for later versions
+        /// it is the location of the [`ManifestHeader`].
+        #[deku(endian = "little")]
+        pub manifest_start: u64,
+
+        /// The number of resources in the manifest.
+        #[deku(endian = "little")]
+        pub n_resources: u32,
+
+        /// The version of the manifest format.
+        ///
+        /// Version 0 files stored `n_resources` in a 64-bit field, so the upper half
+        /// of that field (which is where `version` lives now) is always zero.
+        #[deku(endian = "little")]
+        pub version: u32,
+    }
+
+    /// Digest of a packed binary, stored just before the [`EndMarker`].
+    ///
+    /// The digest covers the loader, the header of every resource, the
+    /// [`ManifestHeader`] and the [`EndMarker`], in that order (see
+    /// [`file_digest`]). The data of each resource isn't included, since it's
+    /// already authenticated chunk by chunk when it's decompressed.
+    #[derive(Debug, DekuRead, DekuWrite)]
+    #[deku(magic = b"tdig")]
+    pub struct FileDigest {
+        /// The SHA-256 digest of the file.
+        pub sha256: [u8; 32],
+    }
+
+    /// Type-length-value entry used to attach additional metadata to the manifest
+    /// and to individual resources.
+    #[derive(Debug, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+    pub struct Extension {
+        /// Identifies the kind of data stored in the extension. If the
+        /// [`EXTENSION_CRITICAL`] bit is set, readers must understand the tag.
+        #[deku(endian = "little")]
+        pub tag: u16,
+
+        /// The length of the value.
+        #[deku(endian = "little", update = "self.value.len()")]
+        pub(super) length: u32,
+
+        /// The data contained in the extension.
+        #[deku(count = "length")]
+        pub value: Vec<u8>,
+    }
+
+    /// Header for the manifest, stored between the last resource and the
+    /// [`EndMarker`].
+    ///
+    /// The header records where the resources begin and carries an extension area
+    /// that can be used to store metadata that applies to the whole file.
+    #[derive(Debug, DekuRead, DekuWrite)]
+    #[deku(magic = b"tmhd")]
+    pub struct ManifestHeader {
+        /// The location in the binary where the first resource starts.
+        #[deku(endian = "little")]
+        pub resources_start: u64,
 
-    /// The number of resources in the manifest.
-    pub n_resources: usize,
+        /// The number of extensions attached to the manifest.
+        #[deku(endian = "little", update = "self.extensions.len()")]
+        pub(super) n_extensions: u16,
+
+        /// Extensions attached to the manifest.
+        #[deku(count = "n_extensions")]
+        pub extensions: Vec<Extension>,
+    }
+
+    /// Header that precedes the data of every [`TardisResource`].
+    #[derive(Debug, Clone, DekuRead, DekuWrite)]
+    pub struct ResourceHeader {
+        /// The size of the resource's data
+        #[deku(endian = "little")]
+        pub length: u64,
+
+        /// The encryption key for the resource
+        pub(crate) key: [u8; 32],
+
+        /// The number of attributes attached to the resource.
+        #[deku(endian = "little", update = "self.attributes.len()")]
+        pub(super) n_attributes: u16,
+
+        /// Extensions describing the resource.
+        #[deku(count = "n_attributes")]
+        pub attributes: Vec<Extension>,
+    }
+
+    /// Block of data that contains the compressed executable that Tardis decompresses
+    /// in memory.
+    #[derive(Debug, DekuRead, DekuWrite)]
+    pub struct TardisResource {
+        /// The header of the resource.
+        pub header: ResourceHeader,
+
+        /// The data contained in the resource.
+        #[deku(count = "header.length")]
+        pub data: Vec<u8>,
+
+        /// The binding of the resource. This isn't stored with the resource; it's
+        /// derived from the resource's position in the manifest.
+        #[deku(skip, default = "None")]
+        pub binding: Option<Binding>,
+    }
 }
 
+pub use layout::{
+    EndMarker, Extension, FileDigest, ManifestHeader, ResourceHeader, TardisResource,
+};
+
 impl EndMarker {
     /// Return the number of bytes (on-disk) required to represent an `EndMarker`.
     pub const fn nbytes() -> usize {
-        20
+        b"etar".len() + size_of::<u64>() + size_of::<u32>() + size_of::<u32>()
+    }
+}
+
+impl FileDigest {
+    /// Return the number of bytes (on-disk) required to represent a `FileDigest`.
+    pub const fn nbytes() -> usize {
+        b"tdig".len() + 32
+    }
+}
+
+/// Compute the digest stored in the [`FileDigest`] of a packed binary. `loader`
+/// must yield exactly the bytes that precede the first resource.
+pub fn file_digest<R: Read>(
+    loader: &mut R,
+    resources: &[ResourceHeader],
+    header: &ManifestHeader,
+    marker: &EndMarker,
+) -> Result<[u8; 32], TardisError> {
+    let mut context = digest::Context::new(&digest::SHA256);
+
+    let mut buf = vec![0u8; 64 << 10];
+    loop {
+        match loader.read(&mut buf)? {
+            0 => break,
+            n => context.update(&buf[..n]),
+        }
+    }
+    for resource in resources {
+        context.update(&resource.to_bytes()?);
+    }
+    context.update(&header.to_bytes()?);
+    context.update(&marker.to_bytes()?);
+
+    Ok(context.finish().as_ref().try_into().unwrap())
+}
+
+impl Extension {
+    /// Create a new extension with the given tag and value.
+    pub fn new(tag: u16, value: Vec<u8>) -> Result<Self, TardisError> {
+        let length = u32::try_from(value.len()).map_err(|_| TardisError::TooLarge {
+            what: "the length of an extension's value in bytes",
+            size: value.len() as u64,
+            max_size: u32::MAX.into(),
+        })?;
+        Ok(Extension { tag, length, value })
+    }
+
+    /// Returns `true` if readers must understand this extension in order to
+    /// load the file.
+    pub fn is_critical(&self) -> bool {
+        self.tag & EXTENSION_CRITICAL != 0
+    }
+
+    /// Return the length of the [`Extension`] after it's converted to a byte string.
+    pub fn len(&self) -> usize {
+        size_of::<u16>() + size_of::<u32>() + self.value.len()
+    }
+
+    /// Returns `true` if the extension doesn't hold a value.
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+}
+
+/// Return an error if the list contains a critical extension whose tag isn't
+/// in `known`.
+fn check_extensions(extensions: &[Extension], known: &[u16]) -> Result<(), TardisError> {
+    match extensions
+        .iter()
+        .find(|ext| ext.is_critical() && !known.contains(&ext.tag))
+    {
+        Some(ext) => Err(TardisError::UnsupportedExtension(ext.tag)),
+        None => Ok(()),
+    }
+}
+
+/// Tags for the extensions that can be attached to the [`ManifestHeader`].
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HeaderTag {
+    /// Random 16-byte identifier of the packed file, which its resources are
+    /// bound to. This extension is critical.
+    BundleId = 0x8001,
+    /// Ed25519 signature over the manifest (see [`crate::signature`]).
+    Signature = 0x0002,
+    /// Prefix of the built-in flags that the loader handles instead of passing
+    /// them to the guests, as a UTF-8 string. An empty prefix turns the flags
+    /// off, and files without this extension use [`DEFAULT_FLAG_PREFIX`].
+    FlagPrefix = 0x0003,
+    /// How a loader that runs several guests supervises them (see
+    /// [`ExitPolicy`]). Without it, the loader starts the guests and exits
+    /// straight away.
+    ExitPolicy = 0x0004,
+    /// How many seconds a supervising loader gives its guests to exit after
+    /// asking them to, before it kills them, as a little-endian `u32`. Files
+    /// without this extension use [`DEFAULT_GRACE_PERIOD`].
+    GracePeriod = 0x0005,
+}
+
+/// Seconds that guests are given to exit before they're killed, in files that
+/// don't choose their own grace period.
+pub const DEFAULT_GRACE_PERIOD: u32 = 10;
+
+/// Prefix of the built-in flags (e.g. `--tardis-info`) in files that don't
+/// choose their own.
+pub const DEFAULT_FLAG_PREFIX: &str = "--tardis-";
+
+/// How a loader that runs several guests waits for them, and the status that
+/// it exits with.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ExitPolicy {
+    /// Exit as soon as a guest fails, with that guest's status, after stopping
+    /// the others. Exit successfully once every guest has.
+    FirstFailure,
+
+    /// Wait for every guest. Exit successfully if all of them succeeded, or
+    /// with the status of the first one that failed.
+    AllSucceed,
+
+    /// Exit with the status of the guest with this name, after stopping the
+    /// others.
+    Primary(String),
+}
+
+impl ExitPolicy {
+    /// The name of the policy, as it's given to the packer.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExitPolicy::FirstFailure => "first-failure",
+            ExitPolicy::AllSucceed => "all-succeed",
+            ExitPolicy::Primary(_) => "primary",
+        }
+    }
+
+    /// Convert the policy into a [`HeaderTag::ExitPolicy`] extension.
+    pub fn to_extension(&self) -> Result<Extension, TardisError> {
+        let value = match self {
+            ExitPolicy::FirstFailure => vec![1],
+            ExitPolicy::AllSucceed => vec![2],
+            ExitPolicy::Primary(name) => [&[3], name.as_bytes()].concat(),
+        };
+        Extension::new(HeaderTag::ExitPolicy as u16, value)
+    }
+
+    fn from_extension(ext: &Extension) -> Result<Self, TardisError> {
+        match ext.value.split_first() {
+            Some((1, [])) => Ok(ExitPolicy::FirstFailure),
+            Some((2, [])) => Ok(ExitPolicy::AllSucceed),
+            Some((3, name)) => match std::str::from_utf8(name) {
+                Ok(name) if !name.is_empty() => Ok(ExitPolicy::Primary(name.into())),
+                _ => Err(TardisError::MalformedManifest(
+                    "bad primary guest name".into(),
+                )),
+            },
+            _ => Err(TardisError::MalformedManifest("unknown exit policy".into())),
+        }
+    }
+
+    /// Return the index of the primary guest among `resources`, if the policy
+    /// has one. It must be a guest that the loader runs.
+    pub fn primary_index(
+        &self,
+        resources: &[ResourceHeader],
+    ) -> Result<Option<usize>, TardisError> {
+        let ExitPolicy::Primary(primary) = self else {
+            return Ok(None);
+        };
+        for (index, resource) in resources.iter().enumerate() {
+            let metadata = resource.metadata()?;
+            if metadata.name.as_ref() == Some(primary)
+                && metadata.kind.unwrap_or_default().is_runnable()
+            {
+                return Ok(Some(index));
+            }
+        }
+        Err(TardisError::UnknownGuest(primary.clone()))
     }
 }
 
-/// Block of data that contains the compressed executable that Tardis decompresses
-/// in memory.
-#[derive(Debug, DekuRead, DekuWrite)]
-pub struct TardisResource {
-    /// The size of the resource
-    #[deku(update = "self.data.len()")]
-    length: usize,
+impl ManifestHeader {
+    /// Extension tags understood by this version of Tardis.
+    pub const KNOWN_EXTENSIONS: &'static [u16] = &[
+        HeaderTag::BundleId as u16,
+        HeaderTag::Signature as u16,
+        HeaderTag::FlagPrefix as u16,
+        HeaderTag::ExitPolicy as u16,
+        HeaderTag::GracePeriod as u16,
+    ];
+
+    /// Create a new header for resources starting at `resources_start`.
+    pub fn new(resources_start: u64, extensions: Vec<Extension>) -> Result<Self, TardisError> {
+        let n_extensions = u16::try_from(extensions.len()).map_err(|_| TardisError::TooLarge {
+            what: "the number of the manifest's extensions",
+            size: extensions.len() as u64,
+            max_size: u16::MAX.into(),
+        })?;
+        Ok(ManifestHeader {
+            resources_start,
+            n_extensions,
+            extensions,
+        })
+    }
+
+    /// Return the identifier of the packed file, if the header records one.
+    pub fn bundle_id(&self) -> Option<[u8; 16]> {
+        self.extensions
+            .iter()
+            .find(|ext| ext.tag == HeaderTag::BundleId as u16)
+            .and_then(|ext| ext.value[..].try_into().ok())
+    }
 
-    /// The encryption key for the resource
-    key: [u8; 32],
+    /// Return the prefix of the built-in flags that the loader handles, or
+    /// `None` if they're turned off.
+    pub fn flag_prefix(&self) -> Option<&str> {
+        match self
+            .extensions
+            .iter()
+            .find(|ext| ext.tag == HeaderTag::FlagPrefix as u16)
+        {
+            Some(ext) => std::str::from_utf8(&ext.value)
+                .ok()
+                .filter(|prefix| !prefix.is_empty()),
+            None => Some(DEFAULT_FLAG_PREFIX),
+        }
+    }
 
-    /// The data contained in the resource.
-    #[deku(count = "length")]
-    pub data: Vec<u8>,
+    /// Return the policy that the loader supervises its guests with, or `None`
+    /// if it doesn't supervise them.
+    pub fn exit_policy(&self) -> Result<Option<ExitPolicy>, TardisError> {
+        self.extensions
+            .iter()
+            .find(|ext| ext.tag == HeaderTag::ExitPolicy as u16)
+            .map(ExitPolicy::from_extension)
+            .transpose()
+    }
+
+    /// Return how many seconds a supervising loader gives its guests to exit
+    /// before it kills them.
+    pub fn grace_period(&self) -> Result<u32, TardisError> {
+        match self
+            .extensions
+            .iter()
+            .find(|ext| ext.tag == HeaderTag::GracePeriod as u16)
+        {
+            Some(ext) => match ext.value[..].try_into() {
+                Ok(value) => Ok(u32::from_le_bytes(value)),
+                Err(_) => Err(TardisError::MalformedManifest("bad grace period".into())),
+            },
+            None => Ok(DEFAULT_GRACE_PERIOD),
+        }
+    }
+
+    /// Return the length of the [`ManifestHeader`] after it's converted to a byte
+    /// string.
+    pub fn len(&self) -> usize {
+        b"tmhd".len()
+            + size_of::<u64>()
+            + size_of::<u16>()
+            + self.extensions.iter().map(Extension::len).sum::<usize>()
+    }
+
+    /// Returns `true` if the header doesn't have any extensions.
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+}
+
+/// The parsed manifest of a packed binary.
+#[derive(Debug)]
+pub struct Manifest {
+    /// The end marker found at the end of the binary.
+    pub marker: EndMarker,
+
+    /// The manifest header. For version 0 files, which didn't have a header,
+    /// this is synthesized from the end marker.
+    pub header: ManifestHeader,
+
+    /// The digest of the file, for files written with version 3 or later.
+    pub digest: Option<FileDigest>,
+}
+
+/// The largest manifest header that readers will accept.
+const MAX_HEADER_SIZE: u64 = 16 << 20;
+
+/// The largest resource attribute that readers will accept.
+pub const MAX_ATTRIBUTE_SIZE: u32 = 16 << 20;
+
+impl Manifest {
+    /// Parse the manifest of a packed binary.
+    pub fn parse(host: &[u8]) -> Result<Self, TardisError> {
+        Self::read_from(&mut Cursor::new(host))
+    }
+
+    /// Read the manifest of a packed binary. Only the end marker and the
+    /// manifest header are read; resources are left in place.
+    ///
+    /// Files that are too short to hold an end marker are reported as
+    /// truncated, and files that don't end with one are reported as having bad
+    /// magic (they either aren't packed binaries, or lost the data at their
+    /// end). For files that carry a digest, any other inconsistency is
+    /// reported as corruption.
+    pub fn read_from<R: Read + Seek>(reader: &mut R) -> Result<Self, TardisError> {
+        Self::read_manifest(reader, false)
+    }
+
+    /// Read the manifest of a file that is known to be packed, such as the
+    /// binary that a loader runs from. Unlike [`Manifest::read_from`], a
+    /// missing end marker is reported as truncation.
+    pub fn read_packed<R: Read + Seek>(reader: &mut R) -> Result<Self, TardisError> {
+        Self::read_manifest(reader, true)
+    }
+
+    fn read_manifest<R: Read + Seek>(reader: &mut R, packed: bool) -> Result<Self, TardisError> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        let marker_start = file_len
+            .checked_sub(EndMarker::nbytes() as u64)
+            .ok_or_else(|| TardisError::FileTruncated("no room for an end marker".into()))?;
+
+        let mut marker_bytes = [0u8; EndMarker::nbytes()];
+        reader.seek(SeekFrom::Start(marker_start))?;
+        reader.read_exact(&mut marker_bytes)?;
+        if !marker_bytes.starts_with(b"etar") {
+            if packed {
+                return Err(TardisError::FileTruncated("no end marker found".into()));
+            }
+            return Err(TardisError::BadMagic(
+                "no end marker found; the file isn't packed, or was truncated".into(),
+            ));
+        }
+        let (_, marker) = EndMarker::from_bytes((&marker_bytes, 0))?;
+
+        if marker.version > FORMAT_VERSION {
+            return Err(TardisError::UnsupportedVersion(marker.version));
+        }
+
+        let version = marker.version;
+        Self::read_header(reader, marker, marker_start).map_err(|e| corruption(version, e))
+    }
+
+    /// Read everything that precedes the end marker.
+    fn read_header<R: Read + Seek>(
+        reader: &mut R,
+        marker: EndMarker,
+        marker_start: u64,
+    ) -> Result<Self, TardisError> {
+        if marker.version == 0 {
+            let header = ManifestHeader::new(marker.manifest_start, Vec::new())?;
+            return Ok(Manifest {
+                marker,
+                header,
+                digest: None,
+            });
+        }
+
+        let (header_end, digest) = if marker.version >= 3 {
+            let digest_start = marker_start
+                .checked_sub(FileDigest::nbytes() as u64)
+                .ok_or_else(|| TardisError::MalformedManifest("missing file digest".into()))?;
+            let mut digest_bytes = [0u8; FileDigest::nbytes()];
+            reader.seek(SeekFrom::Start(digest_start))?;
+            reader.read_exact(&mut digest_bytes)?;
+            if !digest_bytes.starts_with(b"tdig") {
+                return Err(TardisError::BadMagic("no file digest found".into()));
+            }
+            let (_, digest) = FileDigest::from_bytes((&digest_bytes, 0))?;
+            (digest_start, Some(digest))
+        } else {
+            (marker_start, None)
+        };
+
+        let header_len = header_end
+            .checked_sub(marker.manifest_start)
+            .filter(|len| *len <= MAX_HEADER_SIZE)
+            .ok_or_else(|| TardisError::MalformedManifest("bad header offset".into()))?;
+        let mut header_bytes = vec![0u8; header_len as usize];
+        reader.seek(SeekFrom::Start(marker.manifest_start))?;
+        reader.read_exact(&mut header_bytes)?;
+        if !header_bytes.starts_with(b"tmhd") {
+            return Err(TardisError::BadMagic("no manifest header found".into()));
+        }
+
+        let (_, header) = ManifestHeader::from_bytes((&header_bytes, 0))?;
+        check_extensions(&header.extensions, ManifestHeader::KNOWN_EXTENSIONS)?;
+        if header.resources_start > marker.manifest_start {
+            return Err(TardisError::MalformedManifest(
+                "bad resources offset".into(),
+            ));
+        }
+
+        Ok(Manifest {
+            marker,
+            header,
+            digest,
+        })
+    }
+
+    /// Return the binding of the resource at the given index. Resources in files
+    /// written before version 2 aren't bound.
+    pub fn binding(&self, index: u32) -> Result<Option<Binding>, TardisError> {
+        if self.marker.version < 2 {
+            return Ok(None);
+        }
+        let bundle_id = self
+            .header
+            .bundle_id()
+            .ok_or_else(|| TardisError::MalformedManifest("missing bundle id".into()))?;
+        Ok(Some(Binding { bundle_id, index }))
+    }
+
+    /// Read the header of every resource listed in the manifest.
+    ///
+    /// If the file carries a digest, it's checked once all of the headers have
+    /// been read, which means reading the loader as well.
+    pub fn entries<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+    ) -> Result<Vec<ResourceEntry>, TardisError> {
+        let entries = self
+            .read_entries(reader)
+            .map_err(|e| corruption(self.marker.version, e))?;
+
+        if let Some(digest) = &self.digest {
+            let headers: Vec<_> = entries.iter().map(|entry| entry.header.clone()).collect();
+            reader.seek(SeekFrom::Start(0))?;
+            let mut loader = reader.take(self.header.resources_start);
+            let actual = file_digest(&mut loader, &headers, &self.header, &self.marker)?;
+            if actual != digest.sha256 {
+                return Err(TardisError::FileCorrupted("file digest mismatch".into()));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn read_entries<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+    ) -> Result<Vec<ResourceEntry>, TardisError> {
+        // Version 0 files don't have a header, so their resources run up to the
+        // end marker
+        let resources_end = match self.marker.version {
+            0 => reader.seek(SeekFrom::End(0))? - EndMarker::nbytes() as u64,
+            _ => self.marker.manifest_start,
+        };
+        let mut offset = self.header.resources_start;
+        let mut entries = Vec::with_capacity(self.marker.n_resources.min(1024) as usize);
+
+        for index in 0..self.marker.n_resources {
+            let remaining = resources_end
+                .checked_sub(offset)
+                .ok_or_else(|| TardisError::MalformedManifest("bad resource length".into()))?;
+            reader.seek(SeekFrom::Start(offset))?;
+            let (header, header_len) =
+                ResourceHeader::read_from(&mut reader.take(remaining), self.marker.version)?;
+            check_extensions(&header.attributes, TardisResource::KNOWN_ATTRIBUTES)?;
+
+            let data_start = offset + header_len as u64;
+            offset = data_start
+                .checked_add(header.length)
+                .filter(|end| *end <= resources_end)
+                .ok_or_else(|| TardisError::MalformedManifest("bad resource length".into()))?;
+            entries.push(ResourceEntry {
+                header,
+                data_start,
+                binding: self.binding(index)?,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Read all of the resources listed in the manifest from the host binary.
+    pub fn resources(&self, host: &[u8]) -> Result<Vec<TardisResource>, TardisError> {
+        self.entries(&mut Cursor::new(host))?
+            .into_iter()
+            .map(|entry| {
+                let data = usize::try_from(entry.data_start)
+                    .ok()
+                    .zip(usize::try_from(entry.header.length).ok())
+                    .and_then(|(start, len)| host.get(start..start.checked_add(len)?))
+                    .ok_or_else(|| TardisError::MalformedManifest("bad resource offset".into()))?;
+                let mut resource = TardisResource::new(entry.header, data.to_vec());
+                resource.binding = entry.binding;
+                Ok(resource)
+            })
+            .collect()
+    }
+}
+
+/// Report a malformed manifest as corruption if the file carries a digest, since
+/// in that case the file must have been damaged after it was packed.
+fn corruption(version: u32, err: TardisError) -> TardisError {
+    match err {
+        TardisError::MalformedManifest(msg) | TardisError::BadMagic(msg) if version >= 3 => {
+            TardisError::FileCorrupted(msg)
+        }
+        err => err,
+    }
+}
+
+/// The header of a resource, along with the location of its data.
+#[derive(Debug, Clone)]
+pub struct ResourceEntry {
+    /// The header of the resource.
+    pub header: ResourceHeader,
+
+    /// The location in the binary where the resource's data starts.
+    pub data_start: u64,
+
+    /// The binding of the resource, which is needed to read its data.
+    pub binding: Option<Binding>,
+}
+
+impl ResourceHeader {
+    /// Create a new resource header.
+    pub fn new(
+        length: u64,
+        key: [u8; 32],
+        attributes: Vec<Extension>,
+    ) -> Result<Self, TardisError> {
+        let n_attributes = u16::try_from(attributes.len()).map_err(|_| TardisError::TooLarge {
+            what: "the number of a resource's attributes",
+            size: attributes.len() as u64,
+            max_size: u16::MAX.into(),
+        })?;
+        Ok(ResourceHeader {
+            length,
+            key,
+            n_attributes,
+            attributes,
+        })
+    }
+
+    /// Read a resource header that was written with the given version of the
+    /// manifest format. Returns the header and the number of bytes it took up.
+    pub fn read_from<R: Read>(reader: &mut R, version: u32) -> Result<(Self, usize), TardisError> {
+        let mut read_exact = |buf: &mut [u8]| {
+            reader.read_exact(buf).map_err(|e| match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => {
+                    TardisError::MalformedManifest("resource header is truncated".into())
+                }
+                _ => e.into(),
+            })
+        };
+
+        let mut buf = vec![0u8; size_of::<u64>() + 32];
+        read_exact(&mut buf)?;
+
+        // Version 0 resources didn't have any attributes
+        if version == 0 {
+            let length = u64::from_le_bytes(buf[..8].try_into().unwrap());
+            let key = buf[8..].try_into().unwrap();
+            return Ok((ResourceHeader::new(length, key, Vec::new())?, buf.len()));
+        }
+
+        let mut n_attributes = [0u8; 2];
+        read_exact(&mut n_attributes)?;
+        buf.extend(n_attributes);
+
+        for _ in 0..u16::from_le_bytes(n_attributes) {
+            let mut attr_header = [0u8; 6];
+            read_exact(&mut attr_header)?;
+            let length = u32::from_le_bytes(attr_header[2..].try_into().unwrap());
+            if length > MAX_ATTRIBUTE_SIZE {
+                return Err(TardisError::MalformedManifest("attribute too large".into()));
+            }
+            buf.extend(attr_header);
+
+            let start = buf.len();
+            buf.resize(start + length as usize, 0);
+            read_exact(&mut buf[start..])?;
+        }
+
+        let (_, header) = ResourceHeader::from_bytes((&buf, 0))?;
+        Ok((header, buf.len()))
+    }
+
+    /// Return the metadata stored in the resource's attributes.
+    pub fn metadata(&self) -> Result<ResourceMetadata, TardisError> {
+        ResourceMetadata::from_attributes(&self.attributes)
+    }
+
+    /// Return the length of the [`ResourceHeader`] after it's converted to a byte
+    /// string.
+    pub fn len(&self) -> usize {
+        size_of::<u64>()
+            + self.key.len()
+            + size_of::<u16>()
+            + self.attributes.iter().map(Extension::len).sum::<usize>()
+    }
+
+    /// Returns `true` if the header doesn't have any attributes.
+    pub fn is_empty(&self) -> bool {
+        self.attributes.is_empty()
+    }
 }
 
 impl TardisResource {
-    /// Compress a block of data and store it in a [`TardisResource`] instance.
-    pub fn compress(data: &[u8]) -> Self {
-        let rng = rand::SystemRandom::new();
-        let key = rand::generate::<[u8; 32]>(&rng).unwrap().expose();
+    /// Attribute tags understood by this version of Tardis.
+    pub const KNOWN_ATTRIBUTES: &'static [u16] = &[
+        AttributeTag::Name as u16,
+        AttributeTag::Kind as u16,
+        AttributeTag::OriginalSize as u16,
+        AttributeTag::Mode as u16,
+        AttributeTag::Sha256 as u16,
+        AttributeTag::Codec as u16,
+        AttributeTag::ChunkSize as u16,
+        AttributeTag::Args as u16,
+        AttributeTag::Env as u16,
+        AttributeTag::Restart as u16,
+        AttributeTag::Foreground as u16,
+        AttributeTag::After as u16,
+        AttributeTag::Ready as u16,
+    ];
 
-        // Compress and encrypt data
-        let mut data = lz4_flex::compress_prepend_size(data);
-        let uk = UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap();
-        let nonces = crypto::NonceSeq::new(1);
-        let mut sk = aead::SealingKey::new(uk, nonces);
+    /// Create a resource from its header and data.
+    pub fn new(header: ResourceHeader, data: Vec<u8>) -> Self {
+        TardisResource {
+            header,
+            data,
+            binding: None,
+        }
+    }
 
-        let aad = aead::Aad::from(b"");
-        sk.seal_in_place_append_tag(aad, &mut data).unwrap();
-        let length = data.len();
+    /// Compress a block of data with the given codec and store it in a
+    /// [`TardisResource`] instance.
+    ///
+    /// The size and SHA-256 digest of `data` and the codec are added to the
+    /// metadata before it's stored in the resource's attributes. Resources
+    /// that are part of a packed file must be bound to their position in the
+    /// file with `binding`.
+    pub fn compress(
+        data: &[u8],
+        metadata: ResourceMetadata,
+        codec: &dyn Codec,
+        binding: Option<Binding>,
+    ) -> Result<Self, TardisError> {
+        let mut output = Cursor::new(Vec::new());
+        let header = stream::write_resource(
+            &mut &data[..],
+            &mut output,
+            metadata,
+            codec,
+            DEFAULT_CHUNK_SIZE,
+            binding,
+        )?;
+
+        let mut data = output.into_inner();
+        data.drain(..header.len());
+        Ok(TardisResource {
+            header,
+            data,
+            binding,
+        })
+    }
 
-        TardisResource { key, data, length }
+    /// Return the metadata stored in the resource's attributes.
+    pub fn metadata(&self) -> Result<ResourceMetadata, TardisError> {
+        self.header.metadata()
     }
 
     /// Decompress the data block and return it.
-    pub fn decompress(self) -> Result<Vec<u8>, DecompressError> {
-        let uk = match UnboundKey::new(&CHACHA20_POLY1305, &self.key) {
-            Ok(key) => key,
-            Err(_) => panic!(),
-        };
+    ///
+    /// If the resource records the size or digest of the uncompressed data, the
+    /// decompressed data is checked against them.
+    pub fn decompress(self) -> Result<Vec<u8>, TardisError> {
+        let metadata = self.metadata()?;
+
+        // Chunked resources are handled by the stream module
+        if metadata.chunk_size.is_some() {
+            let mut output = Vec::new();
+            stream::read_resource(&mut &self.data[..], &self.header, self.binding, &mut output)?;
+            return Ok(output);
+        }
+
+        let uk = UnboundKey::new(&CHACHA20_POLY1305, &self.header.key)
+            .map_err(|_| TardisError::AuthenticationError)?;
         let nonces = crypto::NonceSeq::new(1);
         let mut ok = aead::OpeningKey::new(uk, nonces);
         let aad = aead::Aad::from(b"");
 
         let mut data = self.data;
 
-        let plaintext = match ok.open_in_place(aad, &mut data) {
-            Ok(data) => data,
-            Err(_) => panic!(),
-        };
-        lz4_flex::decompress_size_prepended(plaintext)
+        let plaintext = ok
+            .open_in_place(aad, &mut data)
+            .map_err(|_| TardisError::AuthenticationError)?;
+        let codec = codec::new(metadata.codec.unwrap_or(CodecId::Lz4), None)?;
+        // Resources without chunks can be as large as their recorded size
+        let max_size = metadata
+            .original_size
+            .and_then(|size| usize::try_from(size).ok())
+            .unwrap_or(usize::MAX);
+        let data = codec.decompress(plaintext, max_size)?;
+        metadata.verify(&data)?;
+
+        Ok(data)
     }
 
     /// Return the length of the [`TardisResource`] after it's converted to a byte
     /// string.
     pub fn len(&self) -> usize {
-        8 + 32 + self.data.len()
+        self.header.len() + self.data.len()
     }
 
     /// Returns `true` if there isn't any data stored in the [`TardisResources`].
@@ -107,14 +897,47 @@ impl TardisResource {
 
 #[cfg(test)]
 mod test {
-    use super::{EndMarker, TardisResource};
+    use super::{
+        file_digest, Binding, EndMarker, ExitPolicy, Extension, FileDigest, HeaderTag, Manifest,
+        ManifestHeader, ResourceHeader, TardisResource, DEFAULT_FLAG_PREFIX, DEFAULT_GRACE_PERIOD,
+        EXTENSION_CRITICAL, FORMAT_VERSION,
+    };
+    use crate::{
+        codec::Store,
+        error::TardisError,
+        metadata::{ResourceKind, ResourceMetadata},
+    };
     use deku::prelude::*;
 
+    /// Build a fake packed binary containing the given resources.
+    fn pack(resources: &[TardisResource], header_ext: Vec<Extension>, version: u32) -> Vec<u8> {
+        let loader = b"fake loader";
+        let mut host = loader.to_vec();
+        let header = ManifestHeader::new(host.len() as u64, header_ext).unwrap();
+        for resource in resources {
+            host.extend(resource.to_bytes().unwrap());
+        }
+        let marker = EndMarker {
+            manifest_start: host.len() as u64,
+            n_resources: resources.len() as u32,
+            version,
+        };
+        host.extend(header.to_bytes().unwrap());
+        if version >= 3 {
+            let headers: Vec<_> = resources.iter().map(|r| r.header.clone()).collect();
+            let sha256 = file_digest(&mut &loader[..], &headers, &header, &marker).unwrap();
+            host.extend(FileDigest { sha256 }.to_bytes().unwrap());
+        }
+        host.extend(marker.to_bytes().unwrap());
+        host
+    }
+
     #[test]
     fn test_end_marker_nbytes() {
         let marker = EndMarker {
             manifest_start: 0,
             n_resources: 16,
+            version: FORMAT_VERSION,
         };
         let marker_bytes = marker.to_bytes().unwrap();
         assert_eq!(marker_bytes.len(), EndMarker::nbytes());
@@ -124,7 +947,8 @@ mod test {
     fn test_compress_and_decompress() {
         let original = b"hello, world!";
 
-        let resource = TardisResource::compress(original);
+        let resource =
+            TardisResource::compress(original, ResourceMetadata::default(), &Store, None).unwrap();
         let result = resource.decompress();
         assert!(result.is_ok());
 
@@ -134,7 +958,13 @@ mod test {
 
     #[test]
     fn test_tardis_resource_len() {
-        let resource = TardisResource::compress(b"\x00\x00\x00\x00");
+        let resource = TardisResource::compress(
+            b"\x00\x00\x00\x00",
+            ResourceMetadata::default(),
+            &Store,
+            None,
+        )
+        .unwrap();
         let resource_bytes = resource.to_bytes().unwrap();
         assert_eq!(resource_bytes.len(), resource.len());
     }
@@ -144,7 +974,8 @@ mod test {
         let original = b"hello, world!";
 
         // Serialize to bytes
-        let resource = TardisResource::compress(original);
+        let resource =
+            TardisResource::compress(original, ResourceMetadata::default(), &Store, None).unwrap();
         let result = resource.to_bytes();
         assert!(result.is_ok());
         let resource_bytes = result.unwrap();
@@ -159,4 +990,328 @@ mod test {
 
         assert_eq!(original.to_vec(), decompressed);
     }
+
+    /// Compress resources that are bound to a packed file, along with the
+    /// header extension that records the file's identifier.
+    fn bound_resources(data: &[&[u8]]) -> (Vec<TardisResource>, Extension) {
+        let bundle_id = [0x42; 16];
+        let resources = data
+            .iter()
+            .enumerate()
+            .map(|(index, data)| {
+                let binding = Binding {
+                    bundle_id,
+                    index: index as u32,
+                };
+                TardisResource::compress(data, ResourceMetadata::default(), &Store, Some(binding))
+                    .unwrap()
+            })
+            .collect();
+        let ext = Extension::new(HeaderTag::BundleId as u16, bundle_id.to_vec()).unwrap();
+        (resources, ext)
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let (resources, ext) = bound_resources(&[b"first", b"second"]);
+        let host = pack(&resources, vec![ext], FORMAT_VERSION);
+
+        let manifest = Manifest::parse(&host).unwrap();
+        assert_eq!(manifest.marker.n_resources, 2);
+        assert_eq!(manifest.header.resources_start, b"fake loader".len() as u64);
+
+        let resources = manifest.resources(&host).unwrap();
+        let decompressed: Vec<_> = resources
+            .into_iter()
+            .map(|r| r.decompress().unwrap())
+            .collect();
+        assert_eq!(decompressed, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_parse_v0_manifest() {
+        use crate::{
+            codec::{self, CodecId},
+            crypto,
+        };
+        use ring::aead::{self, BoundKey, UnboundKey, CHACHA20_POLY1305};
+
+        // Version 0 resources were always compressed with LZ4 and sealed as a
+        // single block
+        let key = [0x55; 32];
+        let lz4 = codec::new(CodecId::Lz4, None).unwrap();
+        let mut data = lz4.compress(b"legacy").unwrap();
+        let uk = UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap();
+        let mut sk = aead::SealingKey::new(uk, crypto::NonceSeq::new(1));
+        sk.seal_in_place_append_tag(aead::Aad::empty(), &mut data)
+            .unwrap();
+
+        // Version 0 files stored n_resources as a 64-bit integer
+        let mut host = b"fake loader".to_vec();
+        let manifest_start = host.len();
+        host.extend((data.len() as u64).to_le_bytes());
+        host.extend(key);
+        host.extend(data);
+        host.extend(b"etar");
+        host.extend((manifest_start as u64).to_le_bytes());
+        host.extend(1u64.to_le_bytes());
+
+        let manifest = Manifest::parse(&host).unwrap();
+        assert_eq!(manifest.marker.version, 0);
+        let mut resources = manifest.resources(&host).unwrap();
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources.remove(0).decompress().unwrap(), b"legacy");
+
+        // Tampering with a single-block resource is an error, not a panic
+        let mut tampered = manifest.resources(&host).unwrap().remove(0);
+        tampered.data[0] ^= 1;
+        let result = tampered.decompress();
+        assert!(matches!(result, Err(TardisError::AuthenticationError)));
+    }
+
+    #[test]
+    fn test_reject_reordered_resources() {
+        let (mut resources, ext) = bound_resources(&[b"first", b"second"]);
+        resources.swap(0, 1);
+        let host = pack(&resources, vec![ext], FORMAT_VERSION);
+
+        let manifest = Manifest::parse(&host).unwrap();
+        for resource in manifest.resources(&host).unwrap() {
+            let result = resource.decompress();
+            assert!(matches!(result, Err(TardisError::AuthenticationError)));
+        }
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let (resources, ext) = bound_resources(&[b"first", b"second"]);
+        let host = pack(&resources, vec![ext], FORMAT_VERSION);
+
+        for len in [0, 4] {
+            let result = Manifest::parse(&host[..len]);
+            assert!(matches!(result, Err(TardisError::FileTruncated(_))));
+        }
+        for len in [host.len() - 1, host.len() / 2] {
+            let result = Manifest::read_packed(&mut std::io::Cursor::new(&host[..len]));
+            assert!(matches!(result, Err(TardisError::FileTruncated(_))));
+        }
+
+        // Without knowing that the file was packed, it could be any file
+        let result = Manifest::parse(&host[..host.len() - 1]);
+        assert!(matches!(result, Err(TardisError::BadMagic(_))));
+    }
+
+    #[test]
+    fn test_corrupted_file() {
+        let (resources, ext) = bound_resources(&[b"first", b"second"]);
+        let host = pack(&resources, vec![ext], FORMAT_VERSION);
+        let manifest = Manifest::parse(&host).unwrap();
+        assert!(manifest.digest.is_some());
+        assert_eq!(
+            manifest
+                .entries(&mut std::io::Cursor::new(&host))
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // A byte of the loader
+        let mut corrupted = host.clone();
+        corrupted[3] ^= 1;
+        let manifest = Manifest::parse(&corrupted).unwrap();
+        let result = manifest.resources(&corrupted);
+        assert!(matches!(result, Err(TardisError::FileCorrupted(_))));
+
+        // The length of the first resource, which throws off every offset
+        let mut corrupted = host.clone();
+        corrupted[b"fake loader".len() + 7] = 0x80;
+        let manifest = Manifest::parse(&corrupted).unwrap();
+        let result = manifest.resources(&corrupted);
+        assert!(matches!(result, Err(TardisError::FileCorrupted(_))));
+
+        // The digest of the second resource
+        let mut corrupted = host.clone();
+        let offset = b"fake loader".len() + resources[0].len() + 8;
+        corrupted[offset] ^= 1;
+        let manifest = Manifest::parse(&corrupted).unwrap();
+        let result = manifest.resources(&corrupted);
+        assert!(matches!(result, Err(TardisError::FileCorrupted(_))));
+
+        // The offset of the manifest header
+        let mut corrupted = host.clone();
+        let offset = host.len() - EndMarker::nbytes() + 4;
+        corrupted[offset] ^= 1;
+        let result = Manifest::parse(&corrupted);
+        assert!(matches!(result, Err(TardisError::FileCorrupted(_))));
+    }
+
+    #[test]
+    fn test_reject_newer_version() {
+        let host = pack(&[], Vec::new(), FORMAT_VERSION + 1);
+        let result = Manifest::parse(&host);
+        assert!(
+            matches!(result, Err(TardisError::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn test_extensions() {
+        // Unknown extensions are skipped unless they're marked as critical
+        let ext = Extension::new(0x0123, b"ignored".to_vec()).unwrap();
+        let host = pack(&[], vec![ext.clone()], FORMAT_VERSION);
+        let manifest = Manifest::parse(&host).unwrap();
+        assert_eq!(manifest.header.extensions, vec![ext]);
+
+        let ext = Extension::new(EXTENSION_CRITICAL | 0x0123, Vec::new()).unwrap();
+        let host = pack(&[], vec![ext], FORMAT_VERSION);
+        let result = Manifest::parse(&host);
+        assert!(matches!(result, Err(TardisError::UnsupportedExtension(_))));
+
+        // Counts that don't fit in their fields are rejected instead of being
+        // truncated
+        let extensions = vec![Extension::new(0x0123, Vec::new()).unwrap(); 1 << 16];
+        let result = ManifestHeader::new(0, extensions.clone());
+        assert!(matches!(
+            result,
+            Err(TardisError::TooLarge { size: 65536, .. })
+        ));
+        let result = ResourceHeader::new(0, [0; 32], extensions);
+        assert!(matches!(
+            result,
+            Err(TardisError::TooLarge { size: 65536, .. })
+        ));
+    }
+
+    #[test]
+    fn test_exit_policy() {
+        let header = ManifestHeader::new(0, Vec::new()).unwrap();
+        assert_eq!(header.exit_policy().unwrap(), None);
+
+        let guest = |name: &str| {
+            let metadata = ResourceMetadata {
+                kind: Some(ResourceKind::Executable),
+                ..ResourceMetadata::named(name)
+            };
+            ResourceHeader::new(0, [0; 32], metadata.to_attributes().unwrap()).unwrap()
+        };
+        let resources = [guest("a"), guest("b")];
+        for policy in [
+            ExitPolicy::FirstFailure,
+            ExitPolicy::AllSucceed,
+            ExitPolicy::Primary("b".into()),
+        ] {
+            let header = ManifestHeader::new(0, vec![policy.to_extension().unwrap()]).unwrap();
+            assert_eq!(header.exit_policy().unwrap().as_ref(), Some(&policy));
+        }
+        assert_eq!(
+            ExitPolicy::Primary("b".into())
+                .primary_index(&resources)
+                .unwrap(),
+            Some(1)
+        );
+        let result = ExitPolicy::Primary("c".into()).primary_index(&resources);
+        assert!(matches!(result, Err(TardisError::UnknownGuest(_))));
+
+        let ext = Extension::new(HeaderTag::ExitPolicy as u16, vec![9]).unwrap();
+        let header = ManifestHeader::new(0, vec![ext]).unwrap();
+        assert!(header.exit_policy().is_err());
+    }
+
+    #[test]
+    fn test_grace_period() {
+        let header = ManifestHeader::new(0, Vec::new()).unwrap();
+        assert_eq!(header.grace_period().unwrap(), DEFAULT_GRACE_PERIOD);
+
+        let ext =
+            Extension::new(HeaderTag::GracePeriod as u16, 30u32.to_le_bytes().to_vec()).unwrap();
+        let header = ManifestHeader::new(0, vec![ext]).unwrap();
+        assert_eq!(header.grace_period().unwrap(), 30);
+
+        let ext = Extension::new(HeaderTag::GracePeriod as u16, vec![1, 2]).unwrap();
+        let header = ManifestHeader::new(0, vec![ext]).unwrap();
+        assert!(header.grace_period().is_err());
+    }
+
+    #[test]
+    fn test_flag_prefix() {
+        let header = ManifestHeader::new(0, Vec::new()).unwrap();
+        assert_eq!(header.flag_prefix(), Some(DEFAULT_FLAG_PREFIX));
+
+        let ext = Extension::new(HeaderTag::FlagPrefix as u16, b"--app-".to_vec()).unwrap();
+        let header = ManifestHeader::new(0, vec![ext]).unwrap();
+        assert_eq!(header.flag_prefix(), Some("--app-"));
+
+        let ext = Extension::new(HeaderTag::FlagPrefix as u16, Vec::new()).unwrap();
+        let header = ManifestHeader::new(0, vec![ext]).unwrap();
+        assert_eq!(header.flag_prefix(), None);
+    }
+
+    #[test]
+    fn test_end_marker_layout() {
+        let marker = EndMarker {
+            manifest_start: 0x0102030405060708,
+            n_resources: 3,
+            version: 1,
+        };
+        let expected = b"etar\
+            \x08\x07\x06\x05\x04\x03\x02\x01\
+            \x03\x00\x00\x00\
+            \x01\x00\x00\x00";
+        assert_eq!(marker.to_bytes().unwrap(), expected);
+
+        let (_, marker) = EndMarker::from_bytes((expected, 0)).unwrap();
+        assert_eq!(marker.manifest_start, 0x0102030405060708);
+        assert_eq!(marker.n_resources, 3);
+        assert_eq!(marker.version, 1);
+    }
+
+    #[test]
+    fn test_manifest_header_layout() {
+        let header = ManifestHeader::new(
+            0x1234,
+            vec![Extension::new(0x8001, b"ab".to_vec()).unwrap()],
+        )
+        .unwrap();
+        let expected = b"tmhd\
+            \x34\x12\x00\x00\x00\x00\x00\x00\
+            \x01\x00\
+            \x01\x80\x02\x00\x00\x00ab";
+        let header_bytes = header.to_bytes().unwrap();
+        assert_eq!(header_bytes, expected);
+        assert_eq!(header_bytes.len(), header.len());
+    }
+
+    #[test]
+    fn test_tardis_resource_layout() {
+        let header = ResourceHeader::new(
+            3,
+            [0xaa; 32],
+            vec![Extension::new(0x0002, b"x".to_vec()).unwrap()],
+        )
+        .unwrap();
+        let resource = TardisResource::new(header, b"xyz".to_vec());
+        let mut expected = b"\x03\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        expected.extend([0xaa; 32]);
+        expected.extend(b"\x01\x00\x02\x00\x01\x00\x00\x00x");
+        expected.extend(b"xyz");
+        assert_eq!(resource.to_bytes().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_decompress_checks_digest() {
+        let mut resource =
+            TardisResource::compress(b"hello", ResourceMetadata::named("guest"), &Store, None)
+                .unwrap();
+        let metadata = resource.metadata().unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("guest"));
+        assert_eq!(metadata.original_size, Some(5));
+
+        let mut tampered = metadata.clone();
+        tampered.sha256 = Some([0; 32]);
+        resource.header.attributes = tampered.to_attributes().unwrap();
+
+        let result = resource.decompress();
+        assert!(matches!(result, Err(TardisError::IntegrityError(_))));
+    }
 }
@@ -0,0 +1,254 @@
+//! Ed25519 signatures over the manifest of a packed binary.
+//!
+//! The signature covers the SHA-256 digest of the loader (with its public key
+//! embedded), the manifest header (apart from the signature itself) and the
+//! header of every resource, in order. Since each resource header records the
+//! SHA-256 digest of the resource, which is checked when the resource is
+//! decompressed, this is enough to authenticate the loader and the guests.
+//!
+//! A loader can be made to require a valid signature by embedding a public key
+//! into it with [`embed_public_key`].
+
+use crate::{
+    error::TardisError,
+    metadata::sha256,
+    serialization::{Extension, HeaderTag, ManifestHeader, ResourceHeader},
+};
+use deku::DekuContainerWrite;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+
+/// Prefix of every signed message, so that signatures made by Tardis can't be
+/// confused with signatures over other data made with the same key.
+const SIGNATURE_CONTEXT: &[u8] = b"tardis manifest signature v2\0";
+
+/// Magic bytes that precede the public key slot in the loader.
+pub const PUBLIC_KEY_MAGIC: [u8; 16] = *b"tardis pubkey v1";
+
+/// Slot in the loader binary that holds the public key that packed files must
+/// be signed with. The packer finds the slot through its magic bytes and fills
+/// in the key; a slot holding all zeroes means that signatures aren't required.
+#[repr(C)]
+pub struct EmbeddedKey {
+    pub magic: [u8; 16],
+    pub key: [u8; 32],
+}
+
+impl EmbeddedKey {
+    /// An empty key slot.
+    pub const EMPTY: EmbeddedKey = EmbeddedKey {
+        magic: PUBLIC_KEY_MAGIC,
+        key: [0; 32],
+    };
+
+    /// Return the embedded public key, if one has been filled in.
+    ///
+    /// The slot is read with a volatile read, since the compiler would
+    /// otherwise assume that it always holds zeroes.
+    pub fn public_key(&'static self) -> Option<[u8; 32]> {
+        let key = unsafe { core::ptr::read_volatile(&self.key) };
+        (key != [0; 32]).then_some(key)
+    }
+}
+
+/// Fill in the public key slot of a loader binary.
+pub fn embed_public_key(loader: &mut [u8], public_key: &[u8; 32]) -> Result<(), TardisError> {
+    let mut slots = loader
+        .windows(PUBLIC_KEY_MAGIC.len())
+        .enumerate()
+        .filter(|(_, window)| *window == PUBLIC_KEY_MAGIC)
+        .map(|(i, _)| i + PUBLIC_KEY_MAGIC.len());
+
+    let start = match (slots.next(), slots.next()) {
+        (Some(start), None) if start + public_key.len() <= loader.len() => start,
+        _ => {
+            return Err(TardisError::InvalidKey(
+                "the loader doesn't have exactly one public key slot".into(),
+            ))
+        }
+    };
+    loader[start..start + public_key.len()].copy_from_slice(public_key);
+    Ok(())
+}
+
+/// Generate a new Ed25519 key pair, returned as a PKCS#8 document (in DER form).
+pub fn generate_key_pair() -> Result<Vec<u8>, TardisError> {
+    let rng = ring::rand::SystemRandom::new();
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+        .map_err(|_| TardisError::InvalidKey("failed to generate a key".into()))?;
+    Ok(pkcs8.as_ref().to_vec())
+}
+
+/// Load an Ed25519 key pair from a PKCS#8 document (in DER form).
+pub fn load_key_pair(pkcs8: &[u8]) -> Result<Ed25519KeyPair, TardisError> {
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+        .map_err(|e| TardisError::InvalidKey(e.to_string()))
+}
+
+/// Return the message that is signed for a manifest with the given loader,
+/// header and resources.
+pub fn signed_message(
+    loader: &[u8],
+    header: &ManifestHeader,
+    resources: &[ResourceHeader],
+) -> Result<Vec<u8>, TardisError> {
+    let extensions = header
+        .extensions
+        .iter()
+        .filter(|ext| ext.tag != HeaderTag::Signature as u16)
+        .cloned()
+        .collect();
+    let unsigned = ManifestHeader::new(header.resources_start, extensions)?;
+
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend(sha256(loader));
+    message.extend(unsigned.to_bytes()?);
+    message.extend((resources.len() as u32).to_le_bytes());
+    for resource in resources {
+        message.extend(resource.to_bytes()?);
+    }
+    Ok(message)
+}
+
+/// Sign a manifest, returning a copy of its header with the signature added.
+/// `loader` must already have the public key embedded into it.
+pub fn sign(
+    key_pair: &Ed25519KeyPair,
+    loader: &[u8],
+    header: &ManifestHeader,
+    resources: &[ResourceHeader],
+) -> Result<ManifestHeader, TardisError> {
+    let signature = key_pair.sign(&signed_message(loader, header, resources)?);
+
+    let mut extensions: Vec<_> = header
+        .extensions
+        .iter()
+        .filter(|ext| ext.tag != HeaderTag::Signature as u16)
+        .cloned()
+        .collect();
+    extensions.push(Extension::new(
+        HeaderTag::Signature as u16,
+        signature.as_ref().to_vec(),
+    )?);
+    ManifestHeader::new(header.resources_start, extensions)
+}
+
+/// Check that a packed binary has been signed by the owner of `public_key`.
+pub fn verify(
+    public_key: &[u8; 32],
+    loader: &[u8],
+    header: &ManifestHeader,
+    resources: &[ResourceHeader],
+) -> Result<(), TardisError> {
+    let signature = header
+        .extensions
+        .iter()
+        .find(|ext| ext.tag == HeaderTag::Signature as u16)
+        .ok_or(TardisError::MissingSignature)?;
+
+    UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(
+            &signed_message(loader, header, resources)?,
+            &signature.value,
+        )
+        .map_err(|_| TardisError::InvalidSignature)
+}
+
+/// Return the public key of a key pair.
+pub fn public_key(key_pair: &Ed25519KeyPair) -> [u8; 32] {
+    key_pair.public_key().as_ref().try_into().unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        embed_public_key, generate_key_pair, load_key_pair, public_key, sign, verify, EmbeddedKey,
+    };
+    use crate::{
+        error::TardisError,
+        serialization::{Extension, HeaderTag, ManifestHeader, ResourceHeader},
+    };
+    use ring::signature::Ed25519KeyPair;
+
+    const LOADER: &[u8] = b"fake loader";
+
+    fn key_pair() -> Ed25519KeyPair {
+        load_key_pair(&generate_key_pair().unwrap()).unwrap()
+    }
+
+    fn manifest() -> (ManifestHeader, Vec<ResourceHeader>) {
+        let bundle_id = Extension::new(HeaderTag::BundleId as u16, vec![1; 16]).unwrap();
+        let header = ManifestHeader::new(100, vec![bundle_id]).unwrap();
+        let resources = vec![
+            ResourceHeader::new(10, [1; 32], Vec::new()).unwrap(),
+            ResourceHeader::new(
+                20,
+                [2; 32],
+                vec![Extension::new(1, b"guest".to_vec()).unwrap()],
+            )
+            .unwrap(),
+        ];
+        (header, resources)
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key_pair = key_pair();
+        let pubkey = public_key(&key_pair);
+        let (header, resources) = manifest();
+
+        let result = verify(&pubkey, LOADER, &header, &resources);
+        assert!(matches!(result, Err(TardisError::MissingSignature)));
+
+        let signed = sign(&key_pair, LOADER, &header, &resources).unwrap();
+        assert!(verify(&pubkey, LOADER, &signed, &resources).is_ok());
+
+        // Signing again replaces the old signature
+        let resigned = sign(&key_pair, LOADER, &signed, &resources).unwrap();
+        assert_eq!(resigned.extensions.len(), 2);
+        assert!(verify(&pubkey, LOADER, &resigned, &resources).is_ok());
+
+        // The signature doesn't verify with another key
+        let other = public_key(&self::key_pair());
+        let result = verify(&other, LOADER, &signed, &resources);
+        assert!(matches!(result, Err(TardisError::InvalidSignature)));
+
+        // The signature covers the order of the resources
+        let mut reordered = resources.clone();
+        reordered.swap(0, 1);
+        let result = verify(&pubkey, LOADER, &signed, &reordered);
+        assert!(matches!(result, Err(TardisError::InvalidSignature)));
+
+        // The signature covers the other header extensions
+        let mut extensions = signed.extensions.clone();
+        extensions[0].value = vec![2; 16];
+        let tampered = ManifestHeader::new(100, extensions).unwrap();
+        let result = verify(&pubkey, LOADER, &tampered, &resources);
+        assert!(matches!(result, Err(TardisError::InvalidSignature)));
+
+        // The signature covers the loader
+        let result = verify(&pubkey, b"tampered loader", &signed, &resources);
+        assert!(matches!(result, Err(TardisError::InvalidSignature)));
+    }
+
+    #[test]
+    fn test_embed_public_key() {
+        let slot = EmbeddedKey::EMPTY;
+        let mut loader = b"fake loader ".to_vec();
+        loader.extend(slot.magic);
+        loader.extend(slot.key);
+        loader.extend(b" more code");
+
+        embed_public_key(&mut loader, &[7; 32]).unwrap();
+        assert_eq!(&loader[28..60], &[7; 32]);
+        assert!(loader.ends_with(b" more code"));
+
+        // Loaders without exactly one slot are rejected
+        let result = embed_public_key(&mut b"no slot here".to_vec(), &[7; 32]);
+        assert!(matches!(result, Err(TardisError::InvalidKey(_))));
+
+        let mut twice = loader.clone();
+        twice.extend(loader);
+        let result = embed_public_key(&mut twice, &[7; 32]);
+        assert!(matches!(result, Err(TardisError::InvalidKey(_))));
+    }
+}
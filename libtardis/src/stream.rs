@@ -0,0 +1,951 @@
+//! Streaming compression and encryption of resources.
+//!
+//! Resources are split into chunks that are compressed and sealed
+//! independently, so that neither the packer nor the loader ever has to hold
+//! more than a couple of chunks in memory. On disk, each chunk is stored as a
+//! little-endian u32 header followed by the sealed chunk:
+//!
+//! ```text
+//! +-------------------------------+---------------------------------+
+//! | last (1 bit) | length (31 bits) | compressed chunk | Poly1305 tag |
+//! +-------------------------------+---------------------------------+
+//! ```
+//!
+//! Chunks are encrypted with the STREAM construction (see
+//! [`crypto::stream_nonce`]), so the final chunk is authenticated as such and
+//! a stream that has been truncated at a chunk boundary is rejected.
+//!
+//! Resources in a packed file are also bound to their position in the file and
+//! to their metadata (see [`Binding`]), so that they can't be reordered, moved
+//! into another file or have their metadata changed without failing
+//! authentication.
+//!
+//! [`ResourceWriter`] and [`ResourceReader`] adapt resources to the
+//! [`std::io`] traits, so that data can be piped in and out of a resource from
+//! any reader or writer.
+
+use crate::{
+    codec::{self, Codec, CodecId},
+    crypto,
+    error::TardisError,
+    metadata::{AttributeTag, ResourceMetadata},
+    serialization::{Extension, ResourceHeader, TardisResource},
+};
+use deku::DekuContainerWrite;
+use ring::{
+    aead::{Aad, LessSafeKey, UnboundKey, CHACHA20_POLY1305},
+    digest::{Context, SHA256},
+    rand,
+};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// The default amount of uncompressed data stored in each chunk.
+pub const DEFAULT_CHUNK_SIZE: u32 = 4 << 20;
+
+/// The largest chunk size that can be represented in a chunk header.
+pub const MAX_CHUNK_SIZE: u32 = (1 << 30) - 1;
+
+/// Flag set in the header of the final chunk of a resource.
+const LAST_CHUNK: u32 = 1 << 31;
+
+/// The length of the authentication tag appended to each sealed chunk.
+const TAG_LEN: u64 = 16;
+
+/// The largest sealed chunk that a stream with chunks of `chunk_size` bytes
+/// can contain. This leaves room for codecs that expand data they can't
+/// compress, and for the authentication tag.
+fn max_sealed_len(chunk_size: u32) -> u64 {
+    let chunk_size = u64::from(chunk_size);
+    chunk_size + chunk_size / 64 + 1024 + TAG_LEN
+}
+
+/// Summary of a stream of data that was sealed or opened.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct StreamSummary {
+    /// The number of bytes of uncompressed data.
+    pub original_size: u64,
+
+    /// The SHA-256 digest of the uncompressed data.
+    pub sha256: [u8; 32],
+
+    /// The number of bytes of sealed chunks (including chunk headers).
+    pub sealed_size: u64,
+}
+
+/// Identifies the position of a resource in a packed file.
+///
+/// The binding of a resource is authenticated along with each of its chunks,
+/// together with the resource's attributes. Every chunk covers the attributes
+/// that are known before any data is written; the final chunk also covers the
+/// size and digest of the resource, which are only known at the end.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Binding {
+    /// Random identifier of the packed file that the resource belongs to.
+    pub bundle_id: [u8; 16],
+
+    /// Index of the resource in the manifest.
+    pub index: u32,
+}
+
+impl Binding {
+    /// Generate a random identifier for a new packed file.
+    pub fn new_bundle_id() -> [u8; 16] {
+        let rng = rand::SystemRandom::new();
+        rand::generate(&rng).unwrap().expose()
+    }
+
+    /// Return the associated data for a chunk of a resource with the given
+    /// attributes.
+    fn associated_data(
+        &self,
+        attributes: &[Extension],
+        last: bool,
+    ) -> Result<Vec<u8>, TardisError> {
+        const SUMMARY: [u16; 2] = [
+            AttributeTag::OriginalSize as u16,
+            AttributeTag::Sha256 as u16,
+        ];
+
+        let mut aad = self.bundle_id.to_vec();
+        aad.extend(self.index.to_le_bytes());
+        for attr in attributes {
+            if last || !SUMMARY.contains(&attr.tag) {
+                aad.extend(attr.to_bytes()?);
+            }
+        }
+        Ok(aad)
+    }
+}
+
+/// Return the associated data for a chunk of a resource, which is empty for
+/// resources that aren't bound to a packed file.
+fn associated_data(
+    binding: Option<&Binding>,
+    attributes: &[Extension],
+    last: bool,
+) -> Result<Vec<u8>, TardisError> {
+    match binding {
+        Some(binding) => binding.associated_data(attributes, last),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Read from `input` until `buf` is full or the end of the input is reached.
+/// Returns the number of bytes read.
+fn read_full<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<usize, TardisError> {
+    let mut n = 0;
+    while n < buf.len() {
+        match input.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(n)
+}
+
+fn check_chunk_size(chunk_size: u32) -> Result<(), TardisError> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(TardisError::CodecError(format!(
+            "chunk size must be between 1 and {MAX_CHUNK_SIZE} bytes"
+        )));
+    }
+    Ok(())
+}
+
+/// Running state shared by the sealing and opening sides of a stream.
+struct StreamState {
+    key: LessSafeKey,
+    counter: u64,
+    hasher: Context,
+    original_size: u64,
+    sealed_size: u64,
+}
+
+impl StreamState {
+    fn new(key: &[u8; 32]) -> Self {
+        StreamState {
+            key: LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).unwrap()),
+            counter: 0,
+            hasher: Context::new(&SHA256),
+            original_size: 0,
+            sealed_size: 0,
+        }
+    }
+
+    /// Add a chunk of uncompressed data to the running size and digest.
+    fn absorb(&mut self, chunk: &[u8]) {
+        self.hasher.update(chunk);
+        self.original_size += chunk.len() as u64;
+    }
+
+    /// Compress and seal one chunk, and write it to `output`. The chunk must
+    /// already have been passed to [`StreamState::absorb`].
+    fn seal_chunk<W: Write>(
+        &mut self,
+        chunk: &[u8],
+        last: bool,
+        aad: &[u8],
+        codec: &dyn Codec,
+        output: &mut W,
+    ) -> Result<(), TardisError> {
+        self.seal_compressed(codec.compress(chunk)?, last, aad, output)
+    }
+
+    /// Seal one chunk that has already been compressed, and write it to
+    /// `output`.
+    fn seal_compressed<W: Write>(
+        &mut self,
+        mut sealed: Vec<u8>,
+        last: bool,
+        aad: &[u8],
+        output: &mut W,
+    ) -> Result<(), TardisError> {
+        self.key
+            .seal_in_place_append_tag(
+                crypto::stream_nonce(self.counter, last),
+                Aad::from(aad),
+                &mut sealed,
+            )
+            .map_err(|_| TardisError::CodecError("failed to seal chunk".into()))?;
+        self.counter += 1;
+
+        let header = sealed.len() as u32 | if last { LAST_CHUNK } else { 0 };
+        output.write_all(&header.to_le_bytes())?;
+        output.write_all(&sealed)?;
+        self.sealed_size += 4 + sealed.len() as u64;
+
+        Ok(())
+    }
+
+    /// Read, open and decompress the next chunk from `input`. Returns the
+    /// chunk and whether it was the final chunk of the stream.
+    ///
+    /// `aad` is the associated data of every chunk but the final one, which
+    /// uses `last_aad`. Chunks that are longer than `max_len` are rejected
+    /// before anything is allocated for them, as are chunks that decompress to
+    /// more than `chunk_size` bytes.
+    fn open_chunk<R: Read>(
+        &mut self,
+        input: &mut R,
+        max_len: u64,
+        chunk_size: u32,
+        aad: &[u8],
+        last_aad: &[u8],
+        codec: &dyn Codec,
+    ) -> Result<(Vec<u8>, bool), TardisError> {
+        let (compressed, last) = self.open_compressed(input, max_len, aad, last_aad)?;
+        let chunk = codec.decompress(&compressed, chunk_size as usize)?;
+        self.absorb(&chunk);
+
+        Ok((chunk, last))
+    }
+
+    /// Read and open the next chunk from `input`, without decompressing it.
+    /// The chunk still has to be passed to [`StreamState::absorb`] once it's
+    /// been decompressed.
+    fn open_compressed<R: Read>(
+        &mut self,
+        input: &mut R,
+        max_len: u64,
+        aad: &[u8],
+        last_aad: &[u8],
+    ) -> Result<(Vec<u8>, bool), TardisError> {
+        let mut header = [0u8; 4];
+        if read_full(input, &mut header)? != header.len() {
+            return Err(TardisError::IntegrityError("resource is truncated".into()));
+        }
+        let header = u32::from_le_bytes(header);
+        let last = header & LAST_CHUNK != 0;
+        let len = header & !LAST_CHUNK;
+        if u64::from(len) > max_len {
+            return Err(TardisError::FileCorrupted(format!(
+                "chunk of {len} bytes is longer than the {max_len} bytes allowed"
+            )));
+        }
+
+        let mut sealed = vec![0u8; len as usize];
+        if read_full(input, &mut sealed)? != sealed.len() {
+            return Err(TardisError::IntegrityError("resource is truncated".into()));
+        }
+        self.sealed_size += 4 + sealed.len() as u64;
+
+        let len = self
+            .key
+            .open_in_place(
+                crypto::stream_nonce(self.counter, last),
+                Aad::from(if last { last_aad } else { aad }),
+                &mut sealed,
+            )
+            .map_err(|_| TardisError::AuthenticationError)?
+            .len();
+        self.counter += 1;
+        sealed.truncate(len);
+
+        Ok((sealed, last))
+    }
+
+    fn summary(&self) -> StreamSummary {
+        StreamSummary {
+            original_size: self.original_size,
+            sha256: self.hasher.clone().finish().as_ref().try_into().unwrap(),
+            sealed_size: self.sealed_size,
+        }
+    }
+}
+
+/// Compress and encrypt everything read from `input`, writing a stream of
+/// sealed chunks to `output`.
+pub fn seal<R: Read, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    key: &[u8; 32],
+    codec: &dyn Codec,
+    chunk_size: u32,
+) -> Result<StreamSummary, TardisError> {
+    check_chunk_size(chunk_size)?;
+    let mut state = StreamState::new(key);
+
+    // We always read one chunk ahead so that we know whether the current
+    // chunk is the last one before sealing it.
+    let mut current = vec![0u8; chunk_size as usize];
+    let mut next = vec![0u8; chunk_size as usize];
+    let mut current_len = read_full(input, &mut current)?;
+
+    loop {
+        let next_len = match current_len {
+            n if n == current.len() => read_full(input, &mut next)?,
+            _ => 0,
+        };
+        let last = next_len == 0;
+        state.absorb(&current[..current_len]);
+        state.seal_chunk(&current[..current_len], last, &[], codec, output)?;
+
+        if last {
+            break;
+        }
+        std::mem::swap(&mut current, &mut next);
+        current_len = next_len;
+    }
+
+    Ok(state.summary())
+}
+
+/// Decrypt and decompress a stream of sealed chunks read from `input`, writing
+/// the uncompressed data to `output`.
+///
+/// Reading stops after the final chunk, so `input` may contain other data
+/// after the stream.
+pub fn open<R: Read, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    key: &[u8; 32],
+    codec: &dyn Codec,
+) -> Result<StreamSummary, TardisError> {
+    let mut state = StreamState::new(key);
+
+    loop {
+        let (chunk, last) = state.open_chunk(
+            input,
+            max_sealed_len(MAX_CHUNK_SIZE),
+            MAX_CHUNK_SIZE,
+            &[],
+            &[],
+            codec,
+        )?;
+        output.write_all(&chunk)?;
+        if last {
+            break;
+        }
+    }
+
+    Ok(state.summary())
+}
+
+/// Compress and encrypt everything read from `input`, and write it to `output`
+/// as a new chunked resource. Returns the header of the new resource.
+pub fn write_resource<R: Read, W: Write + Seek>(
+    input: &mut R,
+    output: &mut W,
+    metadata: ResourceMetadata,
+    codec: &dyn Codec,
+    chunk_size: u32,
+    binding: Option<Binding>,
+) -> Result<ResourceHeader, TardisError> {
+    let mut writer = ResourceWriter::new(output, metadata, codec, chunk_size, binding)?;
+    io::copy(input, &mut writer)?;
+    let (_, header) = writer.finish()?;
+    Ok(header)
+}
+
+/// Decrypt and decompress the data of a resource, writing the uncompressed
+/// data to `output`. `input` must be positioned at the start of the resource's
+/// data (i.e. just after its header), and `binding` must be the binding that
+/// the resource was written with.
+///
+/// The data is checked against the size and digest recorded in the resource's
+/// metadata. Since this only happens once the whole resource has been read,
+/// callers must not trust anything written to `output` if this function
+/// returns an error.
+pub fn read_resource<R: Read, W: Write>(
+    input: &mut R,
+    header: &ResourceHeader,
+    binding: Option<Binding>,
+    output: &mut W,
+) -> Result<StreamSummary, TardisError> {
+    let mut reader = ResourceReader::new(input, header, binding)?;
+    io::copy(&mut reader, output)?;
+    reader
+        .summary()
+        .ok_or_else(|| TardisError::IntegrityError("resource ended early".into()))
+}
+
+/// Copy a resource to `output` as a resource bound to `binding`, without
+/// recompressing its data. `input` must be positioned at the start of the
+/// resource's data (i.e. just after its header), and `old_binding` must be the
+/// binding that the resource was written with. Returns the header of the new
+/// resource.
+///
+/// Each chunk is opened and sealed again under a new key, so the new resource
+/// keeps the old one's codec settings, even those that aren't recorded in its
+/// metadata. As with [`read_resource`], the data is only checked against the
+/// resource's size and digest once all of it has been copied, so `output` must
+/// be discarded if this function returns an error.
+pub fn reseal_resource<R: Read, W: Write>(
+    input: &mut R,
+    header: &ResourceHeader,
+    old_binding: Option<Binding>,
+    output: &mut W,
+    binding: Option<Binding>,
+) -> Result<ResourceHeader, TardisError> {
+    let metadata = ResourceMetadata::from_attributes(&header.attributes)?;
+    let Some(chunk_size) = metadata.chunk_size else {
+        return Err(TardisError::MalformedManifest(
+            "only chunked resources can be sealed again".into(),
+        ));
+    };
+    let chunk_size = chunk_size.min(MAX_CHUNK_SIZE);
+    let codec = codec::new(metadata.codec.unwrap_or(CodecId::Lz4), None)?;
+    let mut input = input.take(header.length);
+    let old_aad = associated_data(old_binding.as_ref(), &header.attributes, false)?;
+    let old_last_aad = associated_data(old_binding.as_ref(), &header.attributes, true)?;
+    let mut old_state = StreamState::new(&header.key);
+
+    // Chunks keep their length, so the new resource is exactly as long as the
+    // old one and its header can be written up front
+    let rng = rand::SystemRandom::new();
+    let key = rand::generate::<[u8; 32]>(&rng).unwrap().expose();
+    let new_header = ResourceHeader::new(header.length, key, header.attributes.clone())?;
+    output.write_all(&new_header.to_bytes()?)?;
+    let aad = associated_data(binding.as_ref(), &header.attributes, false)?;
+    let last_aad = associated_data(binding.as_ref(), &header.attributes, true)?;
+    let mut state = StreamState::new(&key);
+
+    loop {
+        let max_len = max_sealed_len(chunk_size).min(input.limit().saturating_sub(4));
+        let (compressed, last) =
+            old_state.open_compressed(&mut input, max_len, &old_aad, &old_last_aad)?;
+        old_state.absorb(&codec.decompress(&compressed, chunk_size as usize)?);
+        state.seal_compressed(
+            compressed,
+            last,
+            if last { &last_aad } else { &aad },
+            output,
+        )?;
+        if last {
+            break;
+        }
+    }
+
+    let summary = old_state.summary();
+    if summary.sealed_size != header.length {
+        return Err(TardisError::IntegrityError(
+            "unexpected data after the final chunk".into(),
+        ));
+    }
+    metadata.verify_summary(summary.original_size, &summary.sha256)?;
+
+    Ok(new_header)
+}
+
+/// Writer that compresses and encrypts data into a new resource as it's
+/// written.
+///
+/// The resource's header comes before its data, but its size and digest are
+/// only known once all of the data has been written, so `output` must be
+/// seekable so that the header can be filled in by [`ResourceWriter::finish`].
+/// A resource that is dropped without being finished is left incomplete.
+pub struct ResourceWriter<'a, W: Write + Seek> {
+    output: W,
+    metadata: ResourceMetadata,
+    codec: &'a dyn Codec,
+    key: [u8; 32],
+    binding: Option<Binding>,
+    aad: Vec<u8>,
+    header_start: u64,
+    state: StreamState,
+    buffer: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl<'a, W: Write + Seek> ResourceWriter<'a, W> {
+    /// Start writing a new resource to `output`, at its current position.
+    /// Resources that are part of a packed file must be bound to their
+    /// position in the file with `binding`.
+    pub fn new(
+        mut output: W,
+        mut metadata: ResourceMetadata,
+        codec: &'a dyn Codec,
+        chunk_size: u32,
+        binding: Option<Binding>,
+    ) -> Result<Self, TardisError> {
+        check_chunk_size(chunk_size)?;
+        let rng = rand::SystemRandom::new();
+        let key = rand::generate::<[u8; 32]>(&rng).unwrap().expose();
+
+        // Reserve space for the header
+        metadata.codec = Some(codec.id());
+        metadata.chunk_size = Some(chunk_size);
+        metadata.original_size = Some(0);
+        metadata.sha256 = Some([0; 32]);
+        let header = ResourceHeader::new(0, key, metadata.to_attributes()?)?;
+        let header_start = output.stream_position()?;
+        output.write_all(&header.to_bytes()?)?;
+        let aad = associated_data(binding.as_ref(), &header.attributes, false)?;
+
+        Ok(ResourceWriter {
+            output,
+            metadata,
+            codec,
+            key,
+            binding,
+            aad,
+            header_start,
+            state: StreamState::new(&key),
+            buffer: Vec::with_capacity(chunk_size as usize),
+            chunk_size: chunk_size as usize,
+        })
+    }
+
+    /// Seal the final chunk and fill in the resource's header. Returns the
+    /// underlying writer, positioned just after the resource, along with the
+    /// resource's header.
+    pub fn finish(mut self) -> Result<(W, ResourceHeader), TardisError> {
+        self.state.absorb(&self.buffer);
+        let summary = self.state.summary();
+        self.metadata.original_size = Some(summary.original_size);
+        self.metadata.sha256 = Some(summary.sha256);
+        let attributes = self.metadata.to_attributes()?;
+
+        let aad = associated_data(self.binding.as_ref(), &attributes, true)?;
+        self.state
+            .seal_chunk(&self.buffer, true, &aad, self.codec, &mut self.output)?;
+
+        // Go back and fill in the header
+        let header = ResourceHeader::new(self.state.sealed_size, self.key, attributes)?;
+        let end = self.output.stream_position()?;
+        self.output.seek(SeekFrom::Start(self.header_start))?;
+        self.output.write_all(&header.to_bytes()?)?;
+        self.output.seek(SeekFrom::Start(end))?;
+
+        Ok((self.output, header))
+    }
+}
+
+impl<W: Write + Seek> Write for ResourceWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // A full chunk is only sealed once more data arrives, since until then
+        // it could still turn out to be the final chunk.
+        if self.buffer.len() == self.chunk_size {
+            self.state.absorb(&self.buffer);
+            self.state
+                .seal_chunk(&self.buffer, false, &self.aad, self.codec, &mut self.output)?;
+            self.buffer.clear();
+        }
+
+        let n = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Reader that decrypts and decompresses the data of a resource as it's read.
+///
+/// Once the final chunk has been read, the data is checked against the size
+/// and digest recorded in the resource's metadata, and an error is returned
+/// instead of the end of the stream if they don't match. As with
+/// [`read_resource`], nothing read from a `ResourceReader` should be trusted
+/// until it has reached the end of the stream without errors.
+pub struct ResourceReader<R: Read> {
+    input: io::Take<R>,
+    header: ResourceHeader,
+    metadata: ResourceMetadata,
+    codec: Box<dyn Codec>,
+    aad: Vec<u8>,
+    last_aad: Vec<u8>,
+    state: StreamState,
+    chunk: Vec<u8>,
+    pos: usize,
+    summary: Option<StreamSummary>,
+}
+
+impl<R: Read> ResourceReader<R> {
+    /// Start reading a resource. `input` must be positioned at the start of the
+    /// resource's data (i.e. just after its header), and `binding` must be the
+    /// binding that the resource was written with.
+    pub fn new(
+        input: R,
+        header: &ResourceHeader,
+        binding: Option<Binding>,
+    ) -> Result<Self, TardisError> {
+        let metadata = ResourceMetadata::from_attributes(&header.attributes)?;
+        let codec = codec::new(metadata.codec.unwrap_or(CodecId::Lz4), None)?;
+
+        // Resources that are stored as a single sealed block predate bindings
+        if binding.is_some() && metadata.chunk_size.is_none() {
+            return Err(TardisError::MalformedManifest(
+                "bound resources must be chunked".into(),
+            ));
+        }
+
+        Ok(ResourceReader {
+            input: input.take(header.length),
+            header: header.clone(),
+            metadata,
+            codec,
+            aad: associated_data(binding.as_ref(), &header.attributes, false)?,
+            last_aad: associated_data(binding.as_ref(), &header.attributes, true)?,
+            state: StreamState::new(&header.key),
+            chunk: Vec::new(),
+            pos: 0,
+            summary: None,
+        })
+    }
+
+    /// Return a summary of the resource's data, once all of it has been read
+    /// and verified.
+    pub fn summary(&self) -> Option<StreamSummary> {
+        self.summary
+    }
+
+    /// Read the next chunk of the resource into the buffer.
+    fn next_chunk(&mut self) -> Result<(), TardisError> {
+        // Resources that aren't chunked are stored as a single sealed block.
+        if self.metadata.chunk_size.is_none() {
+            let mut data = Vec::new();
+            self.input.read_to_end(&mut data)?;
+            let resource = TardisResource::new(self.header.clone(), data);
+            self.chunk = resource.decompress()?;
+            self.summary = Some(StreamSummary {
+                original_size: self.chunk.len() as u64,
+                sha256: crate::metadata::sha256(&self.chunk),
+                sealed_size: self.header.length,
+            });
+            return Ok(());
+        }
+
+        // The chunk can't be longer than what's left of the resource, once its
+        // own header has been read
+        // Writers never use chunks longer than MAX_CHUNK_SIZE, so neither can a
+        // chunk decompress to more than that
+        let chunk_size = self
+            .metadata
+            .chunk_size
+            .map_or(MAX_CHUNK_SIZE, |size| size.min(MAX_CHUNK_SIZE));
+        let max_len = max_sealed_len(chunk_size).min(self.input.limit().saturating_sub(4));
+        let (chunk, last) = self.state.open_chunk(
+            &mut self.input,
+            max_len,
+            chunk_size,
+            &self.aad,
+            &self.last_aad,
+            &*self.codec,
+        )?;
+        if last {
+            let summary = self.state.summary();
+            if summary.sealed_size != self.header.length {
+                return Err(TardisError::IntegrityError(
+                    "unexpected data after the final chunk".into(),
+                ));
+            }
+            self.metadata
+                .verify_summary(summary.original_size, &summary.sha256)?;
+            self.summary = Some(summary);
+        }
+        self.chunk = chunk;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ResourceReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.summary.is_some() || buf.is_empty() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+            self.pos = 0;
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        open, read_resource, reseal_resource, seal, write_resource, Binding, ResourceReader,
+        ResourceWriter,
+    };
+    use crate::{
+        codec::Store, error::TardisError, metadata::ResourceMetadata, serialization::ResourceHeader,
+    };
+    use deku::DekuContainerRead;
+    use std::io::{Cursor, Read, Write};
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn sealed(data: &[u8], chunk_size: u32) -> Vec<u8> {
+        let mut output = Vec::new();
+        seal(&mut &data[..], &mut output, &KEY, &Store, chunk_size).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        for len in [0, 1, 15, 16, 17, 100] {
+            let data: Vec<u8> = (0..len as u8).collect();
+            let stream = sealed(&data, 16);
+
+            let mut output = Vec::new();
+            let summary = open(&mut &stream[..], &mut output, &KEY, &Store).unwrap();
+            assert_eq!(output, data);
+            assert_eq!(summary.original_size, len as u64);
+            assert_eq!(summary.sealed_size, stream.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_truncated_stream() {
+        let stream = sealed(&[1; 64], 16);
+
+        // Drop the final chunk
+        let truncated = &stream[..stream.len() - (4 + 16 + 16)];
+        let result = open(&mut &truncated[..], &mut Vec::new(), &KEY, &Store);
+        assert!(matches!(result, Err(TardisError::IntegrityError(_))));
+
+        // Mark the new final chunk as being the last one
+        let mut forged = truncated.to_vec();
+        let n = forged.len();
+        forged[n - 36 + 3] |= 0x80;
+        let result = open(&mut &forged[..], &mut Vec::new(), &KEY, &Store);
+        assert!(matches!(result, Err(TardisError::AuthenticationError)));
+    }
+
+    #[test]
+    fn test_oversized_chunk() {
+        let mut output = Cursor::new(Vec::new());
+        let metadata = ResourceMetadata::named("guest");
+        write_resource(&mut &[1; 64][..], &mut output, metadata, &Store, 16, None).unwrap();
+        let mut bytes = output.into_inner();
+        let ((rest, _), header) = ResourceHeader::from_bytes((&bytes, 0)).unwrap();
+
+        // Claim that the first chunk is far longer than the resource
+        let data_start = bytes.len() - rest.len();
+        bytes[data_start..data_start + 4].copy_from_slice(&0x3fff_ffffu32.to_le_bytes());
+        let result = read_resource(&mut &bytes[data_start..], &header, None, &mut Vec::new());
+        assert!(matches!(result, Err(TardisError::FileCorrupted(_))));
+    }
+
+    #[test]
+    fn test_write_and_read_resource() {
+        let data = b"hello, world!".repeat(10);
+        let mut output = Cursor::new(Vec::new());
+        let metadata = ResourceMetadata::named("guest");
+        write_resource(&mut &data[..], &mut output, metadata, &Store, 16, None).unwrap();
+
+        let bytes = output.into_inner();
+        let ((rest, _), header) = ResourceHeader::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(header.length as usize, rest.len());
+
+        let metadata = header.metadata().unwrap();
+        assert_eq!(metadata.original_size, Some(data.len() as u64));
+
+        let mut decompressed = Vec::new();
+        read_resource(&mut &rest[..], &header, None, &mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_resource_writer_and_reader() {
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+
+        // Write the data in pieces that don't line up with the chunks
+        let mut writer = ResourceWriter::new(
+            Cursor::new(Vec::new()),
+            Default::default(),
+            &Store,
+            64,
+            None,
+        )
+        .unwrap();
+        for piece in data.chunks(37) {
+            writer.write_all(piece).unwrap();
+        }
+        let (output, header) = writer.finish().unwrap();
+        let bytes = output.into_inner();
+        let data_start = bytes.len() - header.length as usize;
+
+        let mut reader = ResourceReader::new(&bytes[data_start..], &header, None).unwrap();
+        let mut piece = [0u8; 23];
+        let mut decompressed = Vec::new();
+        loop {
+            let n = reader.read(&mut piece).unwrap();
+            if n == 0 {
+                break;
+            }
+            decompressed.extend_from_slice(&piece[..n]);
+        }
+        assert_eq!(decompressed, data);
+        assert_eq!(reader.summary().unwrap().original_size, 1000);
+    }
+
+    #[test]
+    fn test_resource_reader_checks_digest() {
+        let mut output = Cursor::new(Vec::new());
+        write_resource(
+            &mut &b"hello"[..],
+            &mut output,
+            Default::default(),
+            &Store,
+            4,
+            None,
+        )
+        .unwrap();
+        let bytes = output.into_inner();
+        let ((rest, _), header) = ResourceHeader::from_bytes((&bytes, 0)).unwrap();
+
+        let mut metadata = header.metadata().unwrap();
+        metadata.sha256 = Some([0; 32]);
+        let header =
+            ResourceHeader::new(header.length, header.key, metadata.to_attributes().unwrap())
+                .unwrap();
+
+        let mut reader = ResourceReader::new(rest, &header, None).unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(
+            TardisError::from(err),
+            TardisError::IntegrityError(_)
+        ));
+    }
+
+    #[test]
+    fn test_binding() {
+        let binding = Binding {
+            bundle_id: [1; 16],
+            index: 3,
+        };
+        let mut output = Cursor::new(Vec::new());
+        let metadata = ResourceMetadata::named("guest");
+        let data = b"hello, world!".repeat(4);
+        write_resource(
+            &mut &data[..],
+            &mut output,
+            metadata,
+            &Store,
+            16,
+            Some(binding),
+        )
+        .unwrap();
+        let bytes = output.into_inner();
+        let ((rest, _), header) = ResourceHeader::from_bytes((&bytes, 0)).unwrap();
+        let read = |header: &ResourceHeader, binding| {
+            read_resource(&mut &rest[..], header, binding, &mut Vec::new())
+        };
+        assert!(read(&header, Some(binding)).is_ok());
+
+        // The resource can't be moved to another position or another file
+        for binding in [
+            None,
+            Some(Binding {
+                index: 2,
+                ..binding
+            }),
+            Some(Binding {
+                bundle_id: [2; 16],
+                ..binding
+            }),
+        ] {
+            let result = read(&header, binding);
+            assert!(matches!(result, Err(TardisError::AuthenticationError)));
+        }
+
+        // The resource's metadata can't be changed
+        let mut metadata = header.metadata().unwrap();
+        metadata.name = Some("other".into());
+        let renamed =
+            ResourceHeader::new(header.length, header.key, metadata.to_attributes().unwrap())
+                .unwrap();
+        let result = read(&renamed, Some(binding));
+        assert!(matches!(result, Err(TardisError::AuthenticationError)));
+    }
+
+    #[test]
+    fn test_reseal_resource() {
+        let old_binding = Binding {
+            bundle_id: [1; 16],
+            index: 3,
+        };
+        let binding = Binding {
+            index: 1,
+            ..old_binding
+        };
+        let mut output = Cursor::new(Vec::new());
+        let data = b"hello, world!".repeat(4);
+        write_resource(
+            &mut &data[..],
+            &mut output,
+            ResourceMetadata::named("guest"),
+            &Store,
+            16,
+            Some(old_binding),
+        )
+        .unwrap();
+        let bytes = output.into_inner();
+        let ((rest, _), header) = ResourceHeader::from_bytes((&bytes, 0)).unwrap();
+
+        let mut resealed = Vec::new();
+        let new_header = reseal_resource(
+            &mut &rest[..],
+            &header,
+            Some(old_binding),
+            &mut resealed,
+            Some(binding),
+        )
+        .unwrap();
+        assert_eq!(new_header.length, header.length);
+        assert_eq!(new_header.attributes, header.attributes);
+        assert_eq!(resealed.len(), bytes.len());
+
+        let ((rest, _), header) = ResourceHeader::from_bytes((&resealed, 0)).unwrap();
+        assert_eq!(header.key, new_header.key);
+        let mut decompressed = Vec::new();
+        read_resource(&mut &rest[..], &header, Some(binding), &mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+        let result = read_resource(&mut &rest[..], &header, Some(old_binding), &mut Vec::new());
+        assert!(matches!(result, Err(TardisError::AuthenticationError)));
+    }
+}
@@ -3,9 +3,11 @@
 mod linux_syscall;
 pub use linux_syscall::LinuxSyscall;
 
+use crate::error::TardisError;
 use alloc::ffi::CString;
 use core::arch::asm;
 use core::ffi::CStr;
+use nix::errno::Errno;
 #[cfg(unix)]
 use std::os::unix::io::RawFd;
 
@@ -36,7 +38,7 @@ pub unsafe fn write(fd: i64, buf: *const u8, count: u64) -> i64 {
 /// # Safety
 /// Directly executes assembly code.
 #[inline(always)]
-pub unsafe fn memfd_create(name: &CStr, flags: u64) -> RawFd {
+pub unsafe fn memfd_create(name: &CStr, flags: u64) -> Result<RawFd, TardisError> {
     let name = name.as_ptr();
     let mut rax = LinuxSyscall::memfd_create as i64;
 
@@ -49,8 +51,14 @@ pub unsafe fn memfd_create(name: &CStr, flags: u64) -> RawFd {
         options(nostack),
     );
 
-    let fd: RawFd = rax.try_into().unwrap();
-    fd
+    // Failures are reported as a negated errno
+    match RawFd::try_from(rax) {
+        Ok(fd) if fd >= 0 => Ok(fd),
+        _ => Err(TardisError::SyscallError {
+            syscall: "memfd_create",
+            errno: Errno::from_i32(-rax as i32),
+        }),
+    }
 }
 
 /// Run the `execve_at` Linux syscall.
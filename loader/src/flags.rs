@@ -0,0 +1,107 @@
+//! Built-in flags that let a packed binary describe itself.
+//!
+//! If the first argument of a packed binary starts with the file's flag prefix
+//! (`--tardis-` unless it was changed when the file was packed), the loader
+//! handles it instead of running the guests:
+//!
+//! - `--tardis-list` lists the resources in the file.
+//! - `--tardis-extract DIR` extracts every resource into `DIR`.
+//! - `--tardis-info` describes the file and the loader.
+//! - `--tardis-version` prints the loader's version.
+//! - `--tardis-help` lists these flags.
+
+use crate::EMBEDDED_KEY;
+use libtardis::{
+    codec::CodecId, error::TardisError, runtime::Bundle, serialization::FORMAT_VERSION,
+};
+use std::{ffi::OsString, fs, path::Path};
+
+/// Handle a built-in flag. `args` holds the flag followed by the rest of the
+/// arguments.
+pub fn run(bundle: &Bundle, prefix: &str, args: &[OsString]) -> Result<(), TardisError> {
+    let flag = args[0].to_string_lossy();
+    match (flag.strip_prefix(prefix).unwrap_or_default(), &args[1..]) {
+        ("list", []) => list(bundle),
+        ("extract", [dir]) => extract(bundle, Path::new(dir)),
+        ("info", []) => info(bundle, prefix),
+        ("version", []) => {
+            version();
+            Ok(())
+        }
+        ("help", []) => {
+            help(prefix);
+            Ok(())
+        }
+        _ => {
+            eprintln!("tardis: bad use of {flag}; see {prefix}help");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn list(bundle: &Bundle) -> Result<(), TardisError> {
+    for (index, entry) in bundle.entries().iter().enumerate() {
+        let metadata = entry.header.metadata()?;
+        println!(
+            "{index:>3}  {:<10}  {:>10}  {}",
+            metadata.kind.unwrap_or_default().name(),
+            metadata
+                .original_size
+                .map_or("?".into(), |size| size.to_string()),
+            metadata.name.as_deref().unwrap_or("unnamed"),
+        );
+    }
+    Ok(())
+}
+
+/// Extract every resource into `dir`, in the same way as `tardis unpack`.
+fn extract(bundle: &Bundle, dir: &Path) -> Result<(), TardisError> {
+    fs::create_dir_all(dir)?;
+    for index in 0..bundle.entries().len() {
+        println!("{}", bundle.extract_to(index, dir)?.display());
+    }
+    Ok(())
+}
+
+fn info(bundle: &Bundle, prefix: &str) -> Result<(), TardisError> {
+    let manifest = bundle.manifest();
+    version();
+    println!("format version: {}", manifest.marker.version);
+    if let Some(bundle_id) = manifest.header.bundle_id() {
+        let hex: String = bundle_id.iter().map(|b| format!("{b:02x}")).collect();
+        println!("bundle id: {hex}");
+    }
+    println!("loader size: {}", manifest.header.resources_start);
+    println!("resources: {}", bundle.entries().len());
+    println!(
+        "signature: {}",
+        match EMBEDDED_KEY.public_key() {
+            Some(_) => "required and verified",
+            None => "not required",
+        }
+    );
+    let codecs: Vec<_> = CodecId::ALL
+        .iter()
+        .filter(|codec| codec.is_available())
+        .map(|codec| codec.name())
+        .collect();
+    println!("codecs: {}", codecs.join(", "));
+    println!("flag prefix: {prefix}");
+    Ok(())
+}
+
+fn version() {
+    println!(
+        "tardis loader {} (format version {FORMAT_VERSION})",
+        env!("CARGO_PKG_VERSION")
+    );
+}
+
+fn help(prefix: &str) {
+    println!("Flags handled by the tardis loader instead of the packed program:");
+    println!("  {prefix}list           List the resources in this file");
+    println!("  {prefix}extract DIR    Extract every resource into DIR");
+    println!("  {prefix}info           Describe this file and its loader");
+    println!("  {prefix}version        Print the loader's version");
+    println!("  {prefix}help           Print this message");
+}
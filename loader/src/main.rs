@@ -2,84 +2,502 @@
 //!
 //! This program is in charge of reading the compressed binary from
 //! the manifest, decompressing it in memory, and then running it.
+//!
+//! Guests are streamed from the packed binary into memory one chunk at a time,
+//! so the loader never holds more than a couple of chunks of a guest (on top of
+//! the in-memory file that the guest is written to).
+//!
+//! If the loader can't run its guests, it prints the reason and exits with one
+//! of the following codes:
+//!
+//! | Code | Error                                                       |
+//! |------|-------------------------------------------------------------|
+//! | 1    | any other error                                             |
+//! | 2    | a built-in flag (see [`flags`]) was misused                 |
+//! | 10   | the packed binary couldn't be read                          |
+//! | 11   | the packed binary doesn't end with a manifest (bad magic)   |
+//! | 12   | the packed binary is truncated                              |
+//! | 13   | the packed binary is corrupted                              |
+//! | 14   | the manifest is malformed, or names a guest that's missing  |
+//! | 15   | the manifest uses an unsupported format version             |
+//! | 16   | the manifest uses an unsupported critical extension         |
+//! | 17   | a guest uses a codec that this loader wasn't built with     |
+//! | 18   | a guest failed authentication                               |
+//! | 19   | the packed binary isn't signed, but must be                 |
+//! | 20   | the packed binary's signature is invalid                    |
+//! | 21   | a guest couldn't be decompressed                            |
+//! | 22   | a guest doesn't match its recorded size or digest           |
+//! | 23   | a system call failed (e.g. `memfd_create` or `execveat`)    |
+//! | 24   | a guest that others depend on stopped before it was ready   |
+//! | 25   | some other I/O error (e.g. a guest couldn't be written out) |
+//!
+//! Setting `TARDIS_DEBUG=1` makes the loader print a trace of each step to
+//! stderr: where the manifest is, what each resource holds, how each guest was
+//! decrypted and decompressed, and how it was run. Building with the `small`
+//! feature leaves the trace out, which makes the loader a little smaller.
+//!
+//! When there are several guests, each one runs in its own child process. If
+//! the packed file records an exit policy, the loader waits for the guests and
+//! exits with the status that the policy picks, forwarding the signals it
+//! receives to the guests in the meantime (see [`supervisor`]); otherwise it
+//! exits as soon as they've been started. A guest that's marked as the
+//! foreground guest is run in the loader's own process instead, once the
+//! others have been started; it then has the loader's pid, terminal and exit
+//! status, as if it had been run directly.
+//!
+//! Guests are started after the guests that they depend on are ready (see
+//! [`startup`]), and otherwise in the order that they were packed in.
 
-use deku::DekuContainerRead;
-use libtardis::serialization::{EndMarker, TardisResource};
+mod flags;
+mod startup;
+mod supervisor;
+
+use libtardis::{
+    error::TardisError,
+    metadata::{self, ResourceMetadata, Restart},
+    runtime::{Bundle, Guest},
+    signature::EmbeddedKey,
+};
 use nix::{
-    fcntl::AtFlags,
-    sys::memfd::{memfd_create, MemFdCreateFlag},
-    unistd::{execveat, fork, ForkResult},
+    libc,
+    sys::wait::{waitpid, WaitStatus},
+    unistd::{fork, ForkResult, Pid},
 };
+use startup::Startup;
 use std::{
     env,
-    error::Error,
-    ffi::{CStr, CString},
-    fs::{self, File},
-    io::Write,
-    os::{fd::AsRawFd, unix::io::FromRawFd},
+    ffi::{c_char, CStr, CString, OsString},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::ffi::OsStringExt,
+    },
 };
+use supervisor::Supervisor;
 
-fn spawn_guest(res: TardisResource) -> Result<(), Box<dyn Error>> {
-    // Decompress the guest
-    let guest = res.decompress().expect("invalid lz4 payload");
+/// Public key that packed files must be signed with. This is filled in by the
+/// packer when it signs a file; if it's left empty, files don't need to be
+/// signed.
+#[used]
+static EMBEDDED_KEY: EmbeddedKey = EmbeddedKey::EMPTY;
 
-    // Create an in-memory file to store the guest binary
-    let name = match CStr::from_bytes_with_nul(b"a\0") {
-        Ok(fd) => fd,
-        Err(_) => panic!("aborted"),
+/// Print a line of the diagnostics trace, if it's turned on. See the module
+/// documentation.
+#[cfg(not(feature = "small"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if debug_enabled() {
+            eprintln!("tardis: debug: {}", format_args!($($arg)*));
+        }
     };
-    let flags = MemFdCreateFlag::MFD_CLOEXEC;
-    let fd = memfd_create(name, flags)?;
+}
 
-    // Write the guest binary to the in-memory file
-    let mut f = unsafe { File::from_raw_fd(fd.as_raw_fd()) };
-    f.write_all(&guest)?;
+/// Without the trace, the arguments are still type-checked (so that they don't
+/// turn into unused variables) but never evaluated, and the optimizer drops them.
+#[cfg(feature = "small")]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if false {
+            eprintln!($($arg)*);
+        }
+    };
+}
 
-    // Use execveat to run the binary
-    let argv: Vec<CString> = env::args().filter_map(|x| CString::new(x).ok()).collect();
+/// Return whether `TARDIS_DEBUG=1` is set.
+#[cfg(not(feature = "small"))]
+fn debug_enabled() -> bool {
+    static ENABLED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *ENABLED.get_or_init(|| env::var_os("TARDIS_DEBUG").is_some_and(|value| value == "1"))
+}
 
-    let envp: Vec<CString> = env::vars()
-        .map(|(k, v)| format!("{k}={v}\0"))
-        .filter_map(|x| CString::new(x).ok())
-        .collect();
+extern "C" {
+    /// The environment that the loader was started with, as a null-terminated
+    /// array of `NAME=value` strings.
+    static environ: *const *const c_char;
+}
 
-    let path = CStr::from_bytes_with_nul(b"\0")?;
-    let flags = AtFlags::AT_EMPTY_PATH;
+/// Return the environment that the loader was started with, exactly as it was
+/// passed to it. Unlike `env::vars_os`, this keeps entries that don't contain
+/// an `=` or that repeat a name.
+fn inherited_env() -> Vec<CString> {
+    let mut vars = Vec::new();
+    // SAFETY: the loader doesn't change its environment, so `environ` is the
+    // array that was set up by the C runtime before `main` was called
+    unsafe {
+        let mut var = environ;
+        while !var.is_null() && !(*var).is_null() {
+            vars.push(CStr::from_ptr(*var).to_owned());
+            var = var.add(1);
+        }
+    }
+    vars
+}
+
+/// Build the argument and environment lists that a guest is run with. Both are
+/// passed on byte for byte, whether or not they're valid UTF-8.
+fn guest_command(metadata: &ResourceMetadata) -> (Vec<CString>, Vec<CString>) {
+    // Arguments recorded when the guest was packed come before the ones that
+    // the loader was run with. Arguments can't contain NUL, so converting them
+    // to C strings never fails.
+    let mut args = env::args_os();
+    let argv: Vec<CString> = args
+        .next()
+        .into_iter()
+        .chain(metadata.args.iter().map(OsString::from))
+        .chain(args)
+        .filter_map(|arg| CString::new(arg.into_vec()).ok())
+        .collect();
 
-    execveat(fd.as_raw_fd(), path, &argv, &envp, flags)?;
+    // Variables recorded when the guest was packed take precedence over the
+    // ones that the loader was run with
+    let overridden = |var: &CString| {
+        let name = var
+            .as_bytes()
+            .split(|&b| b == b'=')
+            .next()
+            .unwrap_or_default();
+        metadata.env.iter().any(|(k, _)| k.as_bytes() == name)
+    };
+    let mut envp: Vec<CString> = inherited_env()
+        .into_iter()
+        .filter(|var| !overridden(var))
+        .collect();
+    envp.extend(
+        metadata
+            .env
+            .iter()
+            .filter_map(|(k, v)| CString::new(format!("{k}={v}")).ok()),
+    );
 
-    // Should not reach this point
-    Ok(())
+    (argv, envp)
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let host = fs::read("/proc/self/exe").unwrap();
-    let marker_start = host.len() - EndMarker::nbytes();
+fn run() -> Result<i32, TardisError> {
+    trace!("reading manifest of {}", libtardis::runtime::HOST);
+    let bundle = Bundle::open_self()?;
+    let manifest = bundle.manifest();
+    trace!(
+        "manifest: format version {}, resources start at {:#x}, manifest starts at {:#x}, {} \
+         resources, file digest {}",
+        manifest.marker.version,
+        manifest.header.resources_start,
+        manifest.marker.manifest_start,
+        manifest.marker.n_resources,
+        if manifest.digest.is_some() {
+            "checked"
+        } else {
+            "absent"
+        },
+    );
+
+    match EMBEDDED_KEY.public_key() {
+        Some(public_key) => {
+            bundle.verify_signature(&public_key)?;
+            trace!("signature verified");
+        }
+        None => trace!("no embedded public key; not checking for a signature"),
+    }
+
+    // Built-in flags are handled instead of running the guests
+    let args: Vec<OsString> = env::args_os().collect();
+    if let Some(prefix) = bundle.manifest().header.flag_prefix() {
+        if args
+            .get(1)
+            .is_some_and(|arg| arg.as_encoded_bytes().starts_with(prefix.as_bytes()))
+        {
+            trace!("handling built-in flag {:?}", args[1]);
+            return flags::run(&bundle, prefix, &args[1..]).map(|()| 0);
+        }
+    }
+
+    // Every guest is decompressed and authenticated before any of them is run,
+    // so that nothing runs if the manifest has been rearranged or tampered
+    // with. Resources that only hold data aren't run.
+    let mut guests: Vec<(usize, Guest)> = Vec::new();
+    for (index, entry) in bundle.entries().iter().enumerate() {
+        let metadata = entry.header.metadata()?;
+        let kind = metadata.kind.unwrap_or_default();
+        trace!(
+            "resource {index} ({}): {}, {} bytes stored at {:#x}, {} bytes uncompressed, codec {}",
+            metadata.name.as_deref().unwrap_or("unnamed"),
+            kind.name(),
+            entry.header.length,
+            entry.data_start,
+            metadata
+                .original_size
+                .map_or("?".into(), |size| size.to_string()),
+            metadata.codec.map_or("?", |codec| codec.name()),
+        );
+        if !kind.is_runnable() {
+            trace!("resource {index}: not runnable; skipping");
+            continue;
+        }
+
+        let guest = bundle.materialize(entry).inspect_err(|e| {
+            trace!("resource {index}: decrypting and decompressing failed: {e}");
+        })?;
+        trace!(
+            "resource {index}: decrypted {} bytes and decompressed {} bytes (sha256 {}) into \
+             memfd fd {}",
+            guest.summary.sealed_size,
+            guest.summary.original_size,
+            guest
+                .summary
+                .sha256
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>(),
+            guest.file.as_raw_fd(),
+        );
+        guests.push((index, guest));
+    }
+
+    // Guests are started after the guests they depend on
+    let order = metadata::start_order(
+        &guests
+            .iter()
+            .map(|(_, guest)| &guest.metadata)
+            .collect::<Vec<_>>(),
+    )?;
+    let mut unordered: Vec<_> = guests.into_iter().map(Some).collect();
+    let mut guests: Vec<_> = order
+        .into_iter()
+        .filter_map(|position| unordered[position].take())
+        .collect();
+    trace!(
+        "starting resources in the order {:?}",
+        guests.iter().map(|(index, _)| index).collect::<Vec<_>>()
+    );
+
+    let policy = bundle.manifest().header.exit_policy()?;
+    let primary = match &policy {
+        Some(policy) => {
+            let headers: Vec<_> = bundle
+                .entries()
+                .iter()
+                .map(|entry| entry.header.clone())
+                .collect();
+            policy.primary_index(&headers)?
+        }
+        None => None,
+    };
+    let grace_period = bundle.manifest().header.grace_period()?;
+
+    // Guests are only restarted by a supervising loader
+    let restarts = |guest: &Guest| {
+        policy.is_some()
+            && guest
+                .metadata
+                .restart
+                .is_some_and(|restart| restart.restart != Restart::No)
+    };
 
-    let (_, marker) = EndMarker::from_bytes((&host[marker_start..], 0))?;
-    let mut offset = marker.manifest_start;
+    // A foreground guest takes over the loader's process, so that it has the
+    // loader's pid and exit status. The other guests are started first, in
+    // processes that aren't its children, and they aren't supervised.
+    if let Some(position) = guests
+        .iter()
+        .position(|(_, guest)| guest.metadata.foreground)
+    {
+        let (index, guest) = guests.remove(position);
+        trace!("resource {index} runs in the foreground");
+        if let Some(name) = &guest.metadata.name {
+            if guests
+                .iter()
+                .any(|(_, other)| other.metadata.after.contains(name))
+            {
+                return Err(TardisError::MalformedManifest(format!(
+                    "{name} runs in the foreground, so no guest can be started after it"
+                )));
+            }
+        }
+        if !guests.is_empty() {
+            spawn_detached(&guests, &guest.metadata.after)?;
+        }
+        drop(guests);
+        return Err(exec(&guest));
+    }
+
+    // Only fork off processes if there is more than one executable that needs
+    // to be launched, or if the only one has to be restarted. The guests'
+    // files are closed on exec, so they don't leak into each other.
+    if let [(_, guest)] = &guests[..] {
+        if !restarts(guest) {
+            return Err(exec(guest));
+        }
+    }
 
-    // Read the next resource and fork a new process off
-    // of it
-    for _ in 0..marker.n_resources {
-        let (_, resource) = TardisResource::from_bytes((&host[offset..], 0))?;
-        offset += resource.len();
+    // Supervised guests have their signals forwarded to them from before they
+    // start, so that none of them is left behind if the loader is stopped
+    let mut supervisor = match &policy {
+        Some(_) => Some(Supervisor::new(guests.len(), grace_period)?),
+        None => None,
+    };
+    let mut startup = Startup::new(&guests, &[]);
+    for (index, guest) in &guests {
+        let pid = match startup.spawn(guest) {
+            Ok(pid) => pid,
+            Err(e) => {
+                // Supervised guests don't outlive the loader
+                if let Some(supervisor) = supervisor {
+                    supervisor.shut_down();
+                }
+                return Err(e);
+            }
+        };
+        if let Some(supervisor) = &mut supervisor {
+            let restart = guest.metadata.restart.filter(|_| restarts(guest));
+            supervisor.add(
+                *index,
+                guest.metadata.name.clone().unwrap_or_default(),
+                pid,
+                restart,
+            );
+        }
+    }
 
-        // Only fork off processes if there is more than one executable that needs
-        // to be launched.
-        if marker.n_resources == 1 {
-            spawn_guest(resource)?;
+    // The guests' in-memory files aren't kept around while they run. Guests
+    // that are restarted are decompressed from the packed file again.
+    drop(guests);
 
-            // Should not reach this point
-            return Ok(());
+    // Without an exit policy, the guests are left to run on their own
+    match (supervisor, policy) {
+        (Some(supervisor), Some(policy)) => {
+            trace!(
+                "supervising guests with policy {} and a grace period of {grace_period}s",
+                policy.name()
+            );
+            let respawn = |index: usize| {
+                let guest = bundle.materialize(&bundle.entries()[index])?;
+                trace!(
+                    "resource {index}: decrypted and decompressed again into memfd fd {}",
+                    guest.file.as_raw_fd()
+                );
+                spawn(&guest, None)
+            };
+            Ok(supervisor.wait(&policy, primary, respawn))
         }
+        _ => Ok(0),
+    }
+}
+
+/// Run a guest in the loader's process, and return the error that kept it from
+/// running.
+fn exec(guest: &Guest) -> TardisError {
+    let (argv, envp) = guest_command(&guest.metadata);
+    trace!(
+        "execveat(fd {}, argv {argv:?}, {} environment variables)",
+        guest.file.as_raw_fd(),
+        envp.len(),
+    );
+    let Err(e) = guest.exec(&argv, &envp);
+    trace!("execveat returned an error: {e}");
+    e
+}
 
-        match unsafe { fork() } {
-            Ok(ForkResult::Child) => spawn_guest(resource)?,
-            Ok(_) => continue,
-            Err(e) => return Err(Box::new(e)),
+/// Run guests in processes that aren't children of the loader. They're started
+/// from an intermediate process that exits once they've been started and the
+/// guests named in `after` are ready, so that they're adopted by init rather
+/// than by the guest that replaces the loader.
+fn spawn_detached(guests: &[(usize, Guest)], after: &[String]) -> Result<(), TardisError> {
+    let syscall = |syscall| move |errno| TardisError::SyscallError { syscall, errno };
+    match unsafe { fork() }.map_err(syscall("fork"))? {
+        ForkResult::Child => {
+            let mut startup = Startup::new(guests, after);
+            let started = guests
+                .iter()
+                .try_for_each(|(_, guest)| startup.spawn(guest).map(drop))
+                .and_then(|()| startup.wait_for(after));
+            if let Err(e) = started {
+                eprintln!("tardis: {e}");
+                unsafe { libc::_exit(exit_code(&e)) };
+            }
+            unsafe { libc::_exit(0) };
+        }
+        ForkResult::Parent { child } => {
+            // The intermediate process has already said why it failed, if it
+            // did, so the loader only passes its status on
+            match waitpid(child, None).map_err(syscall("waitpid"))? {
+                WaitStatus::Exited(_, 0) => Ok(()),
+                WaitStatus::Exited(_, code) => std::process::exit(code),
+                WaitStatus::Signaled(_, signal, _) => std::process::exit(128 + signal as i32),
+                _ => Ok(()),
+            }
         }
     }
+}
+
+/// Run a guest in a child process. If `notify` is given, the guest inherits
+/// that descriptor to say when it's ready (see [`startup`]).
+fn spawn(guest: &Guest, notify: Option<RawFd>) -> Result<Pid, TardisError> {
+    let (argv, mut envp) = guest_command(&guest.metadata);
+    let mut inherited = Vec::new();
+    if let Some(fd) = notify {
+        let prefix = format!("{}=", startup::NOTIFY_FD);
+        envp.retain(|var| !var.as_bytes().starts_with(prefix.as_bytes()));
+        envp.extend(CString::new(format!("{prefix}{fd}")));
+        inherited.push(fd);
+    }
+    trace!(
+        "forking to execveat(fd {}, argv {argv:?}, {} environment variables{})",
+        guest.file.as_raw_fd(),
+        envp.len(),
+        match notify {
+            Some(fd) => format!(", notify fd {fd}"),
+            None => String::new(),
+        },
+    );
+    let pid = guest
+        .spawn_inheriting(&argv, &envp, &inherited)
+        .inspect_err(|e| {
+            trace!("execveat returned an error: {e}");
+        })?;
+    trace!("execveat succeeded in pid {pid}");
+    Ok(pid)
+}
+
+/// Return the code that the loader exits with when it fails with `err`. See the
+/// table in the module documentation.
+fn exit_code(err: &TardisError) -> i32 {
+    match err {
+        TardisError::HostReadError(_) => 10,
+        TardisError::BadMagic(_) => 11,
+        TardisError::FileTruncated(_) => 12,
+        TardisError::FileCorrupted(_) => 13,
+        TardisError::MalformedManifest(_)
+        | TardisError::UnknownGuest(_)
+        | TardisError::UnknownDependency { .. }
+        | TardisError::DependencyCycle(_) => 14,
+        TardisError::UnsupportedVersion(_) => 15,
+        TardisError::UnsupportedExtension(_) => 16,
+        TardisError::UnsupportedCodec(_) => 17,
+        TardisError::AuthenticationError => 18,
+        TardisError::MissingSignature => 19,
+        TardisError::InvalidSignature => 20,
+        TardisError::DecompressionError(_) => 21,
+        TardisError::IntegrityError(_) => 22,
+        TardisError::SyscallError { .. } => 23,
+        TardisError::NotReady(_) => 24,
+        TardisError::FilesystemError(_) => 25,
+
+        // These only happen while packing
+        TardisError::CodecError(_)
+        | TardisError::InvalidKey(_)
+        | TardisError::MissingLoader
+        | TardisError::NoGuests
+        | TardisError::TooManyGuests(_)
+        | TardisError::TooLarge { .. }
+        | TardisError::RestartUnsupervised(_)
+        | TardisError::ForegroundSupervised(_)
+        | TardisError::ForegroundNotLast(_) => 1,
+    }
+}
 
-    Ok(())
+fn main() {
+    match run() {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("tardis: {e}");
+            std::process::exit(exit_code(&e));
+        }
+    }
 }
@@ -0,0 +1,364 @@
+//! Starting guests in dependency order.
+//!
+//! Guests are started in the order picked by [`start_order`], so that each one
+//! comes after the guests named in its `after` list. A guest is only started
+//! once those guests are ready. Guests are ready as soon as they've been
+//! started, unless they were packed with a [`Readiness`]:
+//!
+//! - With [`Readiness::Notify`], the guest is ready once it writes `READY=1`,
+//!   on a line of its own, to the file descriptor whose number is given in
+//!   `TARDIS_NOTIFY_FD`. Only guests that others depend on are given one, so a
+//!   guest should do nothing if the variable isn't set, in the same way as
+//!   with `sd_notify`. The loader stops reading once the guest is ready, so the
+//!   guest should close the descriptor then. A guest that hasn't said that
+//!   it's ready once its timeout is over is treated as having failed to start.
+//! - With [`Readiness::Delay`], the guest is ready once it's been running for a
+//!   fixed time.
+//!
+//! [`start_order`]: libtardis::metadata::start_order
+
+use libtardis::{error::TardisError, metadata::Readiness, runtime::Guest};
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    libc::c_int,
+    poll::{poll, PollFd, PollFlags},
+    unistd::{pipe2, Pid},
+};
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::fd::{AsRawFd, FromRawFd},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Environment variable that holds the number of a guest's notify descriptor.
+pub const NOTIFY_FD: &str = "TARDIS_NOTIFY_FD";
+
+/// A guest that has been started.
+struct Started {
+    name: Option<String>,
+    ready: Option<Readiness>,
+
+    /// Where the guest says that it's ready, if it was given a descriptor.
+    notify: Option<File>,
+
+    /// When the guest was started.
+    since: Instant,
+
+    /// Whether the guest is known to be ready.
+    is_ready: bool,
+}
+
+/// Starts guests once the guests they depend on are ready.
+pub struct Startup {
+    started: Vec<Started>,
+
+    /// Names of the guests that other guests depend on.
+    depended_on: Vec<String>,
+}
+
+impl Startup {
+    /// Prepare to start `guests`, which must already be in dependency order.
+    /// `after` names guests that will be waited for once they've all been
+    /// started.
+    pub fn new(guests: &[(usize, Guest)], after: &[String]) -> Self {
+        Startup {
+            started: Vec::new(),
+            depended_on: guests
+                .iter()
+                .flat_map(|(_, guest)| &guest.metadata.after)
+                .chain(after)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Start a guest in a child process, once the guests that it depends on
+    /// are ready.
+    pub fn spawn(&mut self, guest: &Guest) -> Result<Pid, TardisError> {
+        self.wait_for(&guest.metadata.after)?;
+
+        let name = guest.metadata.name.clone();
+        let notified = matches!(guest.metadata.ready, Some(Readiness::Notify(_)))
+            && name
+                .as_ref()
+                .is_some_and(|name| self.depended_on.contains(name));
+        let (pid, notify) = match notified {
+            true => {
+                let (reader, writer) =
+                    pipe2(OFlag::O_CLOEXEC).map_err(|errno| TardisError::SyscallError {
+                        syscall: "pipe2",
+                        errno,
+                    })?;
+                let (reader, writer) =
+                    unsafe { (File::from_raw_fd(reader), File::from_raw_fd(writer)) };
+                let pid = crate::spawn(guest, Some(writer.as_raw_fd()))?;
+                (pid, Some(reader))
+            }
+            false => (crate::spawn(guest, None)?, None),
+        };
+
+        self.started.push(Started {
+            name,
+            ready: guest.metadata.ready,
+            notify,
+            since: Instant::now(),
+            is_ready: false,
+        });
+        Ok(pid)
+    }
+
+    /// Wait until each of the named guests is ready. Guests that haven't been
+    /// started are skipped.
+    pub fn wait_for(&mut self, names: &[String]) -> Result<(), TardisError> {
+        for name in names {
+            let Some(started) = self
+                .started
+                .iter_mut()
+                .find(|started| started.name.as_ref() == Some(name))
+            else {
+                continue;
+            };
+            if started.is_ready {
+                continue;
+            }
+
+            match (started.ready, &mut started.notify) {
+                (Some(Readiness::Notify(timeout)), Some(notify)) => {
+                    let deadline = (timeout != 0)
+                        .then(|| started.since + Duration::from_millis(timeout.into()));
+                    if !notified(notify, deadline)? {
+                        return Err(TardisError::NotReady(name.clone()));
+                    }
+                }
+                (Some(Readiness::Delay(delay)), _) => {
+                    let delay = Duration::from_millis(delay.into());
+                    thread::sleep(delay.saturating_sub(started.since.elapsed()));
+                }
+                _ => (),
+            }
+            started.is_ready = true;
+        }
+        Ok(())
+    }
+}
+
+/// Read from a guest's notify descriptor until the guest says that it's ready.
+/// Returns false if the guest closes the descriptor without doing so, or if
+/// it's still not ready once `deadline` has passed.
+fn notified(notify: &mut File, deadline: Option<Instant>) -> Result<bool, TardisError> {
+    let mut line = Vec::new();
+    let mut buf = [0u8; 64];
+    loop {
+        if let Some(deadline) = deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let mut fds = [PollFd::new(&*notify, PollFlags::POLLIN)];
+            // poll() takes whole milliseconds, so round up to not give up early
+            let timeout = left.as_micros().div_ceil(1000);
+            match poll(&mut fds, timeout.try_into().unwrap_or(c_int::MAX)) {
+                Ok(0) => return Ok(false),
+                Ok(_) => (),
+                // The supervisor's signal handlers interrupt system calls
+                Err(Errno::EINTR) => continue,
+                Err(errno) => {
+                    return Err(TardisError::SyscallError {
+                        syscall: "poll",
+                        errno,
+                    })
+                }
+            }
+        }
+
+        let n = match notify.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            return Ok(line == b"READY=1");
+        }
+        for &byte in &buf[..n] {
+            match byte {
+                b'\n' if line == b"READY=1" => return Ok(true),
+                b'\n' => line.clear(),
+                _ => line.push(byte),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{notified, Startup, NOTIFY_FD};
+    use libtardis::{
+        error::TardisError,
+        metadata::{Readiness, ResourceMetadata},
+        runtime::Guest,
+        stream::StreamSummary,
+    };
+    use nix::{
+        fcntl::OFlag,
+        sys::{
+            signal::{kill, Signal},
+            wait::waitpid,
+        },
+        unistd::{pipe2, Pid},
+    };
+    use std::{
+        fs::File,
+        io::Write,
+        os::fd::FromRawFd,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    /// A guest that runs a shell script.
+    fn guest(name: &str, script: &str, ready: Option<Readiness>, after: &[&str]) -> (usize, Guest) {
+        let metadata = ResourceMetadata {
+            args: vec!["-c".into(), script.into()],
+            ready,
+            after: after.iter().map(|name| name.to_string()).collect(),
+            ..ResourceMetadata::named(name)
+        };
+        let guest = Guest {
+            file: File::open("/bin/sh").unwrap(),
+            metadata,
+            summary: StreamSummary {
+                original_size: 0,
+                sha256: [0; 32],
+                sealed_size: 0,
+            },
+        };
+        (0, guest)
+    }
+
+    /// Start the guests in order, and return how long after the first one each
+    /// of them was started. If one can't be started, the others are killed.
+    fn start(guests: &[(usize, Guest)]) -> Result<Vec<Duration>, TardisError> {
+        let mut startup = Startup::new(guests, &[]);
+        let begin = Instant::now();
+        let mut pids: Vec<Pid> = Vec::new();
+        let mut started = Vec::new();
+        let result = guests.iter().try_for_each(|(_, guest)| {
+            pids.push(startup.spawn(guest)?);
+            started.push(begin.elapsed());
+            Ok(())
+        });
+        for pid in pids {
+            if result.is_err() {
+                let _ = kill(pid, Signal::SIGKILL);
+            }
+            waitpid(pid, None).unwrap();
+        }
+        result.map(|()| started)
+    }
+
+    /// Run the notify state machine on what a guest writes, a chunk at a time,
+    /// before it closes its descriptor.
+    fn notify(chunks: &[&'static [u8]], deadline: Option<Duration>) -> bool {
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        let (mut reader, mut writer) =
+            unsafe { (File::from_raw_fd(reader), File::from_raw_fd(writer)) };
+        let chunks = chunks.to_vec();
+        let writer = thread::spawn(move || {
+            for chunk in chunks {
+                writer.write_all(chunk).unwrap();
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        let deadline = deadline.map(|deadline| Instant::now() + deadline);
+        let ready = notified(&mut reader, deadline).unwrap();
+        drop(reader);
+        let _ = writer.join();
+        ready
+    }
+
+    #[test]
+    fn test_notified() {
+        assert!(notify(&[b"READY=1\n"], None));
+        assert!(notify(&[b"REA", b"DY=", b"1\n"], None));
+        assert!(notify(&[b"STATUS=starting\nREADY=1\nmore"], None));
+        // A final line doesn't need a newline
+        assert!(notify(&[b"READY=1"], None));
+
+        assert!(!notify(&[], None));
+        assert!(!notify(&[b"READY=0\n"], None));
+        assert!(!notify(&[b"NOT READY=1\n"], None));
+        assert!(!notify(&[b"READY=1 \n"], None));
+    }
+
+    #[test]
+    fn test_notified_timeout() {
+        let (reader, _writer) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        let mut reader = unsafe { File::from_raw_fd(reader) };
+        let begin = Instant::now();
+        let deadline = begin + Duration::from_millis(100);
+        assert!(!notified(&mut reader, Some(deadline)).unwrap());
+        assert!(begin.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_start_in_order() {
+        // Guests that don't depend on others are started straight away
+        let started = start(&[
+            guest("a", "exit 0", Some(Readiness::Delay(300)), &[]),
+            guest("b", "exit 0", None, &[]),
+        ])
+        .unwrap();
+        assert!(started[1] < Duration::from_millis(300), "{started:?}");
+
+        // and the others once the guests they depend on are ready
+        let started = start(&[
+            guest("a", "sleep 1", Some(Readiness::Delay(300)), &[]),
+            guest("b", "exit 0", None, &["a"]),
+            guest("c", "exit 0", None, &["a", "b"]),
+        ])
+        .unwrap();
+        assert!(started[1] >= Duration::from_millis(300), "{started:?}");
+        assert!(started[2] >= started[1], "{started:?}");
+    }
+
+    #[test]
+    fn test_start_after_notify() {
+        let script = format!("sleep 0.2; echo READY=1 >&${NOTIFY_FD}; sleep 1");
+        let started = start(&[
+            guest("a", &script, Some(Readiness::Notify(5000)), &[]),
+            guest("b", "exit 0", None, &["a"]),
+        ])
+        .unwrap();
+        assert!(started[1] >= Duration::from_millis(200), "{started:?}");
+        assert!(started[1] < Duration::from_secs(1), "{started:?}");
+
+        // Guests that nothing depends on aren't given a descriptor, so there's
+        // nothing to wait for
+        let script = format!("test -z \"${NOTIFY_FD}\" || sleep 5");
+        let started = start(&[
+            guest("a", &script, Some(Readiness::Notify(0)), &[]),
+            guest("b", "exit 0", None, &[]),
+        ])
+        .unwrap();
+        assert!(started[1] < Duration::from_secs(1), "{started:?}");
+    }
+
+    #[test]
+    fn test_not_ready() {
+        // The guest exits without saying that it's ready
+        let result = start(&[
+            guest("a", "exit 0", Some(Readiness::Notify(5000)), &[]),
+            guest("b", "exit 0", None, &["a"]),
+        ]);
+        assert!(matches!(result, Err(TardisError::NotReady(ref name)) if name == "a"));
+
+        // or doesn't say so in time
+        let begin = Instant::now();
+        let result = start(&[
+            guest("a", "sleep 5", Some(Readiness::Notify(200)), &[]),
+            guest("b", "exit 0", None, &["a"]),
+        ]);
+        assert!(matches!(result, Err(TardisError::NotReady(ref name)) if name == "a"));
+        assert!(begin.elapsed() >= Duration::from_millis(200));
+        assert!(begin.elapsed() < Duration::from_secs(1));
+    }
+}
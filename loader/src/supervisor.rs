@@ -0,0 +1,579 @@
+//! Supervision of guests that run in child processes.
+//!
+//! When a packed file records an [`ExitPolicy`], the loader stays around as the
+//! parent of its guests instead of exiting once they've been started. It reaps
+//! each guest as it exits, reports how it exited on stderr, and exits with the
+//! status that the policy picks. Statuses follow the shell's conventions: a
+//! guest that was killed by a signal counts as having exited with 128 plus the
+//! signal's number.
+//!
+//! While it supervises, the loader passes `SIGTERM`, `SIGINT`, `SIGHUP`,
+//! `SIGUSR1` and `SIGUSR2` on to every guest that's still running, instead of
+//! acting on them itself. Once the guests have been asked to exit (by one of
+//! the first three signals, or because the policy stops them), they have the
+//! file's grace period to do so before they're sent `SIGKILL`.
+//!
+//! Guests with a [`RestartPolicy`] are started again when they exit, after a
+//! delay that doubles with each restart in a row. Their status only counts
+//! towards the exit policy once they're no longer restarted, either because
+//! their policy doesn't call for it, they've been restarted as many times as
+//! they can be, or the guests are being stopped.
+
+use libtardis::{
+    error::TardisError,
+    metadata::{RestartPolicy, MAX_RESTART_DELAY},
+    serialization::ExitPolicy,
+};
+use nix::{
+    errno::Errno,
+    libc::{self, c_int},
+    sys::{
+        signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::Pid,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering},
+        OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Signals that are passed on to the guests.
+const FORWARDED: [Signal; 5] = [
+    Signal::SIGTERM,
+    Signal::SIGINT,
+    Signal::SIGHUP,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+];
+
+/// The pid of every guest that hasn't been reaped yet, or 0 in the slots of
+/// those that have. The signal handlers only read this, so it's filled in with
+/// atomic stores rather than behind a lock.
+static PIDS: OnceLock<Box<[AtomicI32]>> = OnceLock::new();
+
+/// Seconds that guests are given to exit once they've been asked to.
+static GRACE_PERIOD: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the guests have been asked to exit.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the grace period ran out and the guests were sent `SIGKILL`.
+static KILLED: AtomicBool = AtomicBool::new(false);
+
+/// A guest that runs in a child process.
+struct Child {
+    /// The index of the guest's resource in the packed file.
+    index: usize,
+
+    /// The guest's name.
+    name: String,
+
+    /// The child process.
+    pid: Pid,
+
+    /// The child's slot in [`Supervisor::pids`].
+    slot: usize,
+
+    /// How the guest is restarted when it exits, if it is.
+    restart: Option<RestartPolicy>,
+
+    /// How many times in a row the guest has been restarted.
+    restarts: u32,
+
+    /// When the child process was started.
+    started: Instant,
+}
+
+/// A guest that exited and is waiting to be restarted.
+struct PendingRestart {
+    child: Child,
+
+    /// The status that the guest exited with.
+    code: i32,
+
+    /// When the guest is due to be restarted.
+    due: Instant,
+}
+
+/// The status that the loader exits with, as it's picked by the exit policy.
+struct Outcome<'a> {
+    policy: &'a ExitPolicy,
+    primary: Option<usize>,
+    status: Option<i32>,
+    first_failure: Option<i32>,
+}
+
+impl Outcome<'_> {
+    /// Record that the guest of resource `index` exited for good with `code`,
+    /// and return whether the other guests should be stopped.
+    fn record(&mut self, index: usize, code: i32) -> bool {
+        // Once the policy has picked a status, the remaining guests are only
+        // waited for so that they don't outlive the loader
+        if self.status.is_some() {
+            return false;
+        }
+        match self.policy {
+            ExitPolicy::FirstFailure if code != 0 => {
+                self.status = Some(code);
+                true
+            }
+            ExitPolicy::AllSucceed if code != 0 => {
+                self.first_failure.get_or_insert(code);
+                false
+            }
+            ExitPolicy::Primary(_) if Some(index) == self.primary => {
+                self.status = Some(code);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn status(&self) -> i32 {
+        self.status.or(self.first_failure).unwrap_or(0)
+    }
+}
+
+/// Supervisor for the guests of a packed file.
+pub struct Supervisor {
+    children: Vec<Child>,
+    grace_period: u32,
+
+    /// The pid of each child, by slot. This is the table in [`PIDS`], which
+    /// the signal handlers read as well.
+    pids: &'static [AtomicI32],
+}
+
+impl Supervisor {
+    /// Start forwarding signals to the guests, of which there will be at most
+    /// `capacity`. Only one supervisor can be created per process.
+    pub fn new(capacity: usize, grace_period: u32) -> Result<Self, TardisError> {
+        // The signal handlers are shared by the whole process, so a second
+        // supervisor would take the guests of the first one away from it
+        let mut created = false;
+        let pids = PIDS.get_or_init(|| {
+            created = true;
+            (0..capacity).map(|_| AtomicI32::new(0)).collect()
+        });
+        if !created {
+            return Err(TardisError::SyscallError {
+                syscall: "sigaction",
+                errno: Errno::EBUSY,
+            });
+        }
+        GRACE_PERIOD.store(grace_period, Ordering::SeqCst);
+
+        // The handlers are installed without SA_RESTART, so that waitpid() is
+        // interrupted and the loop in wait() gets to notice that the grace
+        // period ran out
+        let action = SigAction::new(
+            SigHandler::Handler(forward),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        for signal in FORWARDED.into_iter().chain([Signal::SIGALRM]) {
+            unsafe { sigaction(signal, &action) }.map_err(|errno| TardisError::SyscallError {
+                syscall: "sigaction",
+                errno,
+            })?;
+        }
+
+        Ok(Supervisor {
+            children: Vec::with_capacity(capacity),
+            grace_period,
+            pids,
+        })
+    }
+
+    /// Supervise a guest that was started in process `pid`, and that's
+    /// restarted according to `restart` when it exits.
+    pub fn add(&mut self, index: usize, name: String, pid: Pid, restart: Option<RestartPolicy>) {
+        let slot = self.children.len();
+        self.pids[slot].store(pid.as_raw(), Ordering::SeqCst);
+        self.children.push(Child {
+            index,
+            name,
+            pid,
+            slot,
+            restart,
+            restarts: 0,
+            started: Instant::now(),
+        });
+    }
+
+    /// Ask every guest to exit, and kill those that are still running once the
+    /// grace period is over.
+    fn stop(&self) {
+        signal_children(libc::SIGTERM);
+        escalate();
+    }
+
+    /// Stop every guest and wait for them to exit, for when the loader has to
+    /// give up on starting the rest. Guests that are still running once the
+    /// grace period is over are killed, so that none of them outlives the
+    /// loader.
+    pub fn shut_down(self) {
+        self.stop();
+        // Nothing is restarted once the guests have been asked to exit, and the
+        // status that they exit with doesn't matter
+        self.wait(&ExitPolicy::AllSucceed, None, |_| {
+            unreachable!("guests aren't restarted while they're being stopped")
+        });
+    }
+
+    /// Wait for every guest to exit, and return the status that the loader
+    /// should exit with. `primary` is the index of the primary guest's
+    /// resource, if the policy has one. Guests are restarted by calling
+    /// `respawn` with the index of their resource.
+    pub fn wait(
+        mut self,
+        policy: &ExitPolicy,
+        primary: Option<usize>,
+        mut respawn: impl FnMut(usize) -> Result<Pid, TardisError>,
+    ) -> i32 {
+        let mut outcome = Outcome {
+            policy,
+            primary,
+            status: None,
+            first_failure: None,
+        };
+        let mut pending: Vec<PendingRestart> = Vec::new();
+
+        while !self.children.is_empty() || !pending.is_empty() {
+            // Guests aren't restarted once they've been asked to exit
+            if STOPPING.load(Ordering::SeqCst) {
+                for restart in pending.drain(..) {
+                    outcome.record(restart.child.index, restart.code);
+                }
+            }
+            let now = Instant::now();
+            while let Some(position) = pending.iter().position(|restart| restart.due <= now) {
+                let PendingRestart {
+                    mut child, code, ..
+                } = pending.remove(position);
+                match respawn(child.index) {
+                    Ok(pid) => {
+                        child.pid = pid;
+                        child.restarts += 1;
+                        child.started = Instant::now();
+                        self.pids[child.slot].store(pid.as_raw(), Ordering::SeqCst);
+                        eprintln!(
+                            "tardis: restarted guest {} ({}) as pid {pid}",
+                            child.index, child.name
+                        );
+                        self.children.push(child);
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "tardis: couldn't restart guest {} ({}): {e}",
+                            child.index, child.name
+                        );
+                        if outcome.record(child.index, code) {
+                            self.stop();
+                        }
+                    }
+                }
+            }
+
+            if self.children.is_empty() && pending.is_empty() {
+                break;
+            }
+
+            // While restarts are pending, the loader polls for guests that exit
+            // so that it can start them on time
+            let next_restart = pending.iter().map(|restart| restart.due).min();
+            let flags = next_restart.map(|_| WaitPidFlag::WNOHANG);
+            let result = waitpid(None, flags);
+            if KILLED.swap(false, Ordering::SeqCst) {
+                eprintln!(
+                    "tardis: guests were still running {}s after being asked to exit; sent \
+                     SIGKILL",
+                    self.grace_period
+                );
+            }
+            let (pid, code, how) = match result {
+                Ok(WaitStatus::Exited(pid, code)) => {
+                    (pid, code, format!("exited with status {code}"))
+                }
+                Ok(WaitStatus::Signaled(pid, signal, _)) => {
+                    (pid, 128 + signal as i32, format!("was killed by {signal}"))
+                }
+                Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) if next_restart.is_some() => {
+                    let wait = next_restart.map_or(Duration::ZERO, |due| {
+                        due.saturating_duration_since(Instant::now())
+                    });
+                    thread::sleep(wait.min(Duration::from_millis(50)));
+                    continue;
+                }
+                Ok(_) | Err(Errno::EINTR) => continue,
+                Err(e) => {
+                    eprintln!("tardis: waitpid failed: {e}");
+                    break;
+                }
+            };
+            let Some(position) = self.children.iter().position(|child| child.pid == pid) else {
+                continue;
+            };
+            let mut child = self.children.remove(position);
+            self.pids[child.slot].store(0, Ordering::SeqCst);
+            eprintln!(
+                "tardis: guest {} ({}, pid {pid}) {how}",
+                child.index, child.name
+            );
+
+            if let Some(restart) = child.restart {
+                // A guest that ran for a while started properly, so its
+                // restarts don't count as being in a row
+                if child.started.elapsed() >= Duration::from_millis(MAX_RESTART_DELAY.into()) {
+                    child.restarts = 0;
+                }
+                if !STOPPING.load(Ordering::SeqCst) && restart.should_restart(code, child.restarts)
+                {
+                    let delay = restart.delay(child.restarts);
+                    eprintln!(
+                        "tardis: restarting guest {} ({}) in {delay}ms",
+                        child.index, child.name
+                    );
+                    pending.push(PendingRestart {
+                        child,
+                        code,
+                        due: Instant::now() + Duration::from_millis(delay.into()),
+                    });
+                    continue;
+                }
+            }
+            if outcome.record(child.index, code) {
+                self.stop();
+            }
+        }
+
+        outcome.status()
+    }
+}
+
+/// Signal handler that passes forwarded signals on to the guests, and kills
+/// them when the grace period runs out. Only async-signal-safe functions are
+/// called from here.
+extern "C" fn forward(signal: c_int) {
+    match signal {
+        libc::SIGALRM => {
+            KILLED.store(true, Ordering::SeqCst);
+            signal_children(libc::SIGKILL);
+        }
+        libc::SIGTERM | libc::SIGINT | libc::SIGHUP => {
+            signal_children(signal);
+            escalate();
+        }
+        _ => signal_children(signal),
+    }
+}
+
+/// Send `signal` to every guest that hasn't been reaped yet.
+fn signal_children(signal: c_int) {
+    for pid in PIDS.get().into_iter().flat_map(|pids| pids.iter()) {
+        let pid = pid.load(Ordering::SeqCst);
+        if pid > 0 {
+            unsafe { libc::kill(pid, signal) };
+        }
+    }
+}
+
+/// Start the grace period, unless it's already been started. Guests get no
+/// grace period at all if it's 0.
+fn escalate() {
+    if STOPPING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    match GRACE_PERIOD.load(Ordering::SeqCst) {
+        0 => {
+            KILLED.store(true, Ordering::SeqCst);
+            signal_children(libc::SIGKILL);
+        }
+        seconds => unsafe {
+            libc::alarm(seconds);
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Outcome, Supervisor, FORWARDED};
+    use libtardis::serialization::ExitPolicy;
+    use nix::{
+        errno::Errno,
+        libc,
+        sys::{
+            signal::{signal, SigHandler, Signal},
+            wait::wait,
+        },
+        unistd::{fork, getpid, ForkResult},
+    };
+    use std::{
+        env,
+        process::Command,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    /// Environment variable that makes [`supervise_in_child`] run a case.
+    const CASE: &str = "TARDIS_SUPERVISOR_CASE";
+
+    /// Feed the exit codes of guests `0..codes.len()`, in order, to a new
+    /// outcome. Returns its status, and after which guest it stopped the rest.
+    fn record(policy: &ExitPolicy, primary: Option<usize>, codes: &[i32]) -> (i32, Option<usize>) {
+        let mut outcome = Outcome {
+            policy,
+            primary,
+            status: None,
+            first_failure: None,
+        };
+        let mut stopped = None;
+        for (index, code) in codes.iter().enumerate() {
+            if outcome.record(index, *code) {
+                assert!(stopped.is_none(), "the guests were stopped twice");
+                stopped = Some(index);
+            }
+        }
+        (outcome.status(), stopped)
+    }
+
+    #[test]
+    fn test_first_failure() {
+        let policy = ExitPolicy::FirstFailure;
+        assert_eq!(record(&policy, None, &[0, 0, 0]), (0, None));
+        assert_eq!(record(&policy, None, &[0, 3, 4]), (3, Some(1)));
+        assert_eq!(record(&policy, None, &[137, 3]), (137, Some(0)));
+        // The guests that are stopped don't change the status
+        assert_eq!(record(&policy, None, &[0, 2, 143, 143]), (2, Some(1)));
+    }
+
+    #[test]
+    fn test_all_succeed() {
+        let policy = ExitPolicy::AllSucceed;
+        assert_eq!(record(&policy, None, &[0, 0, 0]), (0, None));
+        assert_eq!(record(&policy, None, &[0, 4, 5]), (4, None));
+        assert_eq!(record(&policy, None, &[0, 141, 1]), (141, None));
+    }
+
+    #[test]
+    fn test_primary() {
+        let policy = ExitPolicy::Primary("b".into());
+        assert_eq!(record(&policy, Some(1), &[5, 0, 6]), (0, Some(1)));
+        assert_eq!(record(&policy, Some(1), &[0, 7, 6]), (7, Some(1)));
+        assert_eq!(record(&policy, Some(1), &[0, 137, 143]), (137, Some(1)));
+        assert_eq!(record(&policy, Some(2), &[3, 143, 0]), (0, Some(2)));
+    }
+
+    /// Run a case of [`supervise_in_child`] in a new process, so that it can
+    /// have a supervisor of its own and the only children it waits for are its
+    /// guests. Returns the status that it exited with, and how long it took.
+    fn supervise(case: &str) -> (i32, Duration) {
+        let start = Instant::now();
+        let output = Command::new(env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "supervisor::test::supervise_in_child",
+                "--nocapture",
+            ])
+            .env(CASE, case)
+            .output()
+            .unwrap();
+        let status = output.status.code().expect("the test process was killed");
+        (status, start.elapsed())
+    }
+
+    #[test]
+    fn test_wait() {
+        // Each guest is given as its exit code, where 128 + n means that it's
+        // killed by signal n, and how many milliseconds it runs for
+        for (case, expected) in [
+            ("first-failure 0:0 0:50", 0),
+            ("first-failure 0:0 3:50 0:5000", 3),
+            ("first-failure 0:0 137:50 0:5000", 137),
+            ("all-succeed 0:0 0:50", 0),
+            ("all-succeed 0:0 4:50 5:100", 4),
+            ("all-succeed 141:0 0:50", 141),
+            ("primary=1 5:0 0:50", 0),
+            ("primary=1 5:0 7:50 0:5000", 7),
+            ("primary=1 0:0 137:50 0:5000", 137),
+        ] {
+            let (status, elapsed) = supervise(case);
+            assert_eq!(status, expected, "{case}");
+            // Guests that are still running are stopped rather than waited for
+            assert!(elapsed < Duration::from_secs(4), "{case}: took {elapsed:?}");
+        }
+    }
+
+    #[test]
+    fn test_shut_down() {
+        // Guests that ignore SIGTERM are killed once the grace period is over,
+        // and none of them is left running
+        let (status, elapsed) = supervise("shut-down 0:5000 ignore:5000");
+        assert_eq!(status, 0);
+        assert!(elapsed >= Duration::from_secs(1), "took {elapsed:?}");
+        assert!(elapsed < Duration::from_secs(4), "took {elapsed:?}");
+    }
+
+    /// Start the guests of the case given in [`CASE`], supervise them, and exit
+    /// with the status that the supervisor picks. Does nothing unless it's run
+    /// by [`supervise`].
+    #[test]
+    fn supervise_in_child() {
+        let Ok(case) = env::var(CASE) else {
+            return;
+        };
+        let mut words = case.split(' ');
+        let policy = words.next().unwrap();
+        let guests: Vec<_> = words.collect();
+
+        let mut supervisor = Supervisor::new(guests.len(), 1).unwrap();
+        for (index, guest) in guests.iter().enumerate() {
+            let (code, millis) = guest.split_once(':').unwrap();
+            let millis = millis.parse().unwrap();
+            match unsafe { fork() }.unwrap() {
+                ForkResult::Child => {
+                    // Guests are exec'd, which resets the loader's handlers
+                    for forwarded in FORWARDED.into_iter().chain([Signal::SIGALRM]) {
+                        unsafe { signal(forwarded, SigHandler::SigDfl) }.unwrap();
+                    }
+                    if code == "ignore" {
+                        unsafe { signal(Signal::SIGTERM, SigHandler::SigIgn) }.unwrap();
+                    }
+                    thread::sleep(Duration::from_millis(millis));
+                    let code: i32 = code.parse().unwrap_or(0);
+                    if code > 128 {
+                        unsafe { libc::kill(getpid().as_raw(), code - 128) };
+                    }
+                    unsafe { libc::_exit(code) };
+                }
+                ForkResult::Parent { child } => {
+                    supervisor.add(index, format!("guest{index}"), child, None);
+                }
+            }
+        }
+
+        let status = match policy {
+            "shut-down" => {
+                supervisor.shut_down();
+                // Every guest has been reaped
+                match wait() {
+                    Err(Errno::ECHILD) => 0,
+                    _ => 1,
+                }
+            }
+            "first-failure" => supervisor.wait(&ExitPolicy::FirstFailure, None, |_| unreachable!()),
+            "all-succeed" => supervisor.wait(&ExitPolicy::AllSucceed, None, |_| unreachable!()),
+            primary => {
+                let index = primary.strip_prefix("primary=").unwrap().parse().unwrap();
+                let policy = ExitPolicy::Primary(format!("guest{index}"));
+                supervisor.wait(&policy, Some(index), |_| unreachable!())
+            }
+        };
+        std::process::exit(status);
+    }
+}
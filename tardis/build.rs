@@ -1,18 +1,50 @@
+use std::fmt::Write;
 use std::process::Command;
 
+/// Variants of the loader to build. Each variant only links the codecs that are
+/// listed next to it (plus "store", which is always available), so that packed
+/// binaries don't carry decompressors they never use.
+const LOADER_VARIANTS: &[&[&str]] = &[
+    &[],
+    &["lz4"],
+    &["deflate"],
+    &["zstd"],
+    &["xz"],
+    &["lz4", "deflate", "zstd", "xz"],
+];
+
 fn main() {
-    cargo_build("../loader");
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let mut loaders = String::from("const LOADERS: &[(&[&str], &[u8])] = &[\n");
+
+    for features in LOADER_VARIANTS {
+        let name = match features.len() {
+            0 => "store".to_string(),
+            _ => features.join("+"),
+        };
+        let path = cargo_build("../loader", features, &format!("{out_dir}/loaders/{name}"));
+        writeln!(loaders, "    (&{features:?}, include_bytes!({path:?})),").unwrap();
+    }
+
+    loaders.push_str("];\n");
+    std::fs::write(format!("{out_dir}/loaders.rs"), loaders).unwrap();
 }
 
-fn cargo_build(loader_path: &str) {
-    let target_dir = format!("{}/embeds", std::env::var("OUT_DIR").unwrap());
+/// Build the loader with the given features, and copy the resulting binary to
+/// `dest`. Returns the path to the copied binary.
+fn cargo_build(loader_path: &str, features: &[&str], dest: &str) -> String {
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let target_dir = format!("{out_dir}/embeds");
 
     let mut cmd = Command::new("cargo");
     cmd.arg("build")
         .arg("--target-dir")
-        .arg(target_dir)
+        .arg(&target_dir)
         .arg("--profile")
-        .arg("release");
+        .arg("release")
+        .arg("--no-default-features")
+        .arg("--features")
+        .arg(features.join(","));
 
     let output = cmd
         .current_dir(loader_path)
@@ -29,4 +61,13 @@ fn cargo_build(loader_path: &str) {
             String::from_utf8_lossy(&output.stderr),
         );
     }
+
+    std::fs::create_dir_all(format!("{out_dir}/loaders")).unwrap();
+    std::fs::copy(
+        format!("{target_dir}/x86_64-unknown-linux-musl/release/loader"),
+        dest,
+    )
+    .unwrap();
+
+    dest.to_string()
 }
@@ -0,0 +1,611 @@
+//! Configuration files that describe a bundle of guests.
+//!
+//! Instead of listing inputs on the command line, `tardis pack --config
+//! tardis.toml` reads them from a file like the following one:
+//!
+//! ```toml
+//! output = "bundle"
+//! codec = "zstd"
+//!
+//! [[guest]]
+//! name = "server"
+//! path = "target/release/server"
+//! args = ["--port", "8080"]
+//! env = { RUST_LOG = "info" }
+//!
+//! [[guest]]
+//! path = "assets.tar"
+//! codec = "xz"
+//! run = false
+//! ```
+//!
+//! The packed file handles built-in flags such as `--tardis-info` itself.
+//! `flag_prefix = "--bundle-"` changes their prefix, and `builtin_flags =
+//! false` turns them off.
+//!
+//! `exit_policy = "first-failure"` (or `"all-succeed"`) keeps the loader
+//! running as the guests' parent, and makes it exit with a status that reflects
+//! theirs. `primary = "server"` makes it exit with the status of one guest.
+//! `grace_period = 30` gives supervised guests 30 seconds to exit when they're
+//! stopped, instead of 10, before they're killed.
+//!
+//! Guests can also be restarted when they exit, with `restart = "on-failure"`
+//! or `restart = "always"`. `max_restarts` limits how many times in a row a
+//! guest is restarted, and `restart_delay` sets the delay before the first
+//! restart, in milliseconds; it doubles with each restart after that.
+//!
+//! `after = ["helper"]` starts a guest once the guest named `helper` is ready.
+//! Guests are ready as soon as they've been started, unless they set `ready =
+//! "notify"` (they say when they're ready, through the descriptor given in
+//! `TARDIS_NOTIFY_FD`) or `ready_delay = 500` (they're ready after running for
+//! 500 milliseconds). Guests that notify have 90 seconds from being started to
+//! do so, or `ready_timeout` milliseconds (0 for no limit).
+//!
+//! `foreground = true` on one guest makes the loader run it in its own process
+//! once the others have been started, instead of supervising them.
+//!
+//! Relative paths are resolved against the directory that holds the
+//! configuration file.
+
+use crate::{CodecArg, ExitPolicyArg};
+use libtardis::{
+    metadata::{
+        Readiness, ResourceKind, ResourceMetadata, Restart, RestartPolicy, DEFAULT_READY_TIMEOUT,
+        DEFAULT_RESTART_DELAY,
+    },
+    serialization::{ExitPolicy, MAX_ATTRIBUTE_SIZE},
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A bundle of guests, as described by a configuration file.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Where to write the packed file.
+    pub output: Option<PathBuf>,
+
+    /// Codec used to compress guests that don't pick their own.
+    pub codec: Option<CodecArg>,
+
+    /// Compression level used with `codec`.
+    pub level: Option<u32>,
+
+    /// Fail if the packed file would be larger than this many bytes.
+    pub max_size: Option<u64>,
+
+    /// Sign the packed file with this key.
+    pub sign_key: Option<PathBuf>,
+
+    /// Prefix of the built-in flags that the packed file handles itself.
+    pub flag_prefix: Option<String>,
+
+    /// Whether the packed file handles built-in flags at all.
+    pub builtin_flags: Option<bool>,
+
+    /// How the loader supervises the guests.
+    pub exit_policy: Option<ExitPolicyArg>,
+
+    /// The name of the guest whose status the loader exits with.
+    pub primary: Option<String>,
+
+    /// Seconds that supervised guests are given to exit before they're killed.
+    pub grace_period: Option<u32>,
+
+    /// The guests to pack, in order.
+    #[serde(rename = "guest", default)]
+    pub guests: Vec<GuestConfig>,
+}
+
+/// A single guest in a configuration file.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GuestConfig {
+    /// The name recorded for the guest. Defaults to the name of the file.
+    pub name: Option<String>,
+
+    /// The file to pack.
+    pub path: PathBuf,
+
+    /// Arguments passed to the guest before the ones given to the packed file.
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    /// Environment variables set for the guest.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// Codec used to compress the guest.
+    pub codec: Option<CodecArg>,
+
+    /// Compression level used with the guest's codec.
+    pub level: Option<u32>,
+
+    /// Whether the loader runs the guest. Guests that aren't run are only
+    /// stored in the packed file.
+    #[serde(default = "default_run")]
+    pub run: bool,
+
+    /// When a supervising loader restarts the guest.
+    pub restart: Option<RestartArg>,
+
+    /// How many times in a row the guest can be restarted.
+    pub max_restarts: Option<u32>,
+
+    /// Delay before the guest is first restarted, in milliseconds.
+    pub restart_delay: Option<u32>,
+
+    /// Whether the loader runs the guest in its own process.
+    #[serde(default)]
+    pub foreground: bool,
+
+    /// Names of the guests that have to be ready before this one is started.
+    #[serde(default)]
+    pub after: Vec<String>,
+
+    /// How the guest tells the loader that it's ready.
+    pub ready: Option<ReadyArg>,
+
+    /// How long the guest runs before it's considered ready, in milliseconds.
+    pub ready_delay: Option<u32>,
+
+    /// How long the guest has to say that it's ready once it's been started,
+    /// in milliseconds, or 0 for no limit.
+    pub ready_timeout: Option<u32>,
+}
+
+/// How a guest tells the loader that it's ready, as it's set in a
+/// configuration file.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReadyArg {
+    Started,
+    Notify,
+}
+
+/// When a guest is restarted, as it's set in a configuration file.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartArg {
+    No,
+    OnFailure,
+    Always,
+}
+
+fn default_run() -> bool {
+    true
+}
+
+impl Config {
+    /// Read and validate a configuration file.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let mut config: Config =
+            toml::from_str(&text).map_err(|e| format!("{}: {e}", path.display()))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        config.output = config.output.map(|output| base.join(output));
+        config.sign_key = config.sign_key.map(|key| base.join(key));
+        for guest in &mut config.guests {
+            guest.path = base.join(&guest.path);
+        }
+
+        config
+            .validate()
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Check that the bundle can be packed, so that mistakes are reported
+    /// before anything is compressed.
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.guests.is_empty() {
+            return Err("no guests are listed; add a [[guest]] table".into());
+        }
+        if u32::try_from(self.guests.len()).is_err() {
+            return Err("too many guests".into());
+        }
+        if !self.guests.iter().any(|guest| guest.run) {
+            return Err("none of the guests is run".into());
+        }
+        if let Some(prefix) = &self.flag_prefix {
+            check_flag_prefix(prefix).map_err(|e| format!("flag_prefix: {e}"))?;
+        }
+        if let Some(codec) = self.codec {
+            check_codec(codec, self.level)?;
+        }
+
+        let mut names = Vec::new();
+        for (index, guest) in self.guests.iter().enumerate() {
+            let name = guest.name();
+            let (codec, level) = self.codec_for(guest);
+            guest
+                .validate()
+                .and_then(|_| check_codec(codec, level))
+                .map_err(|e| format!("guest {index} ({name}): {e}"))?;
+            if names.contains(&name) {
+                return Err(format!("guest {index}: there is already a guest named {name}").into());
+            }
+            names.push(name);
+        }
+
+        if let Some(ExitPolicy::Primary(primary)) = self.exit_policy()? {
+            if !self
+                .guests
+                .iter()
+                .any(|guest| guest.run && guest.name() == primary)
+            {
+                return Err(format!("primary: no guest that is run is named {primary}").into());
+            }
+        }
+        if self.guests.iter().filter(|guest| guest.foreground).count() > 1 {
+            return Err("only one guest can be in the foreground".into());
+        }
+        if self.grace_period.is_some() && self.exit_policy()?.is_none() {
+            return Err("grace_period: only applies when exit_policy or primary is set".into());
+        }
+
+        Ok(())
+    }
+
+    /// Return the prefix of the packed file's built-in flags, where an empty
+    /// prefix turns them off, or `None` to use the default one.
+    pub fn flag_prefix(&self) -> Option<String> {
+        match self.builtin_flags {
+            Some(false) => Some(String::new()),
+            _ => self.flag_prefix.clone(),
+        }
+    }
+
+    /// Return the policy that the loader supervises the guests with, if any.
+    pub fn exit_policy(&self) -> Result<Option<ExitPolicy>, Box<dyn Error>> {
+        ExitPolicyArg::policy(self.exit_policy, self.primary.as_deref())
+    }
+
+    /// Return the codec and compression level that a guest is compressed
+    /// with. Guests that don't pick a codec use the default one, along with
+    /// the default level unless they pick their own.
+    pub fn codec_for(&self, guest: &GuestConfig) -> (CodecArg, Option<u32>) {
+        match guest.codec {
+            Some(codec) => (codec, guest.level),
+            None => (
+                self.codec.unwrap_or(CodecArg::Lz4),
+                guest.level.or(self.level),
+            ),
+        }
+    }
+}
+
+impl GuestConfig {
+    /// Return the name recorded for the guest.
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            self.path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| self.path.display().to_string())
+        })
+    }
+
+    /// Fill in the parts of a guest's metadata that come from its
+    /// configuration.
+    pub fn apply(&self, metadata: &mut ResourceMetadata) {
+        metadata.name = Some(self.name());
+        metadata.args = self.args.clone();
+        metadata.env = self.env.clone().into_iter().collect();
+        if !self.run {
+            metadata.kind = Some(ResourceKind::Data);
+        }
+        metadata.restart = self.restart.map(|restart| RestartPolicy {
+            restart: match restart {
+                RestartArg::No => Restart::No,
+                RestartArg::OnFailure => Restart::OnFailure,
+                RestartArg::Always => Restart::Always,
+            },
+            max_restarts: self.max_restarts,
+            delay: self.restart_delay.unwrap_or(DEFAULT_RESTART_DELAY),
+        });
+        metadata.foreground = self.foreground;
+        metadata.after = self.after.clone();
+        metadata.ready = match (self.ready, self.ready_delay) {
+            (Some(ReadyArg::Notify), _) => Some(Readiness::Notify(
+                self.ready_timeout.unwrap_or(DEFAULT_READY_TIMEOUT),
+            )),
+            (_, Some(delay)) => Some(Readiness::Delay(delay)),
+            _ => None,
+        };
+    }
+
+    /// Return whether a supervising loader restarts the guest.
+    pub fn restarts(&self) -> bool {
+        !matches!(self.restart, None | Some(RestartArg::No))
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if !self.path.is_file() {
+            return Err(format!("{} isn't a file", self.path.display()).into());
+        }
+
+        let name = self.name();
+        if name.is_empty() || name.contains(['/', '\0']) {
+            return Err("names must be non-empty and can't contain '/' or NUL".into());
+        }
+        if self.args.iter().any(|arg| arg.contains('\0')) {
+            return Err("arguments can't contain NUL".into());
+        }
+        for (var, value) in &self.env {
+            if var.is_empty() || var.contains(['=', '\0']) {
+                return Err(format!(
+                    "bad environment variable name {var:?}; names must be non-empty and can't \
+                     contain '=' or NUL"
+                )
+                .into());
+            }
+            if value.contains('\0') {
+                return Err(format!("the value of {var} can't contain NUL").into());
+            }
+        }
+
+        if self.restarts() && !self.run {
+            return Err("guests that aren't run can't be restarted".into());
+        }
+        if self.foreground && !self.run {
+            return Err("guests that aren't run can't be in the foreground".into());
+        }
+        if self
+            .after
+            .iter()
+            .any(|name| name.is_empty() || name.contains('\0'))
+        {
+            return Err("after: names must be non-empty and can't contain NUL".into());
+        }
+        if self.ready == Some(ReadyArg::Notify) && self.ready_delay.is_some() {
+            return Err("ready_delay can't be used with ready = \"notify\"".into());
+        }
+        if self.ready != Some(ReadyArg::Notify) && self.ready_timeout.is_some() {
+            return Err("ready_timeout only applies to guests with ready = \"notify\"".into());
+        }
+        if !self.run
+            && (!self.after.is_empty() || self.ready.is_some() || self.ready_delay.is_some())
+        {
+            return Err("after, ready and ready_delay only apply to guests that are run".into());
+        }
+        if !self.restarts() && (self.max_restarts.is_some() || self.restart_delay.is_some()) {
+            return Err(
+                "max_restarts and restart_delay only apply to guests that are restarted".into(),
+            );
+        }
+        if self.max_restarts == Some(0) {
+            return Err("max_restarts must be at least 1; use restart = \"no\" instead".into());
+        }
+
+        // Each piece of metadata is stored as a single resource attribute
+        let mut metadata = ResourceMetadata::default();
+        self.apply(&mut metadata);
+        if metadata
+            .to_attributes()?
+            .iter()
+            .any(|attr| attr.value.len() > MAX_ATTRIBUTE_SIZE as usize)
+        {
+            return Err(format!(
+                "the name, arguments and environment must each take up at most \
+                 {MAX_ATTRIBUTE_SIZE} bytes"
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Check that a prefix can be used for built-in flags. Prefixes must look like
+/// the start of an option, so that they can't swallow a guest's other
+/// arguments, and they have to fit in the manifest. The empty prefix, which
+/// turns the flags off, is also accepted.
+pub fn check_flag_prefix(prefix: &str) -> Result<(), Box<dyn Error>> {
+    if prefix.is_empty() {
+        return Ok(());
+    }
+    if prefix.len() < 2 || !prefix.starts_with('-') || prefix.contains(['\0', '=', ' ']) {
+        return Err(format!(
+            "bad flag prefix {prefix:?}; prefixes must start with '-', be at least two \
+             characters long, and can't contain spaces, '=' or NUL"
+        )
+        .into());
+    }
+    if prefix.len() > MAX_ATTRIBUTE_SIZE as usize {
+        return Err("the flag prefix is too long".into());
+    }
+    Ok(())
+}
+
+/// Check that a codec is available and supports a compression level.
+fn check_codec(codec: CodecArg, level: Option<u32>) -> Result<(), Box<dyn Error>> {
+    match codec {
+        CodecArg::Auto if level.is_some() => {
+            Err("level: can't be used with codec = \"auto\"".into())
+        }
+        CodecArg::Auto => Ok(()),
+        arg => arg.codec(level).map(drop),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Config;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// Create an empty scratch directory holding two guests, `a.sh` and `b.sh`.
+    fn scratch_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tardis-config-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.sh"), "#!/bin/sh\n").unwrap();
+        fs::write(dir.join("b.sh"), "#!/bin/sh\n").unwrap();
+        dir
+    }
+
+    /// Load a configuration file with the given contents from `dir`.
+    fn load(dir: &Path, text: &str) -> Result<Config, String> {
+        let path = dir.join("tardis.toml");
+        fs::write(&path, text).unwrap();
+        Config::load(&path).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let dir = scratch_dir("duplicate");
+        let result = load(
+            &dir,
+            r#"
+            [[guest]]
+            path = "a.sh"
+            [[guest]]
+            path = "b.sh"
+            name = "a.sh"
+            "#,
+        );
+        assert!(result.unwrap_err().contains("already a guest named a.sh"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_primary() {
+        let dir = scratch_dir("primary");
+        let result = load(
+            &dir,
+            r#"
+            primary = "c.sh"
+            [[guest]]
+            path = "a.sh"
+            "#,
+        );
+        assert!(result
+            .unwrap_err()
+            .contains("no guest that is run is named c.sh"));
+
+        // The primary guest has to be run
+        let result = load(
+            &dir,
+            r#"
+            primary = "b.sh"
+            [[guest]]
+            path = "a.sh"
+            [[guest]]
+            path = "b.sh"
+            run = false
+            "#,
+        );
+        assert!(result
+            .unwrap_err()
+            .contains("no guest that is run is named b.sh"));
+
+        let result = load(
+            &dir,
+            r#"
+            primary = "a.sh"
+            [[guest]]
+            path = "a.sh"
+            "#,
+        );
+        assert!(result.is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_ready_and_restart() {
+        let dir = scratch_dir("ready");
+        for (guest, error) in [
+            (
+                r#"ready = "notify"
+                ready_delay = 100"#,
+                "ready_delay can't be used",
+            ),
+            ("ready_timeout = 100", "ready_timeout only applies"),
+            (
+                r#"run = false
+                ready = "notify""#,
+                "only apply to guests that are run",
+            ),
+            (
+                r#"run = false
+                restart = "always""#,
+                "can't be restarted",
+            ),
+            (
+                "max_restarts = 3",
+                "only apply to guests that are restarted",
+            ),
+            (
+                r#"restart = "no"
+                restart_delay = 100"#,
+                "only apply to guests that are restarted",
+            ),
+            (
+                r#"restart = "always"
+                max_restarts = 0"#,
+                "max_restarts must be at least 1",
+            ),
+        ] {
+            let text = format!("[[guest]]\npath = \"b.sh\"\n[[guest]]\npath = \"a.sh\"\n{guest}\n");
+            let result = load(&dir, &text);
+            let message = result.unwrap_err();
+            assert!(message.contains("guest 1 (a.sh)"), "{message}");
+            assert!(message.contains(error), "{message}");
+        }
+
+        let result = load(
+            &dir,
+            r#"
+            [[guest]]
+            path = "a.sh"
+            ready = "notify"
+            ready_timeout = 100
+            restart = "on-failure"
+            max_restarts = 3
+            restart_delay = 100
+            "#,
+        );
+        assert!(result.is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_relative_paths() {
+        let dir = scratch_dir("relative");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/c.sh"), "#!/bin/sh\n").unwrap();
+
+        // Paths are relative to the configuration file, not to the current
+        // directory
+        let path = dir.join("sub/tardis.toml");
+        fs::write(
+            &path,
+            r#"
+            output = "bundle"
+            sign_key = "../key.pk8"
+            [[guest]]
+            path = "c.sh"
+            [[guest]]
+            path = "../a.sh"
+            "#,
+        )
+        .unwrap();
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.output, Some(dir.join("sub/bundle")));
+        assert_eq!(config.sign_key, Some(dir.join("sub/../key.pk8")));
+        assert_eq!(config.guests[0].path, dir.join("sub/c.sh"));
+        assert_eq!(config.guests[1].path, dir.join("sub/../a.sh"));
+        assert_eq!(config.guests[1].name(), "a.sh");
+
+        // Guests are looked up next to the configuration file, where a.sh isn't
+        fs::write(&path, "[[guest]]\npath = \"a.sh\"\n").unwrap();
+        let message = Config::load(&path).unwrap_err().to_string();
+        assert!(message.contains("isn't a file"), "{message}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -6,84 +6,1044 @@
 //!
 //! ## Usage
 //!
-//! To compress an executable using the CLI, run `./tardis input_file output_file`. For instance,
-//! the following command packs `/usr/bin/ls` and writes it to the file `./packed_ls`.
+//! To compress an executable using the CLI, run `./tardis pack -i input_file -o output_file`. For
+//! instance, the following command packs `/usr/bin/ls` and writes it to the file `./packed_ls`.
 //!
 //! ```
-//! $ ./tardis /usr/bin/ls ./packed_ls
-//! Wrote ./ls (917.50% of input)
+//! $ ./tardis pack -i /usr/bin/ls -o ./packed_ls
+//! Wrote ./packed_ls (917.50% of input)
 //! ```
 //!
 //! > **Warning:** `tardis` is not especially effective as an all-around packer for smaller
 //! > binaries. The overhead incurred in adding the loader is typically much higher than the
 //! > savings from compression at the lower end.
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use config::Config;
 use deku::DekuContainerWrite;
-use libtardis::serialization::{EndMarker, TardisResource};
+use libtardis::{
+    codec::{self, Codec, CodecId},
+    metadata::{Readiness, ResourceKind, ResourceMetadata},
+    packer::{check_guests, write_manifest, Packer},
+    runtime::Bundle,
+    serialization::{ExitPolicy, HeaderTag, Manifest, ManifestHeader, ResourceEntry},
+    signature,
+    stream::{self, Binding, DEFAULT_CHUNK_SIZE},
+};
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 
-/// Add a new guest binary to the output file. Returns the number
-/// of bytes that were written to the file.
-fn add_guest(f: &mut File, guest: &[u8]) -> Result<usize, Box<dyn Error>> {
-    // Compress the guest binary and write it to the file
-    let resource = TardisResource::compress(guest);
-    let resource_bytes = resource.to_bytes().unwrap();
-    f.write_all(&resource_bytes)?;
+mod config;
 
-    Ok(resource_bytes.len())
+// Defines LOADERS, a list of loader binaries along with the codecs that each
+// of them supports.
+include!(concat!(env!("OUT_DIR"), "/loaders.rs"));
+
+/// Return the smallest loader that can decompress resources compressed with
+/// any of the given codecs.
+fn select_loader(codecs: &[CodecId]) -> &'static [u8] {
+    LOADERS
+        .iter()
+        .filter(|(features, _)| {
+            codecs
+                .iter()
+                .all(|codec| *codec == CodecId::Store || features.contains(&codec.name()))
+        })
+        .min_by_key(|(_, loader)| loader.len())
+        .map(|(_, loader)| *loader)
+        .expect("no loader supports the requested codecs")
+}
+
+/// A codec and compression level, along with the size of an input after it's
+/// compressed with them.
+#[derive(Clone, Copy, Debug)]
+struct Candidate {
+    codec: CodecId,
+    level: Option<u32>,
+    size: usize,
+}
+
+/// Compress the file at `path` with every available codec and level, and
+/// return the smallest result produced by each codec. The file is compressed
+/// one chunk at a time, the same way that it will be when it's packed.
+fn measure(path: &str) -> Result<Vec<Candidate>, Box<dyn Error>> {
+    let mut candidates = Vec::new();
+    for id in CodecId::ALL.into_iter().filter(CodecId::is_available) {
+        for level in id.levels() {
+            let candidate = Candidate {
+                codec: id,
+                level,
+                size: 0,
+            };
+            candidates.push((candidate, codec::new(id, level)?));
+        }
+    }
+
+    let mut input = BufReader::new(File::open(path)?);
+    let mut chunk = Vec::with_capacity(DEFAULT_CHUNK_SIZE as usize);
+    loop {
+        chunk.clear();
+        (&mut input)
+            .take(DEFAULT_CHUNK_SIZE as u64)
+            .read_to_end(&mut chunk)?;
+        for (candidate, codec) in &mut candidates {
+            candidate.size += codec.compress(&chunk)?.len();
+        }
+        if chunk.len() < DEFAULT_CHUNK_SIZE as usize {
+            break;
+        }
+    }
+
+    let mut best: Vec<Candidate> = Vec::new();
+    for (candidate, _) in candidates {
+        match best.iter_mut().find(|c| c.codec == candidate.codec) {
+            Some(c) if candidate.size < c.size => *c = candidate,
+            Some(_) => (),
+            None => best.push(candidate),
+        }
+    }
+
+    Ok(best)
 }
 
-const LOADER: &[u8] = include_bytes!(concat!(
-    env!("OUT_DIR"),
-    "/embeds/x86_64-unknown-linux-musl/release/loader"
-));
+/// Choose a codec for each input so that the packed file is as small as
+/// possible. Since mixing codecs may require a larger loader, this compares
+/// the best codec for each input against using a single codec everywhere
+/// (falling back to storing inputs that it can't shrink).
+fn plan(measurements: &[Vec<Candidate>]) -> Vec<Candidate> {
+    let smallest = |candidates: &[Candidate], accept: &dyn Fn(CodecId) -> bool| {
+        *candidates
+            .iter()
+            .filter(|c| accept(c.codec) || c.codec == CodecId::Store)
+            .min_by_key(|c| c.size)
+            .unwrap()
+    };
 
-fn pack(input_files: &Vec<String>, output_file: &str) -> Result<(), Box<dyn Error>> {
-    // Write the loader to the output file
-    let mut guests_size = 0;
-    let mut output = File::create(output_file)?;
+    let mut plans = vec![measurements
+        .iter()
+        .map(|m| smallest(m, &|_| true))
+        .collect::<Vec<_>>()];
+    for id in CodecId::ALL.into_iter().filter(CodecId::is_available) {
+        plans.push(
+            measurements
+                .iter()
+                .map(|m| smallest(m, &|c| c == id))
+                .collect(),
+        );
+    }
 
-    output.write_all(LOADER)?;
+    plans
+        .into_iter()
+        .min_by_key(|plan| {
+            let codecs: Vec<_> = plan.iter().map(|c| c.codec).collect();
+            select_loader(&codecs).len() + plan.iter().map(|c| c.size).sum::<usize>()
+        })
+        .unwrap()
+}
+
+/// Pick the codec that each input should be compressed with.
+fn choose_codecs(
+    input_files: &[String],
+    codec: CodecArg,
+    level: Option<u32>,
+) -> Result<Vec<Box<dyn Codec>>, Box<dyn Error>> {
+    let codecs = match codec {
+        CodecArg::Auto => {
+            if level.is_some() {
+                return Err("--level can't be used with --codec auto".into());
+            }
+            let measurements = input_files
+                .iter()
+                .map(|path| measure(path))
+                .collect::<Result<Vec<_>, _>>()?;
+            plan(&measurements)
+                .into_iter()
+                .map(|c| codec::new(c.codec, c.level))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+        arg => input_files
+            .iter()
+            .map(|_| arg.codec(level))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    Ok(codecs)
+}
+
+/// Describe an input file, so that it can be identified once it's packed.
+fn guest_metadata(input_file: &str) -> Result<ResourceMetadata, Box<dyn Error>> {
+    let name = Path::new(input_file)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| input_file.to_string());
+    let mut metadata = ResourceMetadata::named(&name);
+    let mut magic = Vec::new();
+    File::open(input_file)?.take(4).read_to_end(&mut magic)?;
+    metadata.kind = Some(ResourceKind::detect(&magic));
+    metadata.mode = Some(fs::metadata(input_file)?.permissions().mode() & 0o7777);
+    Ok(metadata)
+}
+
+/// An input to pack, along with how it should be packed.
+struct Guest {
+    path: String,
+    metadata: ResourceMetadata,
+    codec: CodecArg,
+    level: Option<u32>,
+}
+
+/// Pick the codec that each guest should be compressed with. Guests that use
+/// `auto` are planned together, so that they can share a loader.
+fn guest_codecs(guests: &[Guest]) -> Result<Vec<Box<dyn Codec>>, Box<dyn Error>> {
+    let auto_files: Vec<_> = guests
+        .iter()
+        .filter(|guest| matches!(guest.codec, CodecArg::Auto))
+        .map(|guest| guest.path.clone())
+        .collect();
+    let level = guests
+        .iter()
+        .find(|guest| matches!(guest.codec, CodecArg::Auto))
+        .and_then(|guest| guest.level);
+    let mut auto_codecs = choose_codecs(&auto_files, CodecArg::Auto, level)?.into_iter();
+
+    guests
+        .iter()
+        .map(|guest| match guest.codec {
+            CodecArg::Auto => Ok(auto_codecs.next().unwrap()),
+            arg => arg.codec(guest.level),
+        })
+        .collect()
+}
+
+fn pack(args: &PackArgs) -> Result<(), Box<dyn Error>> {
+    // Inputs come either from the command line or from a configuration file,
+    // whose settings can be overridden from the command line
+    let mut guests = Vec::new();
+    let cli_exit_policy = ExitPolicyArg::policy(args.exit_policy, args.primary.as_deref())?;
+    let (output_file, max_size, sign_key, flag_prefix, exit_policy, grace_period) =
+        match &args.config {
+            Some(path) => {
+                let config = Config::load(Path::new(path))?;
+                for guest_config in &config.guests {
+                    let path = guest_config.path.to_string_lossy().into_owned();
+                    let mut metadata = guest_metadata(&path)?;
+                    guest_config.apply(&mut metadata);
+                    let (codec, level) = match args.codec {
+                        Some(codec) => (codec, args.level),
+                        // --level on its own applies to whichever codec each
+                        // guest uses, and is checked like it is with -i
+                        None => match (config.codec_for(guest_config), args.level) {
+                            ((CodecArg::Auto, _), Some(_)) => {
+                                return Err(format!(
+                                "--level can't be used with guest {}, which uses codec = \"auto\"",
+                                guest_config.name()
+                            )
+                                .into());
+                            }
+                            ((codec, _), Some(level)) => {
+                                codec.codec(Some(level)).map_err(|e| {
+                                    format!("--level: guest {}: {e}", guest_config.name())
+                                })?;
+                                (codec, Some(level))
+                            }
+                            (codec_and_level, None) => codec_and_level,
+                        },
+                    };
+                    guests.push(Guest {
+                        path,
+                        metadata,
+                        codec,
+                        level,
+                    });
+                }
+
+                let flag_prefix = match (&args.flag_prefix, args.no_builtin_flags) {
+                    (Some(prefix), _) => Some(prefix.clone()),
+                    (None, true) => Some(String::new()),
+                    (None, false) => config.flag_prefix(),
+                };
+                let exit_policy = match cli_exit_policy {
+                    Some(policy) => Some(policy),
+                    None => config.exit_policy()?,
+                };
+                let output_file = args
+                    .output_file
+                    .clone()
+                    .or_else(|| Some(config.output?.to_string_lossy().into_owned()));
+                let sign_key = args
+                    .sign_key
+                    .clone()
+                    .or_else(|| Some(config.sign_key?.to_string_lossy().into_owned()));
+                (
+                    output_file,
+                    args.max_size.or(config.max_size),
+                    sign_key,
+                    flag_prefix,
+                    exit_policy,
+                    args.grace_period.or(config.grace_period),
+                )
+            }
+            None => {
+                for input_file in &args.input_file {
+                    guests.push(Guest {
+                        path: input_file.clone(),
+                        metadata: guest_metadata(input_file)?,
+                        codec: args.codec.unwrap_or(CodecArg::Lz4),
+                        level: args.level,
+                    });
+                }
+                let flag_prefix = match args.no_builtin_flags {
+                    true => Some(String::new()),
+                    false => args.flag_prefix.clone(),
+                };
+                (
+                    args.output_file.clone(),
+                    args.max_size,
+                    args.sign_key.clone(),
+                    flag_prefix,
+                    cli_exit_policy,
+                    args.grace_period,
+                )
+            }
+        };
+    let output_file = output_file.ok_or("no output file given; pass -o")?;
+    if guests.is_empty() {
+        return Err("no inputs given; pass -i or --config".into());
+    }
+    if let Some(name) = &args.foreground {
+        for guest in &mut guests {
+            guest.metadata.foreground = guest.metadata.name.as_ref() == Some(name);
+        }
+        if !guests.iter().any(|guest| {
+            guest.metadata.foreground && guest.metadata.kind.unwrap_or_default().is_runnable()
+        }) {
+            return Err(format!("--foreground: no guest that is run is named {name}").into());
+        }
+    }
 
-    let if0 = input_files.first().unwrap();
     let mut total_size = 0;
+    for guest in &guests {
+        total_size += fs::metadata(&guest.path)?.len();
+    }
+
+    // Pick the codec that each input should be compressed with, and the
+    // smallest loader that supports all of them
+    let codecs = guest_codecs(&guests)?;
+    let codec_ids: Vec<_> = codecs.iter().map(|c| c.id()).collect();
+    let mut packer = Packer::new().loader(select_loader(&codec_ids));
+    if let Some(path) = &sign_key {
+        packer = packer.sign_with(signature::load_key_pair(&fs::read(path)?)?);
+    }
+    if let Some(max_size) = max_size {
+        packer = packer.max_size(max_size);
+    }
+    if let Some(prefix) = flag_prefix {
+        config::check_flag_prefix(&prefix)?;
+        packer = packer.flag_prefix(prefix);
+    }
+    match (exit_policy, grace_period) {
+        (Some(policy), grace_period) => {
+            packer = packer.exit_policy(policy);
+            if let Some(seconds) = grace_period {
+                packer = packer.grace_period(seconds);
+            }
+        }
+        (None, Some(_)) => {
+            return Err(
+                "a grace period only applies to guests supervised with an exit policy".into(),
+            )
+        }
+        (None, None) => (),
+    }
+    for (guest, codec) in guests.iter().zip(codecs) {
+        let input = BufReader::new(File::open(&guest.path)?);
+        packer = packer.add_guest_with(guest.metadata.clone(), input, Some(codec));
+    }
+
+    // Don't leave an incomplete file behind. In particular, the size of the
+    // packed file is only known once the guests have been compressed, so the
+    // output has to be removed if it turns out to be too big.
+    let mut output = File::create(&output_file)?;
+    let output_size = match packer.write_to(&mut output) {
+        Ok(summary) => summary.size,
+        Err(e) => {
+            drop(output);
+            fs::remove_file(&output_file)?;
+            return Err(e.into());
+        }
+    };
 
     // Set the same permissions on the output file that existed on the
     // input file
+    let if0 = &guests.first().unwrap().path;
     let input_perms = File::open(if0)?.metadata()?.permissions();
     output.set_permissions(input_perms)?;
 
-    for input_file in input_files.iter() {
-        // Read the input executable into memory
-        //
-        // TODO (kernelmethod): read the file in chunks in case it's too
-        // large for us to fit into memory
-        let data = fs::read(input_file)?;
-        total_size += data.len();
+    println!(
+        "Wrote {} ({:.2}% of input)",
+        output_file,
+        output_size as f64 / total_size as f64 * 100.
+    );
+    for (guest, codec) in guests.iter().zip(&codec_ids) {
+        println!("  {}: {}", guest.path, codec.name());
+    }
+    if output_size > total_size {
+        eprintln!(
+            "warning: the packed file is {} bytes larger than its inputs",
+            output_size - total_size
+        );
+    }
+
+    Ok(())
+}
 
-        // Compress the executable and write it to the output file in a new
-        // data block
-        guests_size += add_guest(&mut output, &data)?;
+/// Return the codecs supported by a loader, if it's one of the loaders that
+/// this version of Tardis embeds.
+fn loader_features(loader: &[u8]) -> Option<&'static [&'static str]> {
+    // Signed files have a public key embedded in their loader
+    let mut unsigned = loader.to_vec();
+    if signature::embed_public_key(&mut unsigned, &[0; 32]).is_err() {
+        return None;
     }
+    LOADERS
+        .iter()
+        .find(|(_, candidate)| *candidate == unsigned)
+        .map(|(features, _)| *features)
+}
+
+/// Check whether a file starts with one of the loaders that this version of
+/// Tardis embeds.
+fn starts_with_loader(file: &mut File) -> io::Result<bool> {
+    let max_len = LOADERS.iter().map(|(_, loader)| loader.len()).max();
+    let mut start = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    Read::by_ref(file)
+        .take(max_len.unwrap_or_default() as u64)
+        .read_to_end(&mut start)?;
+    Ok(LOADERS.iter().any(|(_, loader)| {
+        start.len() >= loader.len() && loader_features(&start[..loader.len()]).is_some()
+    }))
+}
 
-    // Write the EndMarker to the output file
-    let marker = EndMarker {
-        manifest_start: LOADER.len(),
-        n_resources: input_files.len(),
+/// A resource in a packed file that is being edited.
+enum Slot<'a> {
+    /// A resource that is already in the file, given by its index.
+    Existing(usize),
+    /// A new guest, read from disk and compressed with the given codec.
+    New(&'a str, Box<dyn Codec>),
+    /// A new version of the guest at the given index, read from disk and
+    /// compressed with the given codec. It keeps the old guest's name and the
+    /// settings that it's run with.
+    Replacement(usize, &'a str, Box<dyn Codec>),
+}
+
+/// Find the index of a guest in a packed file, given either its name or its
+/// index.
+fn find_guest(entries: &[ResourceEntry], guest: &str) -> Result<usize, Box<dyn Error>> {
+    let mut matches = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.header.metadata()?.name.as_deref() == Some(guest) {
+            matches.push(index);
+        }
+    }
+
+    match matches[..] {
+        [index] => Ok(index),
+        [] => match guest.parse::<usize>() {
+            Ok(index) if index < entries.len() => Ok(index),
+            _ => Err(format!("no guest named {guest}").into()),
+        },
+        _ => Err(format!("more than one guest is named {guest}; use its index instead").into()),
+    }
+}
+
+/// Rewrite a packed file with a new list of resources, chosen by `change` from
+/// the resources that the file already holds.
+///
+/// The loader and the resources that keep their index are copied
+/// byte-for-byte. Since every resource is bound to its index, resources that
+/// move have to be sealed again. The file is replaced
+/// atomically once it has been completely written.
+fn edit<'a>(
+    args: &EditArgs,
+    change: impl FnOnce(&[ResourceEntry]) -> Result<Vec<Slot<'a>>, Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let key_pair = match &args.sign_key {
+        Some(path) => Some(signature::load_key_pair(&fs::read(path)?)?),
+        None => None,
+    };
+
+    let mut host = File::open(&args.file)?;
+    let manifest = Manifest::read_from(&mut host)?;
+    let entries = manifest.entries(&mut host)?;
+    let bundle_id = match manifest.header.bundle_id() {
+        Some(bundle_id) if manifest.marker.version >= 2 => bundle_id,
+        _ => {
+            return Err(format!(
+                "{} was packed with format version {}, which can't be edited; pack it again",
+                args.file, manifest.marker.version
+            )
+            .into())
+        }
+    };
+
+    // Editing a signed file invalidates its signature
+    let extensions: Vec<_> = manifest
+        .header
+        .extensions
+        .iter()
+        .filter(|ext| ext.tag != HeaderTag::Signature as u16)
+        .cloned()
+        .collect();
+    if key_pair.is_none() && extensions.len() != manifest.header.extensions.len() {
+        return Err(format!("{} is signed; pass --sign-key to sign it again", args.file).into());
+    }
+
+    let slots = change(&entries)?;
+
+    // Keep the loader unless it can't decompress the new guests
+    let mut loader = vec![0; manifest.header.resources_start.try_into()?];
+    host.seek(SeekFrom::Start(0))?;
+    host.read_exact(&mut loader)?;
+    let mut codec_ids = Vec::new();
+    for slot in &slots {
+        codec_ids.push(match slot {
+            Slot::Existing(index) => entries[*index]
+                .header
+                .metadata()?
+                .codec
+                .unwrap_or(CodecId::Lz4),
+            Slot::New(_, codec) | Slot::Replacement(_, _, codec) => codec.id(),
+        });
+    }
+    let supported = |features: &[&str]| {
+        codec_ids
+            .iter()
+            .all(|id| *id == CodecId::Store || features.contains(&id.name()))
+    };
+    match loader_features(&loader) {
+        Some(features) if supported(features) => (),
+        Some(_) => loader = select_loader(&codec_ids).to_vec(),
+        None => {
+            let mut existing = Vec::new();
+            for entry in &entries {
+                existing.push(
+                    entry
+                        .header
+                        .metadata()?
+                        .codec
+                        .unwrap_or(CodecId::Lz4)
+                        .name(),
+                );
+            }
+            if !supported(&existing) {
+                return Err(
+                    "the loader of this file wasn't built by this version of tardis, \
+                    so new guests must use a codec that the file already uses"
+                        .into(),
+                );
+            }
+        }
+    }
+    if let Some(key_pair) = &key_pair {
+        signature::embed_public_key(&mut loader, &signature::public_key(key_pair))?;
+    }
+
+    // Write the new file next to the old one, and only replace the old one once
+    // the new one is complete
+    let mut tmp_file = PathBuf::from(&args.file).into_os_string();
+    tmp_file.push(".tmp");
+    let mut output = File::create(&tmp_file)?;
+    let result = (|| -> Result<u64, Box<dyn Error>> {
+        output.write_all(&loader)?;
+
+        let mut resources = Vec::new();
+        for (index, slot) in slots.iter().enumerate() {
+            let binding = Binding {
+                bundle_id,
+                index: index.try_into()?,
+            };
+            let header = match slot {
+                Slot::Existing(old_index) if *old_index == index => {
+                    let entry = &entries[*old_index];
+                    let header_len = entry.header.to_bytes()?.len() as u64;
+                    host.seek(SeekFrom::Start(entry.data_start - header_len))?;
+                    io::copy(
+                        &mut (&mut host).take(header_len + entry.header.length),
+                        &mut output,
+                    )?;
+                    entry.header.clone()
+                }
+                // Resources that move have to be bound to their new index,
+                // but keep their compressed data
+                Slot::Existing(old_index) => {
+                    let entry = &entries[*old_index];
+                    host.seek(SeekFrom::Start(entry.data_start))?;
+                    stream::reseal_resource(
+                        &mut BufReader::new(&mut host),
+                        &entry.header,
+                        entry.binding,
+                        &mut output,
+                        Some(binding),
+                    )?
+                }
+                Slot::New(input_file, codec) | Slot::Replacement(_, input_file, codec) => {
+                    let mut metadata = guest_metadata(input_file)?;
+                    if let Slot::Replacement(old_index, ..) = slot {
+                        // Only what describes the file itself comes from the
+                        // new one; its size and digest are filled in below
+                        let new = metadata;
+                        metadata = entries[*old_index].header.metadata()?;
+                        metadata.kind = new.kind;
+                        metadata.mode = new.mode;
+                        metadata.original_size = None;
+                        metadata.sha256 = None;
+                    }
+                    let mut input = BufReader::new(File::open(input_file)?);
+                    stream::write_resource(
+                        &mut input,
+                        &mut output,
+                        metadata,
+                        &**codec,
+                        DEFAULT_CHUNK_SIZE,
+                        Some(binding),
+                    )?
+                }
+            };
+            resources.push(header);
+        }
+
+        let mut header = ManifestHeader::new(loader.len() as u64, extensions)?;
+        let policy = header.exit_policy()?;
+        if let Some(policy) = &policy {
+            policy.primary_index(&resources).map_err(|e| {
+                format!("{e}; the file's exit policy needs it as its primary guest")
+            })?;
+        }
+        let metadata = resources
+            .iter()
+            .map(|resource| resource.metadata())
+            .collect::<Result<Vec<_>, _>>()?;
+        check_guests(&metadata.iter().collect::<Vec<_>>(), policy.as_ref())?;
+        if let Some(key_pair) = &key_pair {
+            header = signature::sign(key_pair, &loader, &header, &resources)?;
+        }
+        Ok(write_manifest(&mut output, &loader, &resources, &header)?)
+    })();
+
+    let output_size = match result {
+        Ok(size) => size,
+        Err(e) => {
+            drop(output);
+            fs::remove_file(&tmp_file)?;
+            return Err(e);
+        }
     };
-    let marker_bytes = marker.to_bytes().unwrap();
-    output.write_all(&marker_bytes)?;
+    output.set_permissions(host.metadata()?.permissions())?;
+    fs::rename(&tmp_file, &args.file)?;
 
-    let output_size = LOADER.len() + guests_size;
     println!(
-        "Wrote {} ({:.2}% of input)",
-        output_file,
-        output_size as f64 / total_size as f64 * 100.
+        "Wrote {} ({} resources, {output_size} bytes)",
+        args.file,
+        slots.len()
     );
+    Ok(())
+}
+
+/// Add guests to the end of a packed file.
+fn add(args: &AddArgs) -> Result<(), Box<dyn Error>> {
+    let codecs = choose_codecs(&args.input_file, args.codec, args.level)?;
+    edit(&args.edit, |entries| {
+        let mut slots: Vec<_> = (0..entries.len()).map(Slot::Existing).collect();
+        for (input_file, codec) in args.input_file.iter().zip(codecs) {
+            slots.push(Slot::New(input_file, codec));
+        }
+        Ok(slots)
+    })
+}
 
+/// Remove a guest from a packed file.
+fn remove(args: &RemoveArgs) -> Result<(), Box<dyn Error>> {
+    edit(&args.edit, |entries| {
+        let removed = find_guest(entries, &args.guest)?;
+        Ok((0..entries.len())
+            .filter(|index| *index != removed)
+            .map(Slot::Existing)
+            .collect())
+    })
+}
+
+/// Replace a guest in a packed file with a new one.
+fn replace(args: &ReplaceArgs) -> Result<(), Box<dyn Error>> {
+    let input_file = std::slice::from_ref(&args.input_file);
+    let codec = choose_codecs(input_file, args.codec, args.level)?.remove(0);
+    edit(&args.edit, |entries| {
+        let replaced = find_guest(entries, &args.guest)?;
+        let mut slots: Vec<_> = (0..entries.len()).map(Slot::Existing).collect();
+        slots[replaced] = Slot::Replacement(replaced, &args.input_file, codec);
+        Ok(slots)
+    })
+}
+
+/// Compression codecs that can be selected from the command line or from a
+/// configuration file.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum CodecArg {
+    /// Try every available codec and level on each input, and keep whichever
+    /// produces the smallest file. This can be slow for large inputs.
+    Auto,
+    /// Don't compress the inputs.
+    Store,
+    /// LZ4. Fast to decompress, but has a low compression ratio.
+    Lz4,
+    /// LZ4 with the high-compression encoder. Produces files that decompress as
+    /// quickly as plain LZ4.
+    Lz4Hc,
+    /// Zstandard.
+    Zstd,
+    /// XZ (LZMA2). Usually has the highest compression ratio.
+    Xz,
+    /// DEFLATE.
+    Deflate,
+}
+
+impl CodecArg {
+    /// Create the codec selected by this argument with the given compression level.
+    fn codec(self, level: Option<u32>) -> Result<Box<dyn Codec>, Box<dyn Error>> {
+        let codec = match self {
+            CodecArg::Auto => unreachable!("auto doesn't correspond to a single codec"),
+            CodecArg::Store => codec::new(CodecId::Store, level)?,
+            CodecArg::Lz4 => codec::new(CodecId::Lz4, level)?,
+            CodecArg::Lz4Hc => codec::new(CodecId::Lz4, Some(level.unwrap_or(12)))?,
+            CodecArg::Zstd => codec::new(CodecId::Zstd, level)?,
+            CodecArg::Xz => codec::new(CodecId::Xz, level)?,
+            CodecArg::Deflate => codec::new(CodecId::Deflate, level)?,
+        };
+        Ok(codec)
+    }
+}
+
+/// Policies that a loader supervising several guests can exit with, as they're
+/// selected from the command line or from a configuration file.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum ExitPolicyArg {
+    /// Exit as soon as a guest fails, with its status, after stopping the others.
+    FirstFailure,
+    /// Wait for every guest, and fail if any of them failed.
+    AllSucceed,
+    /// Exit with the status of the primary guest, after stopping the others.
+    Primary,
+}
+
+impl ExitPolicyArg {
+    /// Combine a policy with the name of the primary guest. Naming a primary
+    /// guest on its own selects the primary policy.
+    fn policy(
+        policy: Option<Self>,
+        primary: Option<&str>,
+    ) -> Result<Option<ExitPolicy>, Box<dyn Error>> {
+        match (policy, primary) {
+            (None, None) => Ok(None),
+            (Some(ExitPolicyArg::FirstFailure), None) => Ok(Some(ExitPolicy::FirstFailure)),
+            (Some(ExitPolicyArg::AllSucceed), None) => Ok(Some(ExitPolicy::AllSucceed)),
+            (Some(ExitPolicyArg::Primary) | None, Some(name)) => {
+                Ok(Some(ExitPolicy::Primary(name.into())))
+            }
+            (Some(ExitPolicyArg::Primary), None) => {
+                Err("the primary exit policy needs the name of a primary guest".into())
+            }
+            (Some(_), Some(_)) => {
+                Err("a primary guest can only be named with the primary exit policy".into())
+            }
+        }
+    }
+}
+
+/// Parse a public key, given either as 32 raw bytes or as 64 hex digits.
+fn parse_public_key(data: &[u8]) -> Result<[u8; 32], Box<dyn Error>> {
+    if let Ok(key) = data.try_into() {
+        return Ok(key);
+    }
+
+    let hex = std::str::from_utf8(data).unwrap_or_default().trim();
+    if hex.len() != 64 {
+        return Err("public key must be 32 bytes or 64 hex digits".into());
+    }
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)?;
+    }
+    Ok(key)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Check that a packed file is intact, along with its signature if a public key
+/// is given.
+fn verify(args: &VerifyArgs) -> Result<(), Box<dyn Error>> {
+    let mut file = File::open(&args.file)?;
+    // A file that starts with one of our loaders was packed, so if its end
+    // marker is missing then it was truncated
+    let manifest = if starts_with_loader(&mut file)? {
+        Manifest::read_packed(&mut file)?
+    } else {
+        Manifest::read_from(&mut file)?
+    };
+    let entries = manifest.entries(&mut file)?;
+    if let Some(pubkey) = &args.pubkey {
+        let public_key = parse_public_key(&fs::read(pubkey)?)?;
+        let headers: Vec<_> = entries.iter().map(|entry| entry.header.clone()).collect();
+        let mut loader = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        Read::by_ref(&mut file)
+            .take(manifest.header.resources_start)
+            .read_to_end(&mut loader)?;
+        signature::verify(&public_key, &loader, &manifest.header, &headers)?;
+    }
+
+    // Neither the signature nor the file digest covers the data of each guest,
+    // which is authenticated as it's decompressed
+    for entry in &entries {
+        file.seek(SeekFrom::Start(entry.data_start))?;
+        let mut input = BufReader::new(&mut file);
+        stream::read_resource(&mut input, &entry.header, entry.binding, &mut io::sink())?;
+    }
+
+    println!("{}: OK ({} resources)", args.file, entries.len());
+    Ok(())
+}
+
+/// Extract every resource in a packed file into `output_dir`. Each resource is
+/// checked against its recorded digest as it's extracted, and files that fail
+/// the check are removed.
+fn unpack_file(file: &Path, output_dir: &Path, recursive: bool) -> Result<(), Box<dyn Error>> {
+    let bundle = Bundle::open(file)?;
+    fs::create_dir_all(output_dir)?;
+
+    for index in 0..bundle.entries().len() {
+        let path = bundle
+            .extract_to(index, output_dir)
+            .map_err(|e| format!("{}: resource {index}: {e}", file.display()))?;
+        println!("{}", path.display());
+
+        // Guests that are packed files themselves are unpacked next to them
+        if recursive && Manifest::read_from(&mut File::open(&path)?).is_ok() {
+            let mut nested_dir = PathBuf::from(&path).into_os_string();
+            nested_dir.push(".unpacked");
+            unpack_file(&path, Path::new(&nested_dir), recursive)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Extract the guests of a packed file.
+fn unpack(args: &UnpackArgs) -> Result<(), Box<dyn Error>> {
+    unpack_file(
+        Path::new(&args.file),
+        Path::new(&args.output_dir),
+        args.recursive,
+    )
+}
+
+/// Description of a packed file, as printed by `inspect`.
+#[derive(Serialize, Debug)]
+struct Inspection {
+    file: String,
+    version: u32,
+    loader_size: u64,
+    bundle_id: Option<String>,
+    signed: bool,
+    file_digest: Option<String>,
+    flag_prefix: Option<String>,
+    exit_policy: Option<String>,
+    primary: Option<String>,
+    grace_period: Option<u32>,
+    resources: Vec<ResourceInfo>,
+}
+
+/// Description of a single resource in a packed file.
+#[derive(Serialize, Debug)]
+struct ResourceInfo {
+    index: usize,
+    name: Option<String>,
+    kind: &'static str,
+    mode: Option<u32>,
+    codec: &'static str,
+    compressed_size: u64,
+    original_size: Option<u64>,
+    chunk_size: Option<u32>,
+    sha256: Option<String>,
+    restart: Option<RestartInfo>,
+    foreground: bool,
+    after: Vec<String>,
+    ready: Option<String>,
+}
+
+/// How a resource is restarted, as printed by `inspect`.
+#[derive(Serialize, Debug)]
+struct RestartInfo {
+    restart: &'static str,
+    max_restarts: Option<u32>,
+    delay_ms: u32,
+}
+
+/// Describe the contents of a packed file. Only the manifest is read, so
+/// nothing in the file is decompressed or run.
+fn inspect(args: &InspectArgs) -> Result<(), Box<dyn Error>> {
+    let mut file = File::open(&args.file)?;
+    let manifest = Manifest::read_from(&mut file)?;
+    let entries = manifest.entries(&mut file)?;
+
+    let mut resources = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let metadata = entry.header.metadata()?;
+        resources.push(ResourceInfo {
+            index,
+            name: metadata.name,
+            kind: metadata.kind.unwrap_or_default().name(),
+            mode: metadata.mode,
+            codec: metadata.codec.unwrap_or(CodecId::Lz4).name(),
+            compressed_size: entry.header.length,
+            original_size: metadata.original_size,
+            chunk_size: metadata.chunk_size,
+            sha256: metadata.sha256.map(|digest| to_hex(&digest)),
+            restart: metadata.restart.map(|policy| RestartInfo {
+                restart: policy.restart.name(),
+                max_restarts: policy.max_restarts,
+                delay_ms: policy.delay,
+            }),
+            foreground: metadata.foreground,
+            after: metadata.after,
+            ready: metadata.ready.map(|ready| match ready {
+                Readiness::Notify(0) => "notify".into(),
+                Readiness::Notify(timeout) => format!("notify within {timeout}ms"),
+                Readiness::Delay(delay) => format!("after {delay}ms"),
+            }),
+        });
+    }
+    let exit_policy = manifest.header.exit_policy()?;
+    let inspection = Inspection {
+        file: args.file.clone(),
+        version: manifest.marker.version,
+        loader_size: manifest.header.resources_start,
+        bundle_id: manifest.header.bundle_id().map(|id| to_hex(&id)),
+        signed: manifest
+            .header
+            .extensions
+            .iter()
+            .any(|ext| ext.tag == HeaderTag::Signature as u16),
+        file_digest: manifest.digest.map(|digest| to_hex(&digest.sha256)),
+        flag_prefix: manifest.header.flag_prefix().map(String::from),
+        exit_policy: exit_policy.as_ref().map(|policy| policy.name().into()),
+        grace_period: match exit_policy {
+            Some(_) => Some(manifest.header.grace_period()?),
+            None => None,
+        },
+        primary: match exit_policy {
+            Some(ExitPolicy::Primary(name)) => Some(name),
+            _ => None,
+        },
+        resources,
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&inspection)?);
+        return Ok(());
+    }
+
+    let or_unknown = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+    println!("{}", inspection.file);
+    println!("  format version: {}", inspection.version);
+    println!("  loader size:    {} bytes", inspection.loader_size);
+    println!("  bundle id:      {}", or_unknown(inspection.bundle_id));
+    println!(
+        "  signed:         {}",
+        if inspection.signed { "yes" } else { "no" }
+    );
+    println!("  file digest:    {}", or_unknown(inspection.file_digest));
+    println!(
+        "  built-in flags: {}",
+        match inspection.flag_prefix {
+            Some(prefix) => format!("{prefix}*"),
+            None => "off".into(),
+        }
+    );
+    println!(
+        "  exit policy:    {}",
+        match (inspection.exit_policy, inspection.primary) {
+            (Some(policy), Some(primary)) => format!("{policy} ({primary})"),
+            (Some(policy), None) => policy,
+            (None, _) => "none (guests aren't supervised)".into(),
+        }
+    );
+    if let Some(seconds) = inspection.grace_period {
+        println!("  grace period:   {seconds}s");
+    }
+    println!("  resources:      {}", inspection.resources.len());
+    for resource in inspection.resources {
+        println!();
+        println!("  [{}] {}", resource.index, or_unknown(resource.name));
+        println!(
+            "    kind:            {}{}",
+            resource.kind,
+            if resource.foreground {
+                " (foreground)"
+            } else {
+                ""
+            }
+        );
+        println!(
+            "    mode:            {}",
+            or_unknown(resource.mode.map(|mode| format!("{mode:04o}")))
+        );
+        println!("    codec:           {}", resource.codec);
+        println!("    compressed size: {} bytes", resource.compressed_size);
+        println!(
+            "    original size:   {}",
+            or_unknown(resource.original_size.map(|size| format!("{size} bytes")))
+        );
+        println!("    sha256:          {}", or_unknown(resource.sha256));
+        if !resource.after.is_empty() {
+            println!("    after:           {}", resource.after.join(", "));
+        }
+        if let Some(ready) = resource.ready {
+            println!("    ready:           {ready}");
+        }
+        if let Some(restart) = resource.restart {
+            println!(
+                "    restart:         {} ({}, first after {}ms)",
+                restart.restart,
+                match restart.max_restarts {
+                    Some(max) => format!("up to {max} times in a row"),
+                    None => "no limit".into(),
+                },
+                restart.delay_ms
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Generate a new signing key.
+fn keygen(args: &KeygenArgs) -> Result<(), Box<dyn Error>> {
+    let pkcs8 = signature::generate_key_pair()?;
+    let public_key = signature::public_key(&signature::load_key_pair(&pkcs8)?);
+
+    let pubkey_file = format!("{}.pub", args.key_file);
+    fs::write(&args.key_file, &pkcs8)?;
+    fs::set_permissions(&args.key_file, fs::Permissions::from_mode(0o600))?;
+    fs::write(&pubkey_file, to_hex(&public_key) + "\n")?;
+
+    println!("Wrote {} and {pubkey_file}", args.key_file);
     Ok(())
 }
 
@@ -91,20 +1051,278 @@ fn pack(input_files: &Vec<String>, output_file: &str) -> Result<(), Box<dyn Erro
 /// syscalls.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
-struct Args {
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Pack one or more executables into a new file.
+    Pack(PackArgs),
+    /// Check that a packed file is intact, and optionally that it was signed with a given key.
+    Verify(VerifyArgs),
+    /// Generate a key for signing packed files.
+    Keygen(KeygenArgs),
+    /// Describe the contents of a packed file without running it.
+    Inspect(InspectArgs),
+    /// Extract the guests of a packed file.
+    Unpack(UnpackArgs),
+    /// Add guests to an existing packed file.
+    Add(AddArgs),
+    /// Remove a guest from an existing packed file.
+    Remove(RemoveArgs),
+    /// Replace a guest in an existing packed file.
+    Replace(ReplaceArgs),
+}
+
+#[derive(Args, Debug)]
+struct PackArgs {
     /// Name of the executable to compress. Multiple executables can be compressed together
     /// and packed into the same file.
-    #[arg(short, long)]
+    #[arg(short, long, conflicts_with = "config")]
     input_file: Vec<String>,
 
+    /// Read the inputs, along with their names, arguments, environment and codecs, from a
+    /// TOML file (usually `tardis.toml`). Other options override the file's settings.
+    #[arg(long)]
+    config: Option<String>,
+
     /// Name of the output file to write to.
     #[arg(short, long)]
-    output_file: String,
+    output_file: Option<String>,
+
+    /// Codec used to compress the inputs [default: lz4].
+    #[arg(short, long, value_enum)]
+    codec: Option<CodecArg>,
+
+    /// Compression level to use with the codec. By default, a level that favours a high
+    /// compression ratio is used. With --config, it applies to every guest's codec.
+    #[arg(short, long)]
+    level: Option<u32>,
+
+    /// Fail if the packed file would be larger than this many bytes.
+    #[arg(long)]
+    max_size: Option<u64>,
+
+    /// Sign the packed file with this Ed25519 key (a PKCS#8 document in DER form). The
+    /// key's public half is embedded into the loader, which then refuses to run files that
+    /// aren't signed with it.
+    #[arg(long)]
+    sign_key: Option<String>,
+
+    /// Prefix of the built-in flags (such as `--tardis-info`) that the packed file handles
+    /// itself instead of passing them to its guests [default: --tardis-].
+    #[arg(long, conflicts_with = "no_builtin_flags", allow_hyphen_values = true)]
+    flag_prefix: Option<String>,
+
+    /// Pass every argument to the guests, without handling any built-in flags.
+    #[arg(long)]
+    no_builtin_flags: bool,
+
+    /// Keep the loader running while several guests run, and exit with the status chosen by
+    /// this policy. By default, the loader exits as soon as the guests have been started.
+    #[arg(long, value_enum)]
+    exit_policy: Option<ExitPolicyArg>,
+
+    /// Name of the primary guest, whose status the loader exits with. This implies
+    /// `--exit-policy primary`.
+    #[arg(long)]
+    primary: Option<String>,
+
+    /// Seconds that supervised guests are given to exit after being asked to (by a
+    /// forwarded SIGTERM, SIGINT or SIGHUP, or by the exit policy), before they're killed
+    /// [default: 10].
+    #[arg(long, value_name = "SECONDS")]
+    grace_period: Option<u32>,
+
+    /// Name of a guest that the loader runs in its own process once the others have been
+    /// started, so that it keeps the packed file's pid, terminal and exit status.
+    #[arg(long, value_name = "NAME")]
+    foreground: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    /// The packed file to check.
+    file: String,
+
+    /// File containing the public key that the file should be signed with, either as 32 raw
+    /// bytes or as 64 hex digits. If this isn't given, the signature isn't checked.
+    #[arg(long)]
+    pubkey: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct InspectArgs {
+    /// The packed file to describe.
+    file: String,
+
+    /// Print the description as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct UnpackArgs {
+    /// The packed file to extract.
+    file: String,
+
+    /// Directory to extract the guests into. Each guest is written under the name recorded
+    /// when it was packed, with its original permissions.
+    #[arg(short, long)]
+    output_dir: String,
+
+    /// Also extract guests that are packed files themselves, into a directory named after
+    /// the guest with an `.unpacked` suffix.
+    #[arg(short, long)]
+    recursive: bool,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
-    pack(&args.input_file, &args.output_file)?;
+/// Arguments shared by the commands that edit a packed file in place.
+#[derive(Args, Debug)]
+struct EditArgs {
+    /// The packed file to edit.
+    file: String,
 
-    Ok(())
+    /// Sign the edited file with this Ed25519 key. This is required if the file is signed,
+    /// since editing it invalidates its signature.
+    #[arg(long)]
+    sign_key: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct AddArgs {
+    #[command(flatten)]
+    edit: EditArgs,
+
+    /// Name of an executable to add. Guests are added after the ones already in the file.
+    #[arg(short, long, required = true)]
+    input_file: Vec<String>,
+
+    /// Codec used to compress the new guests.
+    #[arg(short, long, value_enum, default_value_t = CodecArg::Lz4)]
+    codec: CodecArg,
+
+    /// Compression level to use with the codec.
+    #[arg(short, long)]
+    level: Option<u32>,
+}
+
+#[derive(Args, Debug)]
+struct RemoveArgs {
+    #[command(flatten)]
+    edit: EditArgs,
+
+    /// The name or index of the guest to remove.
+    guest: String,
+}
+
+#[derive(Args, Debug)]
+struct ReplaceArgs {
+    #[command(flatten)]
+    edit: EditArgs,
+
+    /// The name or index of the guest to replace.
+    guest: String,
+
+    /// Name of the executable to replace the guest with. The guest keeps its
+    /// name, arguments, environment and other settings.
+    #[arg(short, long)]
+    input_file: String,
+
+    /// Codec used to compress the new guest.
+    #[arg(short, long, value_enum, default_value_t = CodecArg::Lz4)]
+    codec: CodecArg,
+
+    /// Compression level to use with the codec.
+    #[arg(short, long)]
+    level: Option<u32>,
+}
+
+#[derive(Args, Debug)]
+struct KeygenArgs {
+    /// Where to write the new key. The public key is written next to it, with a `.pub`
+    /// extension.
+    key_file: String,
+}
+
+fn main() {
+    let result = match Cli::parse().command {
+        Command::Pack(args) => pack(&args),
+        Command::Verify(args) => verify(&args),
+        Command::Keygen(args) => keygen(&args),
+        Command::Inspect(args) => inspect(&args),
+        Command::Unpack(args) => unpack(&args),
+        Command::Add(args) => add(&args),
+        Command::Remove(args) => remove(&args),
+        Command::Replace(args) => replace(&args),
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{measure, plan, Candidate};
+    use libtardis::codec::CodecId;
+    use std::fs;
+
+    fn candidate(codec: CodecId, size: usize) -> Candidate {
+        Candidate {
+            codec,
+            level: None,
+            size,
+        }
+    }
+
+    #[test]
+    fn test_measure() {
+        let path = std::env::temp_dir().join(format!("tardis-measure-{}", std::process::id()));
+        fs::write(&path, "hello ".repeat(10_000)).unwrap();
+        let candidates = measure(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // One candidate for each codec, which compresses the input as a whole
+        let codecs: Vec<_> = candidates.iter().map(|c| c.codec).collect();
+        let available: Vec<_> = CodecId::ALL
+            .into_iter()
+            .filter(CodecId::is_available)
+            .collect();
+        assert_eq!(codecs, available);
+        for candidate in candidates {
+            match candidate.codec {
+                CodecId::Store => assert_eq!(candidate.size, 60_000),
+                _ => assert!(candidate.size < 1_000, "{candidate:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_plan_store_fallback() {
+        let compressible = vec![
+            candidate(CodecId::Store, 100_000),
+            candidate(CodecId::Lz4, 10_000),
+        ];
+        let incompressible = vec![
+            candidate(CodecId::Store, 100_000),
+            candidate(CodecId::Lz4, 100_100),
+        ];
+
+        // Inputs that can't be shrunk are stored
+        let codecs = |measurements: &[Vec<Candidate>]| -> Vec<_> {
+            plan(measurements).iter().map(|c| c.codec).collect()
+        };
+        assert_eq!(
+            codecs(std::slice::from_ref(&incompressible)),
+            [CodecId::Store]
+        );
+        assert_eq!(
+            codecs(&[compressible, incompressible]),
+            [CodecId::Lz4, CodecId::Store]
+        );
+    }
 }
@@ -0,0 +1,87 @@
+//! Helpers shared by the integration tests.
+
+// Each test file only uses some of these
+#![allow(dead_code)]
+
+use serde_json::Value;
+use std::{
+    ffi::OsStr,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+/// Create an empty scratch directory for a test.
+pub fn scratch_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tardis-{test}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write an executable shell script to `dir`, and return its path.
+pub fn script(dir: &Path, name: &str, body: &str) -> PathBuf {
+    let path = dir.join(name);
+    fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+/// Run tardis with the given arguments, from `dir`.
+pub fn tardis<S: AsRef<OsStr>>(dir: &Path, args: &[S]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_tardis"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+/// Run tardis with the given arguments, from `dir`, and check that it
+/// succeeds.
+pub fn tardis_ok<S: AsRef<OsStr>>(dir: &Path, args: &[S]) -> Output {
+    let output = tardis(dir, args);
+    assert!(output.status.success(), "{output:?}");
+    output
+}
+
+/// Write a configuration file to `dir` and pack it into `dir/packed`.
+pub fn pack_config(dir: &Path, config: &str) -> PathBuf {
+    fs::write(dir.join("tardis.toml"), config).unwrap();
+    tardis_ok(dir, &["pack", "--config", "tardis.toml", "-o", "packed"]);
+    dir.join("packed")
+}
+
+/// Return what `tardis inspect --json` says about a packed file.
+pub fn inspect(packed: &Path) -> Value {
+    let dir = packed.parent().unwrap();
+    let output = tardis_ok(
+        dir,
+        &[OsStr::new("inspect"), packed.as_os_str(), "--json".as_ref()],
+    );
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+/// Return the names of the resources in a packed file, in order.
+pub fn names(packed: &Path) -> Vec<String> {
+    inspect(packed)["resources"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|resource| resource["name"].as_str().unwrap().to_string())
+        .collect()
+}
+
+/// Run a packed file, check that it succeeds, and return the lines that its
+/// guests printed, sorted since guests run concurrently.
+pub fn run_lines(packed: &Path) -> Vec<String> {
+    let output = Command::new(packed).output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let mut lines: Vec<String> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect();
+    lines.sort();
+    lines
+}
@@ -0,0 +1,105 @@
+//! Check that guests can be added to, removed from and replaced in a packed
+//! file, and that the guests that are left still run as they were packed.
+
+mod common;
+
+use common::{names, pack_config, run_lines, scratch_dir, script, tardis_ok};
+use std::{fs, os::unix::fs::PermissionsExt};
+
+#[test]
+fn test_add() {
+    let dir = scratch_dir("add");
+    script(&dir, "one.sh", "echo one");
+    script(&dir, "two.sh", "echo two \"$@\"");
+    let packed = pack_config(
+        &dir,
+        r#"
+        exit_policy = "all-succeed"
+        [[guest]]
+        path = "one.sh"
+        "#,
+    );
+
+    tardis_ok(&dir, &["add", "packed", "-i", "two.sh"]);
+    assert_eq!(names(&packed), ["one.sh", "two.sh"]);
+    assert_eq!(run_lines(&packed), ["one", "two"]);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_remove() {
+    let dir = scratch_dir("remove");
+    script(&dir, "one.sh", "echo one");
+    script(&dir, "two.sh", "echo two \"$@\" $GREET");
+    script(&dir, "three.sh", "echo three");
+    let packed = pack_config(
+        &dir,
+        r#"
+        exit_policy = "all-succeed"
+        [[guest]]
+        path = "one.sh"
+        [[guest]]
+        path = "two.sh"
+        args = ["--port", "1"]
+        env = { GREET = "hi" }
+        [[guest]]
+        path = "three.sh"
+        "#,
+    );
+
+    // The guests after the removed one move, but keep their settings
+    tardis_ok(&dir, &["remove", "packed", "one.sh"]);
+    assert_eq!(names(&packed), ["two.sh", "three.sh"]);
+    assert_eq!(run_lines(&packed), ["three", "two --port 1 hi"]);
+
+    tardis_ok(&dir, &["remove", "packed", "1"]);
+    assert_eq!(names(&packed), ["two.sh"]);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_replace() {
+    let dir = scratch_dir("replace");
+    script(&dir, "main.sh", "echo main");
+    script(&dir, "helper.sh", "echo helper \"$@\" $GREET");
+    script(&dir, "helper2.sh", "echo helper2 \"$@\" $GREET");
+    let packed = pack_config(
+        &dir,
+        r#"
+        exit_policy = "all-succeed"
+        [[guest]]
+        path = "main.sh"
+        after = ["helper"]
+        [[guest]]
+        name = "helper"
+        path = "helper.sh"
+        args = ["--port", "1"]
+        env = { GREET = "hi" }
+        "#,
+    );
+    assert_eq!(run_lines(&packed), ["helper --port 1 hi", "main"]);
+
+    // The new guest keeps the old one's name, so the guest that's started
+    // after it still finds it, and it's run with the same settings
+    tardis_ok(&dir, &["replace", "packed", "helper", "-i", "helper2.sh"]);
+    assert_eq!(names(&packed), ["main.sh", "helper"]);
+    assert_eq!(run_lines(&packed), ["helper2 --port 1 hi", "main"]);
+
+    // Only the new file's contents and permissions are taken from it
+    fs::set_permissions(dir.join("helper.sh"), fs::Permissions::from_mode(0o700)).unwrap();
+    tardis_ok(&dir, &["replace", "packed", "1", "-i", "helper.sh"]);
+    let inspection = common::inspect(&packed);
+    let helper = &inspection["resources"][1];
+    assert_eq!(helper["name"], "helper");
+    assert_eq!(helper["mode"], 0o700);
+    assert_eq!(helper["after"], serde_json::json!([]));
+    assert_eq!(
+        inspection["resources"][0]["after"],
+        serde_json::json!(["helper"])
+    );
+    assert_eq!(run_lines(&packed), ["helper --port 1 hi", "main"]);
+
+    fs::remove_dir_all(dir).unwrap();
+}
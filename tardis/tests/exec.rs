@@ -0,0 +1,166 @@
+//! Check that guests are run with exactly the arguments and environment that
+//! the packed binary received.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::PermissionsExt,
+    },
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Guest that prints the arguments and environment it was started with, as the
+/// kernel recorded them. The interpreter's own arguments (`/bin/sh` and the
+/// path of the script) come before the guest's arguments.
+const GUEST: &str = "#!/bin/sh
+/bin/cat /proc/$$/cmdline
+printf '\\001'
+/bin/cat /proc/$$/environ
+";
+
+/// Create an empty scratch directory for a test.
+fn scratch_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tardis-{test}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Pack the guest on its own, or with the given configuration file.
+fn pack(dir: &Path, config: Option<&str>) -> PathBuf {
+    let guest = dir.join("guest.sh");
+    fs::write(&guest, GUEST).unwrap();
+    fs::set_permissions(&guest, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let packed = dir.join("packed");
+    let mut command = Command::new(env!("CARGO_BIN_EXE_tardis"));
+    command.arg("pack").arg("-o").arg(&packed);
+    match config {
+        Some(config) => {
+            let path = dir.join("tardis.toml");
+            fs::write(&path, config).unwrap();
+            command.arg("--config").arg(path);
+        }
+        None => {
+            command.arg("-i").arg(&guest);
+        }
+    }
+    let output = command.output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    packed
+}
+
+/// Run the packed guest, and return the arguments and environment it saw.
+fn run(packed: &Path, args: &[&OsStr], env: &[(&OsStr, &OsStr)]) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let output = Command::new(packed)
+        .args(args)
+        .env_clear()
+        .envs(env.iter().copied())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    // Both files hold a list of NUL-terminated strings
+    let split = |data: &[u8]| -> Vec<Vec<u8>> {
+        match data.strip_suffix(b"\0") {
+            Some(data) => data.split(|&b| b == 0).map(<[u8]>::to_vec).collect(),
+            None => Vec::new(),
+        }
+    };
+    let separator = output.stdout.iter().position(|&b| b == 1).unwrap();
+    let cmdline = split(&output.stdout[..separator]);
+    let mut environ = split(&output.stdout[separator + 1..]);
+    environ.sort();
+    (cmdline[2..].to_vec(), environ)
+}
+
+fn entry(name: &[u8], value: &[u8]) -> Vec<u8> {
+    [name, b"=", value].concat()
+}
+
+#[test]
+fn test_args_are_passed_exactly() {
+    let dir = scratch_dir("args");
+    let packed = pack(&dir, None);
+
+    let args = [
+        OsStr::new("plain"),
+        OsStr::new("with space"),
+        OsStr::new(""),
+        OsStr::from_bytes(b"caf\xe9"),
+        OsStr::from_bytes(b"\xff\xfe"),
+    ];
+    let (seen, _) = run(&packed, &args, &[]);
+    let expected: Vec<Vec<u8>> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+    assert_eq!(seen, expected);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_env_is_passed_exactly() {
+    let dir = scratch_dir("env");
+    let packed = pack(&dir, None);
+
+    let env = [
+        (OsStr::new("PLAIN"), OsStr::new("value")),
+        (OsStr::new("UTF8"), OsStr::new("h\u{e9}llo")),
+        (OsStr::new("LATIN1"), OsStr::from_bytes(b"caf\xe9")),
+        (OsStr::from_bytes(b"NAME\xff"), OsStr::new("x")),
+        (OsStr::new("EMPTY"), OsStr::new("")),
+        (OsStr::new("EQUALS"), OsStr::new("a=b")),
+    ];
+    let (_, seen) = run(&packed, &[], &env);
+    let mut expected: Vec<Vec<u8>> = env
+        .iter()
+        .map(|(name, value)| entry(name.as_bytes(), value.as_bytes()))
+        .collect();
+    expected.sort();
+    assert_eq!(seen, expected);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_packed_args_and_env() {
+    let dir = scratch_dir("config");
+    let packed = pack(
+        &dir,
+        Some(
+            r#"
+            [[guest]]
+            path = "guest.sh"
+            args = ["--from-config", "two words"]
+            env = { GREETING = "hello" }
+            "#,
+        ),
+    );
+
+    let latin1 = OsString::from_vec(b"caf\xe9".to_vec());
+    let (args, env) = run(
+        &packed,
+        &[OsStr::new("given"), &latin1],
+        &[
+            (OsStr::new("GREETING"), OsStr::new("bye")),
+            (OsStr::new("OTHER"), &latin1),
+        ],
+    );
+    assert_eq!(
+        args,
+        vec![
+            b"--from-config".to_vec(),
+            b"two words".to_vec(),
+            b"given".to_vec(),
+            b"caf\xe9".to_vec()
+        ]
+    );
+    assert_eq!(
+        env,
+        vec![entry(b"GREETING", b"hello"), entry(b"OTHER", b"caf\xe9")]
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
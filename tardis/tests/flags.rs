@@ -0,0 +1,134 @@
+//! Check the built-in flags that the loader handles instead of running the
+//! guests.
+
+mod common;
+
+use common::{scratch_dir, script, tardis_ok};
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::{Command, Output},
+};
+
+/// Run a packed file with the given arguments.
+fn run(packed: &Path, args: &[&str]) -> Output {
+    Command::new(packed).args(args).output().unwrap()
+}
+
+#[test]
+fn test_prefix() {
+    let dir = scratch_dir("flags-prefix");
+    script(&dir, "guest.sh", "echo guest \"$@\"");
+    let packed = dir.join("packed");
+    tardis_ok(&dir, &["pack", "-i", "guest.sh", "-o", "packed"]);
+
+    let output = run(&packed, &["--tardis-list"]);
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("guest.sh"), "{stdout}");
+    assert!(!stdout.contains("guest "), "{stdout}");
+
+    // Only the first argument can be a flag
+    let output = run(&packed, &["x", "--tardis-list"]);
+    assert_eq!(output.stdout, b"guest x --tardis-list\n");
+
+    // Arguments that start with another prefix go to the guests
+    tardis_ok(
+        &dir,
+        &[
+            "pack",
+            "-i",
+            "guest.sh",
+            "-o",
+            "packed",
+            "--flag-prefix",
+            "--app-",
+        ],
+    );
+    let output = run(&packed, &["--tardis-list"]);
+    assert_eq!(output.stdout, b"guest --tardis-list\n");
+    let output = run(&packed, &["--app-version"]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(output.stdout.starts_with(b"tardis loader "), "{output:?}");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_no_builtin_flags() {
+    let dir = scratch_dir("flags-none");
+    script(&dir, "guest.sh", "echo guest \"$@\"");
+    let packed = dir.join("packed");
+    tardis_ok(
+        &dir,
+        &[
+            "pack",
+            "-i",
+            "guest.sh",
+            "-o",
+            "packed",
+            "--no-builtin-flags",
+        ],
+    );
+
+    for flag in ["--tardis-list", "--tardis-help", "--tardis-bogus"] {
+        let output = run(&packed, &[flag]);
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(output.stdout, format!("guest {flag}\n").as_bytes());
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_bad_flags() {
+    let dir = scratch_dir("flags-bad");
+    script(&dir, "guest.sh", "echo guest \"$@\"");
+    let packed = dir.join("packed");
+    tardis_ok(&dir, &["pack", "-i", "guest.sh", "-o", "packed"]);
+
+    for args in [
+        &["--tardis-bogus"][..],
+        &["--tardis-"],
+        &["--tardis-list", "extra"],
+        &["--tardis-extract"],
+    ] {
+        let output = run(&packed, args);
+        assert_eq!(output.status.code(), Some(2), "{args:?}: {output:?}");
+        assert!(output.stdout.is_empty(), "{args:?}: {output:?}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("--tardis-help"), "{stderr}");
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_extract() {
+    let dir = scratch_dir("flags-extract");
+    script(&dir, "guest.sh", "echo guest");
+    fs::write(dir.join("data.txt"), "data").unwrap();
+    fs::set_permissions(dir.join("data.txt"), fs::Permissions::from_mode(0o640)).unwrap();
+    let packed = dir.join("packed");
+    tardis_ok(
+        &dir,
+        &["pack", "-i", "guest.sh", "-i", "data.txt", "-o", "packed"],
+    );
+
+    let out = dir.join("out");
+    let output = run(&packed, &["--tardis-extract", out.to_str().unwrap()]);
+    assert!(output.status.success(), "{output:?}");
+    let guest = fs::metadata(out.join("guest.sh")).unwrap();
+    assert_eq!(guest.permissions().mode() & 0o777, 0o755);
+    assert_eq!(fs::read(out.join("data.txt")).unwrap(), b"data");
+    let data = fs::metadata(out.join("data.txt")).unwrap();
+    assert_eq!(data.permissions().mode() & 0o777, 0o640);
+
+    // Existing files aren't overwritten
+    let output = run(&packed, &["--tardis-extract", out.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert_eq!(fs::read(out.join("data.txt")).unwrap(), b"data");
+
+    fs::remove_dir_all(dir).unwrap();
+}
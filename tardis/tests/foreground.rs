@@ -0,0 +1,47 @@
+//! Check that the foreground guest takes over the loader's process, and that
+//! the other guests are started before it.
+
+mod common;
+
+use common::{pack_config, scratch_dir, script};
+use std::{fs, process::Command};
+
+#[test]
+fn test_foreground() {
+    let dir = scratch_dir("foreground");
+    script(
+        &dir,
+        "helper.sh",
+        "echo helper > started\necho READY=1 >&$TARDIS_NOTIFY_FD",
+    );
+    script(&dir, "main.sh", "echo main $$ $(cat started)");
+    // The foreground guest is listed first, but still started last
+    let packed = pack_config(
+        &dir,
+        r#"
+        [[guest]]
+        path = "main.sh"
+        foreground = true
+        after = ["helper"]
+        [[guest]]
+        name = "helper"
+        path = "helper.sh"
+        ready = "notify"
+        "#,
+    );
+
+    let child = Command::new(&packed)
+        .current_dir(&dir)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("main {pid} helper\n")
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,60 @@
+//! Check what `tardis inspect --json` reports about a packed file.
+
+mod common;
+
+use common::{inspect, pack_config, scratch_dir, script};
+use libtardis::metadata::sha256;
+use std::fs;
+
+#[test]
+fn test_inspect_json() {
+    let dir = scratch_dir("inspect");
+    script(&dir, "guest.sh", "echo hello");
+    fs::write(dir.join("data.txt"), "data ".repeat(1000)).unwrap();
+    let packed = pack_config(
+        &dir,
+        r#"
+        [[guest]]
+        path = "guest.sh"
+        codec = "store"
+        [[guest]]
+        name = "data"
+        path = "data.txt"
+        codec = "zstd"
+        run = false
+        "#,
+    );
+
+    let inspection = inspect(&packed);
+    let resources = inspection["resources"].as_array().unwrap();
+    assert_eq!(resources.len(), 2);
+    for (resource, (name, path, codec)) in resources.iter().zip([
+        ("guest.sh", "guest.sh", "store"),
+        ("data", "data.txt", "zstd"),
+    ]) {
+        let contents = fs::read(dir.join(path)).unwrap();
+        let digest: String = sha256(&contents)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        assert_eq!(resource["name"], name);
+        assert_eq!(resource["codec"], codec);
+        assert_eq!(resource["original_size"], contents.len());
+        assert_eq!(resource["sha256"], digest);
+    }
+    assert_eq!(resources[0]["kind"], "script");
+    assert_eq!(resources[1]["kind"], "data");
+
+    // The resources fit between the loader and the manifest
+    let compressed: Vec<_> = resources
+        .iter()
+        .map(|resource| resource["compressed_size"].as_u64().unwrap())
+        .collect();
+    assert!(compressed[0] >= 11, "{compressed:?}");
+    assert!(compressed[1] < 1000, "{compressed:?}");
+    let loader_size = inspection["loader_size"].as_u64().unwrap();
+    let file_size = fs::metadata(&packed).unwrap().len();
+    assert!(loader_size + compressed.iter().sum::<u64>() < file_size);
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,142 @@
+//! Check the codecs and settings that `tardis pack` packs its inputs with.
+
+mod common;
+
+use common::{inspect, scratch_dir, script, tardis, tardis_ok};
+use std::{fs, path::Path};
+
+/// Write a file of text that compresses better at higher levels.
+fn text(dir: &Path, name: &str) {
+    let mut state = 1u32;
+    let words: Vec<_> = (0..40_000)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            ["alpha", "beta", "gamma", "delta", "epsilon", "zeta"][(state >> 16) as usize % 6]
+        })
+        .collect();
+    fs::write(dir.join(name), words.join(" ")).unwrap();
+}
+
+#[test]
+fn test_level_with_config() {
+    let dir = scratch_dir("level-config");
+    script(&dir, "guest.sh", "echo hello");
+    text(&dir, "data.txt");
+    fs::write(
+        dir.join("tardis.toml"),
+        r#"
+        codec = "zstd"
+        [[guest]]
+        path = "guest.sh"
+        [[guest]]
+        path = "data.txt"
+        run = false
+        "#,
+    )
+    .unwrap();
+
+    // --level applies to the codec from the configuration file
+    let size = |args: &[&str]| {
+        let mut command = vec!["pack", "--config", "tardis.toml", "-o", "packed"];
+        command.extend(args);
+        tardis_ok(&dir, &command);
+        inspect(&dir.join("packed"))["resources"][1]["compressed_size"]
+            .as_u64()
+            .unwrap()
+    };
+    assert!(size(&["--level", "1"]) > size(&[]));
+
+    // and is checked against it
+    let output = tardis(
+        &dir,
+        &[
+            "pack",
+            "--config",
+            "tardis.toml",
+            "-o",
+            "packed",
+            "--level",
+            "30",
+        ],
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--level: guest guest.sh"), "{stderr}");
+
+    fs::write(
+        dir.join("tardis.toml"),
+        "codec = \"auto\"\n[[guest]]\npath = \"guest.sh\"\n",
+    )
+    .unwrap();
+    let output = tardis(
+        &dir,
+        &[
+            "pack",
+            "--config",
+            "tardis.toml",
+            "-o",
+            "packed",
+            "--level",
+            "1",
+        ],
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("codec = \"auto\""), "{stderr}");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_auto() {
+    let dir = scratch_dir("auto");
+    script(&dir, "guest.sh", "echo hello");
+    fs::write(dir.join("data.txt"), "hello ".repeat(5_000)).unwrap();
+    // Bytes that no codec can shrink
+    let mut state = 1u32;
+    let random: Vec<u8> = (0..16_384)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 24) as u8
+        })
+        .collect();
+    fs::write(dir.join("random.bin"), &random).unwrap();
+
+    let pack = |max_size: &str| {
+        tardis(
+            &dir,
+            &[
+                "pack",
+                "-i",
+                "guest.sh",
+                "-i",
+                "data.txt",
+                "-i",
+                "random.bin",
+                "-o",
+                "packed",
+                "--codec",
+                "auto",
+                "--max-size",
+                max_size,
+            ],
+        )
+    };
+    let output = pack("100000000");
+    assert!(output.status.success(), "{output:?}");
+    let inspection = inspect(&dir.join("packed"));
+    let resources = &inspection["resources"];
+    assert_ne!(resources[1]["codec"], "store");
+    assert_eq!(resources[2]["codec"], "store");
+    let size = fs::metadata(dir.join("packed")).unwrap().len();
+
+    // The size limit applies to the packed file, loader included
+    fs::remove_file(dir.join("packed")).unwrap();
+    let output = pack(&(size - 1).to_string());
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("exceeds the maximum"), "{stderr}");
+    assert!(!dir.join("packed").exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,36 @@
+//! Check that packed files that were cut short are reported as truncated, both
+//! by the loader and by `tardis verify`.
+
+mod common;
+
+use common::{scratch_dir, script, tardis, tardis_ok};
+use std::{fs, os::unix::fs::PermissionsExt, process::Command};
+
+#[test]
+fn test_truncated() {
+    let dir = scratch_dir("truncated");
+    script(&dir, "guest.sh", "echo hello");
+    tardis_ok(&dir, &["pack", "-i", "guest.sh", "-o", "packed"]);
+    let packed = fs::read(dir.join("packed")).unwrap();
+
+    for len in [packed.len() - 1, packed.len() - 100] {
+        let path = dir.join("truncated");
+        fs::write(&path, &packed[..len]).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let output = Command::new(&path).output().unwrap();
+        assert_eq!(output.status.code(), Some(12), "{output:?}");
+
+        let output = tardis(&dir, &["verify", "truncated"]);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("file truncated"), "{stderr}");
+    }
+
+    // Files that weren't packed at all don't have a loader
+    let output = tardis(&dir, &["verify", "guest.sh"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("bad magic"), "{stderr}");
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,129 @@
+//! Check that `tardis unpack` restores the guests of a packed file, and only
+//! writes inside the output directory.
+
+mod common;
+
+use common::{inspect, scratch_dir, script, tardis, tardis_ok};
+use libtardis::packer::Packer;
+use std::{
+    fs::{self, File},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+};
+
+/// Return the permission bits of a file.
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o7777
+}
+
+#[test]
+fn test_unpack() {
+    let dir = scratch_dir("unpack");
+    script(&dir, "guest.sh", "echo hello");
+    fs::write(dir.join("data.txt"), "data").unwrap();
+    fs::set_permissions(dir.join("data.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+    tardis_ok(
+        &dir,
+        &["pack", "-i", "guest.sh", "-i", "data.txt", "-o", "packed"],
+    );
+
+    let output = tardis_ok(&dir, &["unpack", "packed", "-o", "out"]);
+    assert_eq!(output.stdout, b"out/guest.sh\nout/data.txt\n");
+    for (name, mode_bits) in [("guest.sh", 0o755), ("data.txt", 0o600)] {
+        let path = dir.join("out").join(name);
+        assert_eq!(fs::read(&path).unwrap(), fs::read(dir.join(name)).unwrap());
+        assert_eq!(mode(&path), mode_bits, "{name}");
+    }
+
+    // Files that are already there aren't overwritten
+    fs::write(dir.join("out/data.txt"), "old").unwrap();
+    let output = tardis(&dir, &["unpack", "packed", "-o", "out"]);
+    assert!(!output.status.success());
+    assert_eq!(fs::read(dir.join("out/data.txt")).unwrap(), b"old");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_unpack_corrupted() {
+    let dir = scratch_dir("unpack-corrupted");
+    // More than one chunk, so that the first one is written out before the
+    // last one is found to be corrupted
+    fs::write(dir.join("data"), vec![b'x'; 5 << 20]).unwrap();
+    tardis_ok(
+        &dir,
+        &["pack", "-i", "data", "-o", "packed", "--codec", "store"],
+    );
+    let inspection = inspect(&dir.join("packed"));
+    let end = inspection["loader_size"].as_u64().unwrap()
+        + inspection["resources"][0]["compressed_size"]
+            .as_u64()
+            .unwrap();
+    let mut packed = fs::read(dir.join("packed")).unwrap();
+    packed[end as usize - 1] ^= 1;
+    fs::write(dir.join("packed"), packed).unwrap();
+
+    let output = tardis(&dir, &["unpack", "packed", "-o", "out"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("resource 0"), "{stderr}");
+    assert_eq!(fs::read_dir(dir.join("out")).unwrap().count(), 0);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_unpack_unsafe_names() {
+    let dir = scratch_dir("unpack-names");
+    let names = ["../escape", "/tmp/absolute", "sub/dir", ".", ""];
+    let mut packer = Packer::new().loader(b"loader".to_vec());
+    for name in names {
+        packer = packer.add_guest(name, &b"data"[..]);
+    }
+    packer
+        .write_to(&mut File::create(dir.join("packed")).unwrap())
+        .unwrap();
+
+    fs::create_dir(dir.join("out")).unwrap();
+    tardis_ok(&dir, &["unpack", "packed", "-o", "out"]);
+    let mut unpacked: Vec<_> = fs::read_dir(dir.join("out"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    unpacked.sort();
+    assert_eq!(
+        unpacked,
+        [
+            "resource-0",
+            "resource-1",
+            "resource-2",
+            "resource-3",
+            "resource-4"
+        ]
+    );
+    assert!(!dir.join("escape").exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_unpack_recursive() {
+    let dir = scratch_dir("unpack-recursive");
+    script(&dir, "guest.sh", "echo hello");
+    tardis_ok(&dir, &["pack", "-i", "guest.sh", "-o", "inner"]);
+    tardis_ok(&dir, &["pack", "-i", "inner", "-o", "outer"]);
+
+    tardis_ok(&dir, &["unpack", "outer", "-o", "out"]);
+    assert!(dir.join("out/inner").exists());
+    assert!(!dir.join("out/inner.unpacked").exists());
+
+    tardis_ok(&dir, &["unpack", "outer", "-o", "recursive", "--recursive"]);
+    let guest = dir.join("recursive/inner.unpacked/guest.sh");
+    assert_eq!(
+        fs::read(&guest).unwrap(),
+        fs::read(dir.join("guest.sh")).unwrap()
+    );
+    assert_eq!(mode(&guest), 0o755);
+
+    fs::remove_dir_all(dir).unwrap();
+}